@@ -7,11 +7,24 @@
 //! Cargo re-run the build script whenever `memory.x` is changed,
 //! updating `memory.x` ensures a rebuild of the application with the
 //! new memory settings.
+//!
+//! It also turns the board description in `boards/` into `board.rs`,
+//! see `src/board.rs` for what is generated, and embeds the songs in `songs/`
+//! for `src/player.rs`.
 
+use std::collections::BTreeMap;
 use std::env;
-use std::fs::File;
+use std::fmt::Write as _;
+use std::fs::{self, File};
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+
+const GPIO_COUNT: u8 = 30;
+const MAX_DRIVES: usize = 8;
+
+// gpios with the UART TX and RX function, per UART
+const UART_TX_PINS: [[u8; 4]; 2] = [[0, 12, 16, 28], [4, 8, 20, 24]];
+const UART_RX_PINS: [[u8; 4]; 2] = [[1, 13, 17, 29], [5, 9, 21, 25]];
 
 fn main() {
     // Put `memory.x` in our output directory and ensure it's
@@ -28,4 +41,319 @@ fn main() {
     // here, we ensure the build script is only re-run when
     // `memory.x` is changed.
     println!("cargo:rerun-if-changed=memory.x");
+
+    let board_path = board_path();
+    println!("cargo:rerun-if-changed={}", board_path.display());
+    println!("cargo:rerun-if-env-changed=FLOPPOTRON_BOARD");
+
+    let source = fs::read_to_string(&board_path)
+        .unwrap_or_else(|err| panic!("can't read {}: {}", board_path.display(), err));
+    let board = Board::parse(&source)
+        .and_then(|board| board.check().map(|_| board))
+        .unwrap_or_else(|err| panic!("{}: {}", board_path.display(), err));
+
+    println!("cargo:rustc-check-cfg=cfg(midi_uart1)");
+    if board.midi_uart() == 1 {
+        println!("cargo:rustc-cfg=midi_uart1");
+    }
+
+    fs::write(out.join("board.rs"), board.generate(&board_path)).unwrap();
+
+    let songs_path = env::var_os("FLOPPOTRON_SONGS").map_or(PathBuf::from("songs"), PathBuf::from);
+    println!("cargo:rerun-if-changed={}", songs_path.display());
+    println!("cargo:rerun-if-env-changed=FLOPPOTRON_SONGS");
+    fs::write(out.join("songs.rs"), generate_songs(&songs_path)).unwrap();
+}
+
+/// Embeds the `.mid` files in `path` in name order, a missing directory means no songs.
+fn generate_songs(path: &Path) -> String {
+    let mut songs: Vec<PathBuf> = match fs::read_dir(path) {
+        Ok(entries) => entries
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| {
+                path.extension()
+                    .is_some_and(|ext| ext.eq_ignore_ascii_case("mid"))
+            })
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    songs.sort();
+
+    let mut code = String::new();
+    writeln!(code, "// generated by build.rs from {}", path.display()).unwrap();
+    writeln!(code).unwrap();
+    writeln!(code, "pub const SONGS: [&[u8]; {}] = [", songs.len()).unwrap();
+    for song in &songs {
+        let song = fs::canonicalize(song).unwrap();
+        let data = fs::read(&song).unwrap();
+        if let Err(err) = check_song(&data) {
+            panic!("{}: {}", song.display(), err);
+        }
+        writeln!(
+            code,
+            "    include_bytes!({:?}),",
+            song.display().to_string()
+        )
+        .unwrap();
+    }
+    writeln!(code, "];").unwrap();
+    code
+}
+
+// the header checks of src/smf.rs, so an unplayable song fails the build
+fn check_song(data: &[u8]) -> Result<(), &'static str> {
+    if data.len() < 14 || &data[..4] != b"MThd" {
+        return Err("not a standard MIDI file");
+    }
+    if u16::from_be_bytes([data[8], data[9]]) > 1 {
+        return Err("only format 0 and 1 can be played");
+    }
+    if data[12] & 0x80 != 0 {
+        return Err("SMPTE timing is not supported");
+    }
+    Ok(())
+}
+
+fn board_path() -> PathBuf {
+    if let Some(path) = env::var_os("FLOPPOTRON_BOARD") {
+        return PathBuf::from(path);
+    }
+    let hw_step = env::var_os("CARGO_FEATURE_HW_STEP").is_some();
+    // a bare RP2040 board has all gpios like the Pico, so it uses the same maps
+    let name = if env::var_os("CARGO_FEATURE_BOARD_PRO_MICRO").is_some() {
+        if hw_step {
+            panic!("there is no hw-step pin map for the Pro Micro, set FLOPPOTRON_BOARD");
+        }
+        "pro-micro"
+    } else if hw_step {
+        "pico-hw-step"
+    } else {
+        "pico"
+    };
+    PathBuf::from(format!("boards/{}.toml", name))
+}
+
+struct Drive {
+    step: u8,
+    dir: u8,
+    enable: u8,
+}
+
+struct Board {
+    led: u8,
+    button: Option<u8>,
+    midi_tx: u8,
+    midi_rx: u8,
+    drives: Vec<Drive>,
+}
+
+impl Board {
+    /// Parses the small TOML subset used by the board files: integer keys,
+    /// `[midi]` and any number of `[[drive]]` tables.
+    fn parse(source: &str) -> Result<Self, String> {
+        let mut tables: Vec<(String, BTreeMap<String, u8>)> =
+            vec![(String::new(), BTreeMap::new())];
+
+        for (index, line) in source.lines().enumerate() {
+            let line_number = index + 1;
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            if let Some(name) = line.strip_prefix("[[").and_then(|l| l.strip_suffix("]]")) {
+                tables.push((format!("[[{}]]", name.trim()), BTreeMap::new()));
+            } else if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                tables.push((name.trim().to_string(), BTreeMap::new()));
+            } else if let Some((key, value)) = line.split_once('=') {
+                let value = value
+                    .trim()
+                    .parse()
+                    .map_err(|_| format!("line {}: expected a gpio number", line_number))?;
+                let table = &mut tables.last_mut().unwrap().1;
+                if table.insert(key.trim().to_string(), value).is_some() {
+                    return Err(format!(
+                        "line {}: duplicate key {}",
+                        line_number,
+                        key.trim()
+                    ));
+                }
+            } else {
+                return Err(format!("line {}: can't parse {:?}", line_number, line));
+            }
+        }
+
+        let mut led = None;
+        let mut button = None;
+        let mut midi = None;
+        let mut drives = Vec::new();
+        for (name, mut table) in tables {
+            match name.as_str() {
+                "" => {
+                    led = Some(take_key(&mut table, "led", "top level")?);
+                    button = table.remove("button");
+                }
+                "midi" => {
+                    midi = Some((
+                        take_key(&mut table, "tx", "[midi]")?,
+                        take_key(&mut table, "rx", "[midi]")?,
+                    ))
+                }
+                "[[drive]]" => drives.push(Drive {
+                    step: take_key(&mut table, "step", "[[drive]]")?,
+                    dir: take_key(&mut table, "dir", "[[drive]]")?,
+                    enable: take_key(&mut table, "enable", "[[drive]]")?,
+                }),
+                _ => return Err(format!("unknown table {}", name)),
+            }
+            if let Some(key) = table.keys().next() {
+                return Err(format!("unknown key {} in {}", key, name));
+            }
+        }
+
+        let (midi_tx, midi_rx) = midi.ok_or("missing [midi]")?;
+        Ok(Board {
+            led: led.ok_or("missing led")?,
+            button,
+            midi_tx,
+            midi_rx,
+            drives,
+        })
+    }
+
+    fn midi_uart(&self) -> usize {
+        UART_TX_PINS
+            .iter()
+            .position(|pins| pins.contains(&self.midi_tx))
+            .unwrap()
+    }
+
+    fn check(&self) -> Result<(), String> {
+        if self.drives.is_empty() || self.drives.len() > MAX_DRIVES {
+            return Err(format!("expected 1 to {} drives", MAX_DRIVES));
+        }
+
+        let mut used: BTreeMap<u8, String> = BTreeMap::new();
+        let mut claim = |pin: u8, usage: String| {
+            if pin >= GPIO_COUNT {
+                return Err(format!("{} uses gpio {}, which doesn't exist", usage, pin));
+            }
+            match used.insert(pin, usage.clone()) {
+                Some(other) => Err(format!("gpio {} is used by {} and {}", pin, other, usage)),
+                None => Ok(()),
+            }
+        };
+        claim(self.led, "the led".to_string())?;
+        if let Some(button) = self.button {
+            claim(button, "the button".to_string())?;
+        }
+        claim(self.midi_tx, "midi tx".to_string())?;
+        claim(self.midi_rx, "midi rx".to_string())?;
+        for (index, drive) in self.drives.iter().enumerate() {
+            claim(drive.step, format!("the step pin of drive {}", index))?;
+            claim(drive.dir, format!("the dir pin of drive {}", index))?;
+            claim(drive.enable, format!("the enable pin of drive {}", index))?;
+        }
+
+        let tx_uart = UART_TX_PINS
+            .iter()
+            .position(|pins| pins.contains(&self.midi_tx));
+        let rx_uart = UART_RX_PINS
+            .iter()
+            .position(|pins| pins.contains(&self.midi_rx));
+        match (tx_uart, rx_uart) {
+            (Some(tx), Some(rx)) if tx == rx => (),
+            _ => {
+                return Err(format!(
+                    "midi tx {} and rx {} are not the TX and RX pins of the same UART",
+                    self.midi_tx, self.midi_rx
+                ))
+            }
+        }
+
+        // the channel outputs of slice N are gpio 2N, 2N + 1, 2N + 16 and 2N + 17
+        if env::var_os("CARGO_FEATURE_HW_STEP").is_some() {
+            for (index, drive) in self.drives.iter().enumerate() {
+                if (drive.step as usize / 2) % 8 != index {
+                    return Err(format!(
+                        "hw-step needs the step pin of drive {} on PWM slice {}, gpio {} is on slice {}",
+                        index,
+                        index,
+                        drive.step,
+                        (drive.step / 2) % 8
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn generate(&self, path: &Path) -> String {
+        let uart = self.midi_uart();
+        let enable_mask = self
+            .drives
+            .iter()
+            .map(|drive| format!("1 << {}", drive.enable))
+            .collect::<Vec<_>>()
+            .join(" | ");
+
+        let mut code = String::new();
+        writeln!(code, "// generated by build.rs from {}", path.display()).unwrap();
+        writeln!(code).unwrap();
+        writeln!(
+            code,
+            "pub const DRIVE_COUNT: usize = {};",
+            self.drives.len()
+        )
+        .unwrap();
+        writeln!(code, "pub const ENABLE_PIN_MASK: u32 = {};", enable_mask).unwrap();
+        writeln!(code).unwrap();
+        writeln!(code, "pub type MidiUart = pac::UART{};", uart).unwrap();
+        writeln!(
+            code,
+            "pub const MIDI_UART_IRQ: pac::Interrupt = pac::Interrupt::UART{}_IRQ;",
+            uart
+        )
+        .unwrap();
+        writeln!(
+            code,
+            "pub type MidiUartPins = (Pin<Gpio{}, FunctionUart>, Pin<Gpio{}, FunctionUart>);",
+            self.midi_tx, self.midi_rx
+        )
+        .unwrap();
+        writeln!(code).unwrap();
+        writeln!(code, "pub fn split_pins(pins: Pins) -> BoardPins {{").unwrap();
+        writeln!(code, "    BoardPins {{").unwrap();
+        writeln!(code, "        drives: [").unwrap();
+        for drive in &self.drives {
+            writeln!(
+                code,
+                "            DrivePins {{ step: pins.gpio{}.into(), dir: pins.gpio{}.into(), enable: pins.gpio{}.into() }},",
+                drive.step, drive.dir, drive.enable
+            )
+            .unwrap();
+        }
+        writeln!(code, "        ],").unwrap();
+        writeln!(
+            code,
+            "        midi_uart: (pins.gpio{}.into_mode(), pins.gpio{}.into_mode()),",
+            self.midi_tx, self.midi_rx
+        )
+        .unwrap();
+        writeln!(code, "        led: pins.gpio{}.into(),", self.led).unwrap();
+        match self.button {
+            Some(button) => writeln!(code, "        button: Some(pins.gpio{}.into()),", button),
+            None => writeln!(code, "        button: None,"),
+        }
+        .unwrap();
+        writeln!(code, "    }}").unwrap();
+        writeln!(code, "}}").unwrap();
+        code
+    }
+}
+
+fn take_key(table: &mut BTreeMap<String, u8>, key: &str, table_name: &str) -> Result<u8, String> {
+    table
+        .remove(key)
+        .ok_or_else(|| format!("missing {} in {}", key, table_name))
 }
@@ -0,0 +1,81 @@
+//! Cycle counts of `PWM_IRQ_WRAP`, measured with SysTick.
+//!
+//! Build with `--features isr-bench` and play something: the main loop logs the
+//! average and worst case cycles spent in the handler every `REPORT_INTERVAL` calls.
+//!
+//! For a before/after comparison, flash a second build with `--features isr-bench,isr-legacy`
+//! and play the same file in the same mode. `isr-legacy` restores the old handler: it runs
+//! in a global critical section and every oscillator reads and clears its own slice's flag,
+//! instead of one read of INTS and stepping only the wrapped slices. Chords matter most,
+//! the old handler's cost grows with the number of oscillators whether they play or not.
+
+use core::cell::Cell;
+
+use cortex_m::{
+    interrupt::{self as cortex_interrupt, Mutex},
+    peripheral::{syst::SystClkSource, SYST},
+};
+use defmt::info;
+
+const REPORT_INTERVAL: u32 = 10_000;
+const SYST_MASK: u32 = 0x00ff_ffff;
+
+#[derive(Clone, Copy)]
+struct IsrStats {
+    calls: u32,
+    total: u32,
+    max: u32,
+}
+
+static STATS: Mutex<Cell<IsrStats>> = Mutex::new(Cell::new(IsrStats {
+    calls: 0,
+    total: 0,
+    max: 0,
+}));
+
+/// Lets SysTick free-run on the core clock.
+pub fn init(mut syst: SYST) {
+    syst.set_clock_source(SystClkSource::Core);
+    syst.set_reload(SYST_MASK);
+    syst.clear_current();
+    syst.enable_counter();
+}
+
+pub fn start() -> u32 {
+    SYST::get_current()
+}
+
+pub fn finish(start: u32) {
+    // SysTick counts down
+    let cycles = start.wrapping_sub(SYST::get_current()) & SYST_MASK;
+    cortex_interrupt::free(|cs| {
+        let mut stats = STATS.borrow(cs).get();
+        stats.calls += 1;
+        stats.total = stats.total.saturating_add(cycles);
+        stats.max = stats.max.max(cycles);
+        STATS.borrow(cs).set(stats);
+    });
+}
+
+pub fn report() {
+    let stats = cortex_interrupt::free(|cs| {
+        let stats = STATS.borrow(cs).get();
+        if stats.calls >= REPORT_INTERVAL {
+            STATS.borrow(cs).set(IsrStats {
+                calls: 0,
+                total: 0,
+                max: 0,
+            });
+        }
+        stats
+    });
+
+    if stats.calls >= REPORT_INTERVAL {
+        info!(
+            "PWM_IRQ_WRAP cycles: avg {} max {} over {} calls",
+            stats.total / stats.calls,
+            stats.max,
+            stats.calls
+        );
+    }
+}
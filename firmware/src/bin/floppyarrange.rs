@@ -0,0 +1,494 @@
+//! Reduces a MIDI file to what the drives can play without stealing voices.
+//!
+//! Reports where the song has more notes at once than the oscillators of a mode provide,
+//! then writes a file that never does:
+//!
+//! - drums are dropped, floppies can't play them
+//! - notes are moved by octaves into the playable range of their channel
+//! - a note that already sounds, on any channel, is dropped as a doubling
+//! - every channel gets a zone of voices, by how many notes it plays at once
+//! - once its zone is full, a channel's oldest note makes room, except for its lowest
+//!
+//! Only the notes are written, one track with 1 ms ticks on the original channels.
+//! Program and control changes would switch presets on the device, so they are left out.
+//!
+//! `--range` sets the range all channels are folded into, `--range <channel>:<low>-<high>`
+//! the one of a single channel, e.g. for the drives of a bass zone that move their heads
+//! less. The zones only shape the written file, on the device every channel plays on all
+//! voices until it gets the same zones. `--settings` writes them as `zone` lines for
+//! `floppoctl set` or `floppoctl upload`, the voices are handed out in channel order.
+//!
+//!     cargo run --features sim --target x86_64-unknown-linux-gnu --bin floppyarrange -- \
+//!         song.mid reduced.mid --mode single --range 24-71 --range 2:24-47 \
+//!         --settings zones.txt
+
+use std::{collections::BTreeMap, fmt::Write, fs, ops::RangeInclusive, process::exit};
+
+use floppotron_jr::{
+    control::OscMode,
+    oscillators::mode_voice_count,
+    smf::{Player, Smf},
+};
+use midi_port::MidiMessage;
+
+const DRUM_CHANNEL: u8 = 9;
+// 500 ticks per quarter at 500 ms per quarter
+const DIVISION: u16 = 500;
+const TEMPO_US: u32 = 500_000;
+const TICK_US: u64 = 1_000;
+// how many overloaded passages are listed
+const REPORT_LINES: usize = 20;
+
+struct Options {
+    song: String,
+    out: String,
+    mode: OscMode,
+    range: RangeInclusive<u8>,
+    // by channel, from 0
+    channel_ranges: BTreeMap<u8, RangeInclusive<u8>>,
+    keep_drums: bool,
+    settings: Option<String>,
+}
+
+impl Options {
+    fn range(&self, channel: u8) -> &RangeInclusive<u8> {
+        self.channel_ranges.get(&channel).unwrap_or(&self.range)
+    }
+}
+
+fn usage() -> ! {
+    eprintln!(
+        "usage: floppyarrange <song.mid> <out.mid> [--mode single|inverse|unisono] \
+         [--range [<channel>:]<low>-<high>]... [--keep-drums] [--settings <zones.txt>]"
+    );
+    exit(2)
+}
+
+fn parse_range(arg: &str) -> Option<RangeInclusive<u8>> {
+    let (low, high) = arg.split_once('-')?;
+    let (low, high): (u8, u8) = (low.parse().ok()?, high.parse().ok()?);
+    // a range below an octave can't take every note
+    (high <= 127 && high >= low.saturating_add(11)).then_some(low..=high)
+}
+
+// a channel from 1 to 16 and its range, or the range of all channels
+fn parse_channel_range(arg: &str) -> Option<(Option<u8>, RangeInclusive<u8>)> {
+    match arg.split_once(':') {
+        Some((channel, range)) => {
+            let channel: u8 = channel.parse().ok()?;
+            (1..=16)
+                .contains(&channel)
+                .then_some((Some(channel - 1), parse_range(range)?))
+        }
+        None => Some((None, parse_range(arg)?)),
+    }
+}
+
+fn parse_options() -> Options {
+    let mut args = std::env::args().skip(1);
+    let mut files = Vec::new();
+    let mut options = Options {
+        song: String::new(),
+        out: String::new(),
+        mode: OscMode::Single,
+        range: 24..=71,
+        channel_ranges: BTreeMap::new(),
+        keep_drums: false,
+        settings: None,
+    };
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().unwrap_or_else(|| usage());
+        match arg.as_str() {
+            "--mode" => {
+                options.mode = match value().as_str() {
+                    "single" => OscMode::Single,
+                    "inverse" => OscMode::Inverse,
+                    "unisono" => OscMode::Unisono,
+                    _ => usage(),
+                }
+            }
+            "--range" => match parse_channel_range(&value()) {
+                Some((Some(channel), range)) => {
+                    options.channel_ranges.insert(channel, range);
+                }
+                Some((None, range)) => options.range = range,
+                None => usage(),
+            },
+            "--keep-drums" => options.keep_drums = true,
+            "--settings" => options.settings = Some(value()),
+            _ if arg.starts_with("--") => usage(),
+            _ => files.push(arg),
+        }
+    }
+    match <[String; 2]>::try_from(files) {
+        Ok([song, out]) => {
+            options.song = song;
+            options.out = out;
+        }
+        Err(_) => usage(),
+    }
+    options
+}
+
+#[derive(Clone, Copy)]
+struct Note {
+    start_us: u64,
+    end_us: u64,
+    channel: u8,
+    note: u8,
+    velocity: u8,
+}
+
+/// Pairs the note ons and offs of a file, a note played again ends the previous one.
+fn read_notes(smf: &Smf) -> (Vec<Note>, u64) {
+    let mut player = Player::new(smf);
+    let mut held: BTreeMap<(u8, u8), Note> = BTreeMap::new();
+    let mut notes = Vec::new();
+    while let Some(msg) = player.next_due(u64::MAX) {
+        let now = player.time_us();
+        let (channel, note, velocity) = match msg {
+            MidiMessage::NoteOn {
+                channel,
+                note,
+                velocity,
+            } => (channel, note, velocity),
+            MidiMessage::NoteOff { channel, note, .. } => (channel, note, 0),
+            _ => continue,
+        };
+        if let Some(mut ended) = held.remove(&(channel, note)) {
+            ended.end_us = now;
+            notes.push(ended);
+        }
+        if velocity > 0 {
+            let start = Note {
+                start_us: now,
+                end_us: now,
+                channel,
+                note,
+                velocity,
+            };
+            held.insert((channel, note), start);
+        }
+    }
+    let end_us = player.time_us();
+    notes.extend(held.into_values().map(|note| Note { end_us, ..note }));
+    notes.retain(|note| note.end_us > note.start_us);
+    notes.sort_by_key(|note| (note.start_us, note.channel, note.note));
+    (notes, end_us)
+}
+
+/// Passages with more than `voices` notes at once, with the most notes in each.
+fn overloads(notes: &[Note], voices: usize) -> Vec<(u64, u64, usize)> {
+    // ends sort before starts at the same time
+    let mut changes: Vec<(u64, i32)> = notes
+        .iter()
+        .flat_map(|note| [(note.start_us, 1), (note.end_us, -1)])
+        .collect();
+    changes.sort();
+
+    let mut passages = Vec::new();
+    let mut sounding = 0;
+    let mut current: Option<(u64, usize)> = None;
+    for (at, change) in changes {
+        sounding = (sounding as i32 + change) as usize;
+        current = match current {
+            None if sounding > voices => Some((at, sounding)),
+            Some((start, peak)) if sounding <= voices => {
+                passages.push((start, at, peak));
+                None
+            }
+            Some((start, peak)) => Some((start, peak.max(sounding))),
+            None => None,
+        };
+    }
+    passages
+}
+
+fn fold(note: u8, range: &RangeInclusive<u8>) -> u8 {
+    let mut note = note;
+    while note < *range.start() {
+        note += 12;
+    }
+    while note > *range.end() {
+        note -= 12;
+    }
+    note
+}
+
+/// Drops notes that start while the same note sounds, the sounding one is held as long as
+/// the longer of both.
+fn drop_doublings(notes: &mut Vec<Note>) -> usize {
+    let before = notes.len();
+    let mut kept: Vec<Note> = Vec::with_capacity(notes.len());
+    // index in `kept` of the last note of every pitch
+    let mut last: [Option<usize>; 128] = [None; 128];
+    for &note in notes.iter() {
+        match last[note.note as usize] {
+            Some(index) if kept[index].end_us > note.start_us => {
+                let sounding = &mut kept[index];
+                sounding.end_us = sounding.end_us.max(note.end_us);
+            }
+            _ => {
+                last[note.note as usize] = Some(kept.len());
+                kept.push(note);
+            }
+        }
+    }
+    *notes = kept;
+    before - notes.len()
+}
+
+/// Most notes a channel plays at once.
+fn peak(notes: &[Note]) -> usize {
+    overloads(notes, 0)
+        .iter()
+        .map(|&(_, _, peak)| peak)
+        .max()
+        .unwrap_or(0)
+}
+
+/// Splits the voices between the channels. Every channel gets one voice, as long as there
+/// are enough, the rest goes to the channels that are short of the most voices.
+fn zones(peaks: &BTreeMap<u8, usize>, voices: usize) -> BTreeMap<u8, usize> {
+    let mut by_peak: Vec<(u8, usize)> = peaks.iter().map(|(&c, &p)| (c, p)).collect();
+    // stable, so lower channels win ties
+    by_peak.sort_by_key(|&(_, peak)| std::cmp::Reverse(peak));
+
+    let mut zones: BTreeMap<u8, usize> = peaks.keys().map(|&channel| (channel, 0)).collect();
+    let mut free = voices;
+    for &(channel, _) in by_peak.iter().take(voices) {
+        zones.insert(channel, 1);
+        free -= 1;
+    }
+    while free > 0 {
+        let short = by_peak
+            .iter()
+            .filter(|&&(channel, peak)| zones[&channel] > 0 && zones[&channel] < peak)
+            .max_by_key(|&&(channel, peak)| (peak - zones[&channel], std::cmp::Reverse(channel)));
+        match short {
+            Some(&(channel, _)) => *zones.get_mut(&channel).unwrap() += 1,
+            None => break,
+        }
+        free -= 1;
+    }
+    zones
+}
+
+/// The voices of every zone, in channel order.
+fn zone_voices(zones: &BTreeMap<u8, usize>) -> BTreeMap<u8, RangeInclusive<usize>> {
+    let mut first = 0;
+    zones
+        .iter()
+        .filter(|&(_, &size)| size > 0)
+        .map(|(&channel, &size)| {
+            first += size;
+            (channel, first - size..=first - 1)
+        })
+        .collect()
+}
+
+/// The zones as `floppoctl` settings, indexed by channel from 0.
+fn zone_settings(voices: &BTreeMap<u8, RangeInclusive<usize>>) -> String {
+    let mut text = String::from("# zones written by floppyarrange\n");
+    for (channel, voices) in voices {
+        writeln!(text, "zone {} {} {}", channel, voices.start(), voices.end()).unwrap();
+    }
+    text
+}
+
+/// Keeps at most `size` notes of a channel at once, returns how many notes were cut short.
+fn limit(notes: &mut [Note], size: usize) -> usize {
+    let mut cut = 0;
+    // indices of the sounding notes, oldest first
+    let mut sounding: Vec<usize> = Vec::new();
+    for index in 0..notes.len() {
+        let start_us = notes[index].start_us;
+        sounding.retain(|&other| notes[other].end_us > start_us);
+        if sounding.len() < size {
+            sounding.push(index);
+            continue;
+        }
+        let lowest = sounding
+            .iter()
+            .copied()
+            .min_by_key(|&other| notes[other].note);
+        let victim = match size {
+            1 => 0,
+            _ => sounding
+                .iter()
+                .position(|&other| Some(other) != lowest)
+                .unwrap(),
+        };
+        notes[sounding.remove(victim)].end_us = start_us;
+        sounding.push(index);
+        cut += 1;
+    }
+    cut
+}
+
+fn write_vlq(out: &mut Vec<u8>, value: u32) {
+    let mut bytes = vec![(value & 0x7f) as u8];
+    let mut rest = value >> 7;
+    while rest > 0 {
+        bytes.push((rest & 0x7f) as u8 | 0x80);
+        rest >>= 7;
+    }
+    out.extend(bytes.iter().rev());
+}
+
+/// A format 0 file with one tick per millisecond.
+fn write_smf(notes: &[Note]) -> Vec<u8> {
+    // tick, note on, then channel, note and velocity; offs sort before ons
+    let tick = |us| (us + TICK_US / 2) / TICK_US;
+    let mut events: Vec<(u64, bool, u8, u8, u8)> = notes
+        .iter()
+        // notes shorter than a tick would end before they start
+        .filter(|note| tick(note.end_us) > tick(note.start_us))
+        .flat_map(|note| {
+            [
+                (
+                    tick(note.start_us),
+                    true,
+                    note.channel,
+                    note.note,
+                    note.velocity,
+                ),
+                (tick(note.end_us), false, note.channel, note.note, 0),
+            ]
+        })
+        .collect();
+    events.sort();
+
+    let mut track = vec![0, 0xff, 0x51, 3];
+    track.extend_from_slice(&TEMPO_US.to_be_bytes()[1..]);
+    let mut last = 0;
+    for (at, on, channel, note, velocity) in events {
+        write_vlq(&mut track, (at - last) as u32);
+        last = at;
+        match on {
+            true => track.extend_from_slice(&[0x90 | channel, note, velocity]),
+            false => track.extend_from_slice(&[0x80 | channel, note, 0]),
+        }
+    }
+    track.extend_from_slice(&[0, 0xff, 0x2f, 0]);
+
+    let mut out = b"MThd".to_vec();
+    out.extend_from_slice(&6u32.to_be_bytes());
+    out.extend_from_slice(&0u16.to_be_bytes());
+    out.extend_from_slice(&1u16.to_be_bytes());
+    out.extend_from_slice(&DIVISION.to_be_bytes());
+    out.extend_from_slice(b"MTrk");
+    out.extend_from_slice(&(track.len() as u32).to_be_bytes());
+    out.extend(track);
+    out
+}
+
+fn time(us: u64) -> String {
+    let ms = us / 1000;
+    format!("{}:{:02}.{:03}", ms / 60_000, ms / 1000 % 60, ms % 1000)
+}
+
+fn main() {
+    let options = parse_options();
+    let data = fs::read(&options.song).unwrap_or_else(|err| {
+        eprintln!("can't read {}: {}", options.song, err);
+        exit(1)
+    });
+    let smf = Smf::parse(&data).unwrap_or_else(|err| {
+        eprintln!("can't read {}: {:?}", options.song, err);
+        exit(1)
+    });
+    let voices = mode_voice_count(options.mode) as usize;
+    let (mut notes, end_us) = read_notes(&smf);
+    println!(
+        "{}: {} notes in {}, {} voices in {:?} mode",
+        options.song,
+        notes.len(),
+        time(end_us),
+        voices,
+        options.mode
+    );
+
+    let passages = overloads(&notes, voices);
+    if !passages.is_empty() {
+        println!("more notes than voices:");
+    }
+    for &(start, end, peak) in passages.iter().take(REPORT_LINES) {
+        println!("  {} - {}  {} notes", time(start), time(end), peak);
+    }
+    if passages.len() > REPORT_LINES {
+        println!("  and {} more", passages.len() - REPORT_LINES);
+    }
+
+    let before = notes.len();
+    if !options.keep_drums {
+        notes.retain(|note| note.channel != DRUM_CHANNEL);
+    }
+    let drums = before - notes.len();
+    let mut folded = 0;
+    for note in &mut notes {
+        let moved = fold(note.note, options.range(note.channel));
+        folded += (moved != note.note) as usize;
+        note.note = moved;
+    }
+    let doublings = drop_doublings(&mut notes);
+
+    let mut channels: BTreeMap<u8, Vec<Note>> = BTreeMap::new();
+    for note in notes {
+        channels.entry(note.channel).or_default().push(note);
+    }
+    let peaks: BTreeMap<u8, usize> = channels
+        .iter()
+        .map(|(&channel, notes)| (channel, peak(notes)))
+        .collect();
+    let zones = zones(&peaks, voices);
+    let zone_voices = zone_voices(&zones);
+
+    println!("zones:");
+    let mut cut = 0;
+    let mut dropped = 0;
+    let mut reduced = Vec::new();
+    for (channel, mut notes) in channels {
+        let size = zones[&channel];
+        match size {
+            0 => println!("  channel {:2}: dropped", channel + 1),
+            _ => println!(
+                "  channel {:2}: voices {}-{}, {} of {} notes at once",
+                channel + 1,
+                zone_voices[&channel].start() + 1,
+                zone_voices[&channel].end() + 1,
+                size,
+                peaks[&channel]
+            ),
+        }
+        if size == 0 {
+            dropped += notes.len();
+            continue;
+        }
+        cut += limit(&mut notes, size);
+        reduced.extend(notes.into_iter().filter(|note| note.end_us > note.start_us));
+    }
+    reduced.sort_by_key(|note| (note.start_us, note.channel, note.note));
+
+    println!(
+        "{} drum notes and {} doublings dropped, {} notes folded, {} cut short, \
+         {} dropped with their channel",
+        drums, doublings, folded, cut, dropped
+    );
+    if let Err(err) = fs::write(&options.out, write_smf(&reduced)) {
+        eprintln!("can't write {}: {}", options.out, err);
+        exit(1);
+    }
+    if let Some(path) = &options.settings {
+        if let Err(err) = fs::write(path, zone_settings(&zone_voices)) {
+            eprintln!("can't write {}: {}", path, err);
+            exit(1);
+        }
+    }
+    println!(
+        "{}: {} notes, at most {} at once",
+        options.out,
+        reduced.len(),
+        peak(&reduced)
+    );
+}
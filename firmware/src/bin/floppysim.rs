@@ -0,0 +1,304 @@
+//! Renders a MIDI file to a WAV file the way the drives would play it.
+//!
+//! The file goes through the voice allocation, the oscillator configurations and the head
+//! bounce rules of the firmware, the PWM slices run in the emulator of `oscillators` and
+//! only the pins are simulated. Time is counted in system clock cycles, every head step is
+//! rendered as a click.
+//!
+//!     cargo run --features sim --target x86_64-unknown-linux-gnu --bin floppysim -- \
+//!         songs/01-demo.mid demo.wav --mode inverse
+//!
+//! The simulation follows the software stepping of the default build.
+
+use std::{cell::RefCell, convert::Infallible, fs, process::exit, rc::Rc};
+
+use embedded_hal::digital::v2::OutputPin;
+use floppotron_jr::{
+    floppy::{FloppyImpl, DRIVE_COUNT},
+    oscillators::{
+        emulator::{EmulatedSlice, PwmEmulator, SYS_CLOCK_HZ},
+        OscConfiguration,
+    },
+    smf::{Player, Smf},
+    voices::{VoiceAllocator, ALL_VOICES},
+};
+use midi_port::MidiMessage;
+
+// rendered after the last event, so the last notes can end
+const TAIL_US: u64 = 500_000;
+const CLICK_US: u64 = 3_000;
+
+/// A pin whose level the simulation can read.
+#[derive(Clone, Default)]
+struct SimPin(Rc<RefCell<bool>>);
+
+impl SimPin {
+    fn is_high(&self) -> bool {
+        *self.0.borrow()
+    }
+}
+
+impl OutputPin for SimPin {
+    type Error = Infallible;
+
+    fn set_low(&mut self) -> Result<(), Infallible> {
+        *self.0.borrow_mut() = false;
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Infallible> {
+        *self.0.borrow_mut() = true;
+        Ok(())
+    }
+}
+
+type SimDrive = FloppyImpl<SimPin, SimPin, SimPin>;
+
+struct Options {
+    song: String,
+    wav: String,
+    mode: String,
+    transpose: i16,
+    rate: u32,
+}
+
+fn usage() -> ! {
+    eprintln!(
+        "usage: floppysim <song.mid> <out.wav> [--mode single|inverse|unisono] \
+         [--transpose <semitones>] [--rate <hz>]"
+    );
+    exit(2)
+}
+
+fn parse_options() -> Options {
+    let mut args = std::env::args().skip(1);
+    let mut files = Vec::new();
+    let mut options = Options {
+        song: String::new(),
+        wav: String::new(),
+        mode: "single".into(),
+        transpose: 0,
+        rate: 44_100,
+    };
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().unwrap_or_else(|| usage());
+        match arg.as_str() {
+            "--mode" => options.mode = value(),
+            "--transpose" => options.transpose = value().parse().unwrap_or_else(|_| usage()),
+            "--rate" => options.rate = value().parse().unwrap_or_else(|_| usage()),
+            _ if arg.starts_with("--") => usage(),
+            _ => files.push(arg),
+        }
+    }
+    match <[String; 2]>::try_from(files) {
+        Ok([song, wav]) => {
+            options.song = song;
+            options.wav = wav;
+        }
+        Err(_) => usage(),
+    }
+    options
+}
+
+/// The step and direction pins of every drive and the steps they made.
+struct Recorder {
+    pins: Vec<(SimPin, SimPin)>,
+    last_levels: Vec<(bool, bool)>,
+    // cycle, drive and whether the head turned around
+    clicks: Vec<(u64, usize, bool)>,
+}
+
+impl Recorder {
+    // a step pulse ends with the falling edge, that is when `FloppyImpl` moves the head
+    fn record_steps(&mut self, now: u64) {
+        for (drive, (step, dir)) in self.pins.iter().enumerate() {
+            let levels = (step.is_high(), dir.is_high());
+            let (last_step, last_dir) = self.last_levels[drive];
+            if last_step && !levels.0 {
+                self.clicks.push((now, drive, last_dir != levels.1));
+            }
+            self.last_levels[drive] = levels;
+        }
+    }
+}
+
+/// The drives and slices of the simulation and what they did.
+struct Machine {
+    config: OscConfiguration<SimDrive, EmulatedSlice<'static>>,
+    voices: VoiceAllocator,
+    transpose: i16,
+    pwm: &'static PwmEmulator,
+    recorder: Recorder,
+}
+
+impl Machine {
+    fn new(mode: &str, transpose: i16) -> Self {
+        // the oscillators keep their slices for as long as the simulation runs
+        let pwm: &'static PwmEmulator = Box::leak(Box::default());
+        let slices = core::array::from_fn(|num| pwm.slice(num as u8));
+        let mut pins = Vec::new();
+        let drives = core::array::from_fn(|_| {
+            let (step, dir) = (SimPin::default(), SimPin::default());
+            pins.push((step.clone(), dir.clone()));
+            FloppyImpl::new(step, dir, SimPin::default())
+        });
+        let config = match mode {
+            "single" => OscConfiguration::new_single(slices, drives),
+            "inverse" => OscConfiguration::new_inverse(slices, drives),
+            "unisono" => OscConfiguration::new_unisono(slices, drives),
+            _ => usage(),
+        };
+        Self {
+            voices: VoiceAllocator::new(config.oscillator_count() as usize),
+            config,
+            transpose,
+            pwm,
+            recorder: Recorder {
+                last_levels: vec![(false, false); pins.len()],
+                pins,
+                clicks: Vec::new(),
+            },
+        }
+    }
+
+    // the part of `MidiHandler` that deals with notes, every channel plays on all voices
+    fn handle_message(&mut self, msg: MidiMessage) {
+        match msg {
+            MidiMessage::NoteOn { note, velocity, .. } if velocity > 0 => {
+                let transposed = note as i16 + self.transpose;
+                if !(0..128).contains(&transposed) {
+                    return;
+                }
+                if let Some(voice) = self.voices.play_note(note, ALL_VOICES) {
+                    self.config.set_voice(voice as u8, transposed as u8, 0);
+                }
+            }
+            MidiMessage::NoteOn { note, .. } | MidiMessage::NoteOff { note, .. } => {
+                if let Some(voice) = self.voices.stop_note(note) {
+                    self.config.stop_voice(voice as u8);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    /// Advances the slices to cycle `to`, handling every wrap on the way.
+    fn run(&mut self, to: u64) {
+        let (config, recorder) = (&mut self.config, &mut self.recorder);
+        self.pwm.run_until(to, |now, pending| {
+            config.handle_interrupt(pending);
+            recorder.record_steps(now);
+        });
+    }
+}
+
+fn cycles(us: u64) -> u64 {
+    us * (SYS_CLOCK_HZ / 1_000_000)
+}
+
+// a damped resonance with a bit of noise, louder when the head turns around
+fn click(rate: u32, turn: bool) -> Vec<f32> {
+    let len = (CLICK_US * rate as u64 / 1_000_000) as usize;
+    let mut noise = 0x1234_5678u32;
+    (0..len)
+        .map(|i| {
+            let t = i as f32 / rate as f32;
+            noise = noise.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            let white = (noise >> 8) as f32 / (1 << 23) as f32 - 1.0;
+            let tone = (t * 2.0 * core::f32::consts::PI * 1_800.0).sin();
+            let level = match turn {
+                true => 0.5,
+                false => 0.3,
+            };
+            level * (-t / 0.000_6).exp() * (0.7 * tone + 0.3 * white)
+        })
+        .collect()
+}
+
+/// Mixes the clicks into stereo samples, the drives are spread from left to right.
+fn render(clicks: &[(u64, usize, bool)], end: u64, rate: u32) -> Vec<i16> {
+    let frames = (end as u128 * rate as u128 / SYS_CLOCK_HZ as u128) as usize;
+    let mut mix = vec![0f32; 2 * frames];
+    let sounds = [click(rate, false), click(rate, true)];
+    for &(at, drive, turn) in clicks {
+        let frame = (at as u128 * rate as u128 / SYS_CLOCK_HZ as u128) as usize;
+        let pan = match DRIVE_COUNT {
+            1 => 0.5,
+            _ => drive as f32 / (DRIVE_COUNT - 1) as f32,
+        };
+        for (i, sample) in sounds[turn as usize].iter().enumerate() {
+            if let Some(out) = mix.get_mut(2 * (frame + i)..2 * (frame + i) + 2) {
+                out[0] += sample * (1.0 - pan);
+                out[1] += sample * pan;
+            }
+        }
+    }
+
+    let peak = mix.iter().fold(1f32, |peak, sample| peak.max(sample.abs()));
+    mix.iter()
+        .map(|sample| (sample / peak * i16::MAX as f32) as i16)
+        .collect()
+}
+
+fn wav(samples: &[i16], rate: u32) -> Vec<u8> {
+    let channels = 2u16;
+    let data_len = 2 * samples.len() as u32;
+    let mut out = Vec::with_capacity(44 + data_len as usize);
+    out.extend_from_slice(b"RIFF");
+    out.extend_from_slice(&(36 + data_len).to_le_bytes());
+    out.extend_from_slice(b"WAVEfmt ");
+    out.extend_from_slice(&16u32.to_le_bytes());
+    // PCM
+    out.extend_from_slice(&1u16.to_le_bytes());
+    out.extend_from_slice(&channels.to_le_bytes());
+    out.extend_from_slice(&rate.to_le_bytes());
+    out.extend_from_slice(&(rate * 2 * channels as u32).to_le_bytes());
+    out.extend_from_slice(&(2 * channels).to_le_bytes());
+    out.extend_from_slice(&16u16.to_le_bytes());
+    out.extend_from_slice(b"data");
+    out.extend_from_slice(&data_len.to_le_bytes());
+    for sample in samples {
+        out.extend_from_slice(&sample.to_le_bytes());
+    }
+    out
+}
+
+fn main() {
+    let options = parse_options();
+    let data = fs::read(&options.song).unwrap_or_else(|err| {
+        eprintln!("can't read {}: {}", options.song, err);
+        exit(1)
+    });
+    let smf = Smf::parse(&data).unwrap_or_else(|err| {
+        eprintln!("can't play {}: {:?}", options.song, err);
+        exit(1)
+    });
+
+    let mut player = Player::new(&smf);
+    let mut machine = Machine::new(&options.mode, options.transpose);
+    // MIDI messages are handled once per millisecond, like the main loop polls the player
+    let mut us = 0;
+    while !player.is_finished() {
+        while let Some(msg) = player.next_due(us) {
+            machine.handle_message(msg);
+        }
+        machine.run(cycles(us + 1000));
+        us += 1000;
+    }
+    machine.run(cycles(us + TAIL_US));
+    let end = cycles(us + TAIL_US);
+
+    let samples = render(&machine.recorder.clicks, end, options.rate);
+    if let Err(err) = fs::write(&options.wav, wav(&samples, options.rate)) {
+        eprintln!("can't write {}: {}", options.wav, err);
+        exit(1);
+    }
+    println!(
+        "{}: {:.1} s, {} steps on {} drives in {} mode",
+        options.wav,
+        end as f64 / SYS_CLOCK_HZ as f64,
+        machine.recorder.clicks.len(),
+        DRIVE_COUNT,
+        options.mode
+    );
+}
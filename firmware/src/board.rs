@@ -0,0 +1,54 @@
+//! Board selection and pin assignments.
+//!
+//! One of the `board-*` features picks the HAL crate and the crystal frequency,
+//! the pins are generated by `build.rs` from a file in `boards/`.
+//! `split_pins` takes every pin listed in the board file out of `Pins`, conflicting or
+//! invalid pin assignments fail the build.
+
+#[cfg(not(any(
+    feature = "board-pico",
+    feature = "board-pro-micro",
+    feature = "board-rp2040"
+)))]
+compile_error!(
+    "select a board with one of the board-pico, board-pro-micro or board-rp2040 features"
+);
+
+#[cfg(any(
+    all(feature = "board-pico", feature = "board-pro-micro"),
+    all(feature = "board-pico", feature = "board-rp2040"),
+    all(feature = "board-pro-micro", feature = "board-rp2040")
+))]
+compile_error!("the board-* features are mutually exclusive, use --no-default-features");
+
+#[cfg(feature = "board-pico")]
+pub use rp_pico::{hal, XOSC_CRYSTAL_FREQ};
+
+// the Pro Micro BSP only adds pin names, the board file covers those
+#[cfg(not(feature = "board-pico"))]
+pub use rp2040_hal as hal;
+
+/// Both the Pro Micro and the RP2040 hardware design guide use a 12 MHz crystal.
+#[cfg(not(feature = "board-pico"))]
+pub const XOSC_CRYSTAL_FREQ: u32 = 12_000_000;
+
+use crate::hal::{
+    gpio::{bank0::*, DynPin, FunctionUart, Pin, Pins},
+    pac,
+};
+
+pub struct DrivePins {
+    pub step: DynPin,
+    pub dir: DynPin,
+    pub enable: DynPin,
+}
+
+pub struct BoardPins {
+    pub drives: [DrivePins; DRIVE_COUNT],
+    pub midi_uart: MidiUartPins,
+    pub led: DynPin,
+    /// optional, see `button`
+    pub button: Option<DynPin>,
+}
+
+include!(concat!(env!("OUT_DIR"), "/board.rs"));
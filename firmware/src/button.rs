@@ -0,0 +1,117 @@
+//! A push button to ground on the gpio named `button` in the board file.
+
+use embedded_hal::digital::v2::InputPin;
+
+// the level has to be stable this long to count
+const DEBOUNCE_US: u64 = 20_000;
+const LONG_PRESS_US: u64 = 1_000_000;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Press {
+    /// released within a second
+    Short,
+    /// held for a second, reported while it is still held
+    Long,
+}
+
+pub struct Button<P: InputPin> {
+    pin: P,
+    level: bool,
+    changed_at: u64,
+    pressed_at: Option<u64>,
+    long_reported: bool,
+}
+
+impl<P: InputPin> Button<P> {
+    /// `pin` has to be an input with pull up.
+    pub fn new(pin: P) -> Self {
+        Self {
+            pin,
+            level: false,
+            changed_at: 0,
+            pressed_at: None,
+            long_reported: false,
+        }
+    }
+
+    pub fn poll(&mut self, now_us: u64) -> Option<Press> {
+        let pressed = self.pin.is_low().unwrap_or(false);
+        self.update(pressed, now_us)
+    }
+
+    fn update(&mut self, pressed: bool, now_us: u64) -> Option<Press> {
+        if pressed != self.level {
+            self.level = pressed;
+            self.changed_at = now_us;
+        }
+        if now_us - self.changed_at < DEBOUNCE_US {
+            return None;
+        }
+
+        match (self.level, self.pressed_at) {
+            (true, None) => {
+                self.pressed_at = Some(now_us);
+                None
+            }
+            (true, Some(at)) if !self.long_reported && now_us - at >= LONG_PRESS_US => {
+                self.long_reported = true;
+                Some(Press::Long)
+            }
+            (false, Some(_)) => {
+                self.pressed_at = None;
+                let long = core::mem::replace(&mut self.long_reported, false);
+                (!long).then_some(Press::Short)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::convert::Infallible;
+
+    use super::*;
+
+    struct NoPin;
+
+    impl InputPin for NoPin {
+        type Error = Infallible;
+
+        fn is_high(&self) -> Result<bool, Infallible> {
+            Ok(true)
+        }
+
+        fn is_low(&self) -> Result<bool, Infallible> {
+            Ok(false)
+        }
+    }
+
+    // feeds `pressed` every millisecond from `from` to `to` and collects the presses
+    fn run(button: &mut Button<NoPin>, pressed: bool, from: u64, to: u64) -> Vec<Press> {
+        (from..to)
+            .step_by(1000)
+            .filter_map(|now| button.update(pressed, now))
+            .collect()
+    }
+
+    #[test]
+    fn short_press_with_bounces() {
+        let mut button = Button::new(NoPin);
+        assert!(run(&mut button, false, 0, 50_000).is_empty());
+        // bouncing contacts
+        for now in [50_000, 52_000, 54_000] {
+            assert_eq!(button.update(true, now), None);
+            assert_eq!(button.update(false, now + 1000), None);
+        }
+        assert!(run(&mut button, true, 56_000, 200_000).is_empty());
+        assert_eq!(run(&mut button, false, 200_000, 300_000), [Press::Short]);
+    }
+
+    #[test]
+    fn long_press() {
+        let mut button = Button::new(NoPin);
+        assert_eq!(run(&mut button, true, 0, 2_000_000), [Press::Long]);
+        assert!(run(&mut button, false, 2_000_000, 2_100_000).is_empty());
+    }
+}
@@ -0,0 +1,244 @@
+//! Commands from the control core to the oscillator core.
+//!
+//! Core 1 owns MIDI input and voice allocation and sends one word per oscillator
+//! change over the SIO FIFO. Core 0 only applies them between its oscillator
+//! interrupts, so a burst of MIDI input can't delay a step.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use crate::hal::{
+    pac,
+    sio::{Sio, SioFifo},
+    watchdog::Watchdog,
+};
+use defmt::{warn, Format};
+use embedded_hal::watchdog::WatchdogEnable;
+use embedded_time::duration::Microseconds;
+
+use crate::oscillators::with_oscillators;
+
+const TAG_PLAY: u32 = 1;
+const TAG_STOP: u32 = 2;
+const TAG_MODE: u32 = 3;
+const TAG_TRACKS: u32 = 4;
+const TAG_PAUSE: u32 = 5;
+
+// SIO FIFO registers, accessed directly while the flash can't be read
+const SIO_FIFO_ST: *const u32 = 0xd000_0050 as *const u32;
+const SIO_FIFO_WR: *mut u32 = 0xd000_0054 as *mut u32;
+const SIO_FIFO_RD: *const u32 = 0xd000_0058 as *const u32;
+const FIFO_ST_VLD: u32 = 1;
+
+/// How long core 0 may go without feeding the watchdog.
+pub const WATCHDOG_TIMEOUT_US: u32 = 200_000;
+// nothing feeds the watchdog during a pause: erasing a 4K sector takes up to 400ms,
+// programming the record up to another 50ms
+const PAUSE_WATCHDOG_TIMEOUT_US: u32 = 1_000_000;
+
+#[derive(Clone, Copy, PartialEq, Debug, Format)]
+pub enum OscMode {
+    Single,
+    Inverse,
+    Unisono,
+}
+
+impl OscMode {
+    pub fn to_bits(self) -> u32 {
+        match self {
+            OscMode::Single => 0,
+            OscMode::Inverse => 1,
+            OscMode::Unisono => 2,
+        }
+    }
+
+    pub fn from_bits(bits: u32) -> Option<Self> {
+        match bits {
+            0 => Some(OscMode::Single),
+            1 => Some(OscMode::Inverse),
+            2 => Some(OscMode::Unisono),
+            _ => None,
+        }
+    }
+
+    /// Whether the oscillators can switch to this mode, hardware stepping only plays the
+    /// single configuration.
+    pub fn is_available(self) -> bool {
+        self == OscMode::Single || !cfg!(any(feature = "hw-step", feature = "pio-step"))
+    }
+}
+
+/// A change of a single voice, a drive or the whole oscillator configuration.
+///
+/// Encoded as one FIFO word: the tag in bits 24..32, the voice or drive in bits 8..16
+/// and the note, mode or last track in bits 0..8. The cents or the first track use
+/// bits 16..24.
+#[derive(Clone, Copy, PartialEq, Format)]
+pub enum OscCommand {
+    /// `note` raised by `cents`, 0 to 99
+    Play {
+        voice: u8,
+        note: u8,
+        cents: u8,
+    },
+    Stop {
+        voice: u8,
+    },
+    Mode(OscMode),
+    Tracks {
+        drive: u8,
+        first: u8,
+        last: u8,
+    },
+    /// Stops core 0 until the next FIFO word, see `OscSender::pause`.
+    Pause,
+}
+
+impl OscCommand {
+    pub fn to_word(self) -> u32 {
+        match self {
+            OscCommand::Play { voice, note, cents } => {
+                TAG_PLAY << 24 | (cents as u32) << 16 | (voice as u32) << 8 | note as u32
+            }
+            OscCommand::Stop { voice } => TAG_STOP << 24 | (voice as u32) << 8,
+            OscCommand::Mode(mode) => TAG_MODE << 24 | mode.to_bits(),
+            OscCommand::Tracks { drive, first, last } => {
+                TAG_TRACKS << 24 | (first as u32) << 16 | (drive as u32) << 8 | last as u32
+            }
+            OscCommand::Pause => TAG_PAUSE << 24,
+        }
+    }
+
+    pub fn from_word(word: u32) -> Option<Self> {
+        let voice = (word >> 8) as u8;
+        let low = word & 0xff;
+        match word >> 24 {
+            TAG_PLAY => Some(OscCommand::Play {
+                voice,
+                note: low as u8,
+                cents: (word >> 16) as u8,
+            }),
+            TAG_STOP => Some(OscCommand::Stop { voice }),
+            TAG_MODE => OscMode::from_bits(low).map(OscCommand::Mode),
+            TAG_TRACKS => Some(OscCommand::Tracks {
+                drive: voice,
+                first: (word >> 16) as u8,
+                last: low as u8,
+            }),
+            TAG_PAUSE => Some(OscCommand::Pause),
+            _ => None,
+        }
+    }
+}
+
+// only written by core 1, core 0 checks that it keeps changing before feeding the watchdog
+static CORE1_HEARTBEAT: AtomicU32 = AtomicU32::new(0);
+
+/// Sends commands from core 1, see `apply_commands` for the other side.
+pub struct OscSender {
+    fifo: SioFifo,
+}
+
+impl OscSender {
+    /// Takes core 1's end of the FIFO and waits for the initial voice count.
+    ///
+    /// Must be called on core 1.
+    pub fn new() -> (Self, usize) {
+        // like the HAL's core 1 startup, there is no other way to get this core's FIFO
+        let mut fifo = Sio::new(unsafe { pac::Peripherals::steal() }.SIO).fifo;
+        let voice_count = fifo.read_blocking() as usize;
+        (Self { fifo }, voice_count)
+    }
+
+    pub fn send(&mut self, command: OscCommand) {
+        self.fifo.write_blocking(command.to_word());
+    }
+
+    /// Switches the oscillator configuration and returns its voice count.
+    pub fn change_mode(&mut self, mode: OscMode) -> usize {
+        self.send(OscCommand::Mode(mode));
+        self.fifo.read_blocking() as usize
+    }
+
+    /// Stops core 0 so the flash can be written, it waits in RAM with its interrupts disabled.
+    pub fn pause(&mut self) {
+        self.send(OscCommand::Pause);
+        self.fifo.read_blocking();
+    }
+
+    /// Lets core 0 continue after `pause`, the flash has to be readable again.
+    pub fn resume(&mut self) {
+        self.fifo.write_blocking(0);
+    }
+
+    pub fn heartbeat(&self) {
+        // thumbv6m has no atomic read-modify-write, but there is only one writer
+        let beat = CORE1_HEARTBEAT.load(Ordering::Relaxed);
+        CORE1_HEARTBEAT.store(beat.wrapping_add(1), Ordering::Relaxed);
+    }
+}
+
+/// Tells core 0 whether core 1 made progress since the last check.
+pub struct Core1Monitor {
+    last_beat: u32,
+}
+
+impl Core1Monitor {
+    pub fn new() -> Self {
+        Self {
+            last_beat: CORE1_HEARTBEAT.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn is_alive(&mut self) -> bool {
+        let beat = CORE1_HEARTBEAT.load(Ordering::Relaxed);
+        let alive = beat != self.last_beat;
+        self.last_beat = beat;
+        alive
+    }
+}
+
+impl Default for Core1Monitor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Confirms a pause and waits for the next FIFO word, without touching the flash.
+#[inline(never)]
+#[link_section = ".data.ram_func"]
+unsafe fn wait_in_ram() {
+    core::ptr::write_volatile(SIO_FIFO_WR, 0);
+    while core::ptr::read_volatile(SIO_FIFO_ST) & FIFO_ST_VLD == 0 {}
+    core::ptr::read_volatile(SIO_FIFO_RD);
+}
+
+/// Applies all pending commands from core 1 to the oscillators.
+///
+/// Must be called on core 0, which feeds `watchdog` with `WATCHDOG_TIMEOUT_US`.
+pub fn apply_commands(fifo: &mut SioFifo, watchdog: &mut Watchdog) {
+    while let Some(word) = fifo.read() {
+        match OscCommand::from_word(word) {
+            Some(OscCommand::Play { voice, note, cents }) => {
+                with_oscillators(|oscs| oscs.set_voice(voice, note, cents))
+            }
+            Some(OscCommand::Stop { voice }) => with_oscillators(|oscs| oscs.stop_voice(voice)),
+            Some(OscCommand::Tracks { drive, first, last }) => {
+                with_oscillators(|oscs| oscs.set_track_range(drive, first, last))
+            }
+            Some(OscCommand::Mode(mode)) => {
+                let voice_count = with_oscillators(|oscs| {
+                    oscs.set_mode(mode);
+                    oscs.voice_count()
+                });
+                fifo.write_blocking(voice_count as u32);
+            }
+            // the oscillator interrupts run from flash as well
+            Some(OscCommand::Pause) => {
+                watchdog.start(Microseconds(PAUSE_WATCHDOG_TIMEOUT_US));
+                cortex_m::interrupt::free(|_| unsafe { wait_in_ram() });
+                watchdog.start(Microseconds(WATCHDOG_TIMEOUT_US));
+            }
+            None => warn!("unknown oscillator command {:x}", word),
+        }
+    }
+}
@@ -1,6 +1,14 @@
-use core::{convert::Infallible, fmt::Debug};
+use crate::hal::gpio::DynPin;
+use core::fmt::Debug;
+use defmt::Format;
 use embedded_hal::digital::v2::{OutputPin, PinState};
-use rp_pico::hal::gpio::{bank0::*, Output, Pin, PushPull};
+
+use crate::board::DrivePins;
+#[cfg(any(feature = "hw-step", feature = "pio-step"))]
+use crate::hal::gpio::{DynFunction, DynPinMode};
+
+#[cfg(all(feature = "hw-step", feature = "pio-step"))]
+compile_error!("the hw-step and pio-step features are mutually exclusive");
 
 #[derive(Clone, Copy, PartialEq)]
 pub enum FloppyDirection {
@@ -12,34 +20,128 @@ pub trait Floppy {
     fn set_enabled(&mut self, enabled: bool) -> Result<(), FloppyError>;
     fn step(&mut self) -> Result<(), FloppyError>;
     fn get_dir(&self) -> FloppyDirection;
+    fn error_count(&self) -> u32;
+    /// Limits the head movement to the tracks `first..=last`.
+    fn set_track_range(&mut self, first: u8, last: u8);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub enum FloppyError {
+    Disabled,
+    OutOfRange,
+    PinFault,
+}
+
+pub const LAST_TRACK: u8 = 79;
+
+// head position bookkeeping shared by the floppy implementations
+struct Head {
+    track_index: u8,
+    dir: FloppyDirection,
+    first: u8,
+    last: u8,
+    // the position is only known after the head reached track 0 once
+    homed: bool,
+}
+
+impl Head {
+    fn new() -> Self {
+        Self {
+            track_index: 80,
+            dir: FloppyDirection::Backward,
+            first: 0,
+            last: LAST_TRACK,
+            homed: false,
+        }
+    }
+
+    fn set_range(&mut self, first: u8, last: u8) {
+        self.last = last.min(LAST_TRACK);
+        self.first = first.min(self.last.saturating_sub(1));
+    }
+
+    // moves the head by one track, returns whether it has to turn around
+    fn advance(&mut self) -> Result<bool, FloppyError> {
+        match self.dir {
+            FloppyDirection::Forward => {
+                self.track_index = self.track_index.saturating_add(1);
+                if self.track_index >= self.last {
+                    self.dir = FloppyDirection::Backward;
+                    return Ok(true);
+                }
+            }
+            FloppyDirection::Backward => {
+                self.track_index = self.track_index.saturating_sub(1);
+                self.homed |= self.track_index == 0;
+                if self.homed && self.track_index <= self.first {
+                    self.dir = FloppyDirection::Forward;
+                    return Ok(true);
+                }
+            }
+        }
+
+        // the head can only be off the track range if the bookkeeping went wrong,
+        // clamp it and carry on in the direction that leads back
+        if self.track_index > LAST_TRACK {
+            self.track_index = LAST_TRACK;
+            self.dir = FloppyDirection::Backward;
+            return Err(FloppyError::OutOfRange);
+        }
+        Ok(false)
+    }
+
+    // advances the head and updates the direction pin if necessary
+    fn step<D: OutputPin>(&mut self, pin_dir: &mut D) -> Result<(), FloppyError> {
+        let result = self.advance();
+        if result != Ok(false) {
+            pin_dir
+                .set_state(match self.dir {
+                    FloppyDirection::Forward => PinState::High,
+                    FloppyDirection::Backward => PinState::Low,
+                })
+                .map_err(|_| FloppyError::PinFault)?;
+        }
+        result.map(|_| ())
+    }
+}
+
+fn set_enable_pin<E: OutputPin>(pin_en: &mut E, enabled: bool) -> Result<(), FloppyError> {
+    pin_en
+        .set_state(match enabled {
+            true => PinState::High,
+            false => PinState::Low,
+        })
+        .map_err(|_| FloppyError::PinFault)
+}
+
+fn count_error(error_count: &mut u32, result: Result<(), FloppyError>) -> Result<(), FloppyError> {
+    if result.is_err() {
+        *error_count = error_count.saturating_add(1);
+    }
+    result
 }
 
 pub struct FloppyImpl<S, D, E>
 where
-    S: OutputPin<Error = Infallible>,
-    D: OutputPin<Error = Infallible>,
-    E: OutputPin<Error = Infallible>,
+    S: OutputPin,
+    D: OutputPin,
+    E: OutputPin,
 {
     pin_step: S,
     pin_dir: D,
     pin_en: E,
 
     enabled: bool,
-    track_index: u8,
+    head: Head,
     step_state: PinState,
-    dir: FloppyDirection,
-}
-
-#[derive(Debug)]
-pub enum FloppyError {
-    Disabled,
+    error_count: u32,
 }
 
 impl<S, D, E> FloppyImpl<S, D, E>
 where
-    S: OutputPin<Error = Infallible>,
-    D: OutputPin<Error = Infallible>,
-    E: OutputPin<Error = Infallible>,
+    S: OutputPin,
+    D: OutputPin,
+    E: OutputPin,
 {
     pub fn new(pin_step: S, pin_dir: D, pin_en: E) -> Self {
         Self {
@@ -48,99 +150,177 @@ where
             pin_en,
 
             enabled: false,
-            track_index: 80,
+            head: Head::new(),
             step_state: PinState::Low,
-            dir: FloppyDirection::Backward,
+            error_count: 0,
         }
     }
+
+    fn try_step(&mut self) -> Result<(), FloppyError> {
+        if !self.enabled {
+            return Err(FloppyError::Disabled);
+        }
+
+        self.step_state = !self.step_state;
+
+        let result = match self.step_state {
+            PinState::Low => self.head.step(&mut self.pin_dir),
+            PinState::High => Ok(()),
+        };
+
+        self.pin_step
+            .set_state(self.step_state)
+            .map_err(|_| FloppyError::PinFault)?;
+        result
+    }
 }
 
 impl<S, D, E> Floppy for FloppyImpl<S, D, E>
 where
-    S: OutputPin<Error = Infallible>,
-    D: OutputPin<Error = Infallible>,
-    E: OutputPin<Error = Infallible>,
+    S: OutputPin,
+    D: OutputPin,
+    E: OutputPin,
 {
     fn set_enabled(&mut self, enabled: bool) -> Result<(), FloppyError> {
-        self.pin_en
-            .set_state(match enabled {
-                true => PinState::High,
-                false => PinState::Low,
-            })
-            .unwrap();
-        self.enabled = enabled;
-        Ok(())
+        let result = set_enable_pin(&mut self.pin_en, enabled);
+        self.enabled = enabled && result.is_ok();
+        count_error(&mut self.error_count, result)
     }
 
     fn step(&mut self) -> Result<(), FloppyError> {
-        if !self.enabled {
-            return Err(FloppyError::Disabled);
-        }
+        let result = self.try_step();
+        count_error(&mut self.error_count, result)
+    }
 
-        self.step_state = !self.step_state;
+    fn get_dir(&self) -> FloppyDirection {
+        self.head.dir
+    }
 
-        if self.step_state == PinState::Low {
-            match self.dir {
-                FloppyDirection::Forward => {
-                    self.track_index += 1;
-                    if self.track_index >= 79 {
-                        self.dir = FloppyDirection::Backward;
-                        self.pin_dir.set_low().unwrap();
-                    }
-                }
-                FloppyDirection::Backward => {
-                    self.track_index -= 1;
-                    if self.track_index <= 0 {
-                        self.dir = FloppyDirection::Forward;
-                        self.pin_dir.set_high().unwrap();
-                    }
-                }
-            }
+    fn error_count(&self) -> u32 {
+        self.error_count
+    }
+
+    fn set_track_range(&mut self, first: u8, last: u8) {
+        self.head.set_range(first, last);
+    }
+}
+
+/// A floppy whose step pin is driven by hardware, either by the channel output of its
+/// oscillator's PWM slice or by a PIO state machine.
+///
+/// The hardware generates one step pulse per period, `step` is called from the interrupt
+/// once per pulse and only keeps track of the head to flip the direction pin.
+pub struct HwStepFloppy<S, D, E>
+where
+    D: OutputPin,
+    E: OutputPin,
+{
+    // never toggled here, the pin is in PWM or PIO function
+    pin_step: S,
+    pin_dir: D,
+    pin_en: E,
+
+    enabled: bool,
+    head: Head,
+    error_count: u32,
+}
+
+impl<S, D, E> HwStepFloppy<S, D, E>
+where
+    D: OutputPin,
+    E: OutputPin,
+{
+    pub fn new(pin_step: S, pin_dir: D, pin_en: E) -> Self {
+        Self {
+            pin_step,
+            pin_dir,
+            pin_en,
+
+            enabled: false,
+            head: Head::new(),
+            error_count: 0,
         }
+    }
+}
+
+impl<D, E> HwStepFloppy<DynPin, D, E>
+where
+    D: OutputPin,
+    E: OutputPin,
+{
+    pub fn step_pin(&self) -> u8 {
+        self.pin_step.id().num
+    }
+}
 
-        self.pin_step.set_state(self.step_state).unwrap();
-        Ok(())
+impl<S, D, E> Floppy for HwStepFloppy<S, D, E>
+where
+    D: OutputPin,
+    E: OutputPin,
+{
+    fn set_enabled(&mut self, enabled: bool) -> Result<(), FloppyError> {
+        let result = set_enable_pin(&mut self.pin_en, enabled);
+        self.enabled = enabled && result.is_ok();
+        count_error(&mut self.error_count, result)
+    }
+
+    fn step(&mut self) -> Result<(), FloppyError> {
+        let result = match self.enabled {
+            true => self.head.step(&mut self.pin_dir),
+            false => Err(FloppyError::Disabled),
+        };
+        count_error(&mut self.error_count, result)
     }
 
     fn get_dir(&self) -> FloppyDirection {
-        self.dir
+        self.head.dir
+    }
+
+    fn error_count(&self) -> u32 {
+        self.error_count
+    }
+
+    fn set_track_range(&mut self, first: u8, last: u8) {
+        self.head.set_range(first, last);
     }
 }
 
-pub type Floppy0 = FloppyImpl<
-    Pin<Gpio26, Output<PushPull>>,
-    Pin<Gpio27, Output<PushPull>>,
-    Pin<Gpio28, Output<PushPull>>,
->;
-
-pub type Floppy1 = FloppyImpl<
-    Pin<Gpio7, Output<PushPull>>,
-    Pin<Gpio6, Output<PushPull>>,
-    Pin<Gpio5, Output<PushPull>>,
->;
-
-pub type Floppy2 = FloppyImpl<
-    Pin<Gpio20, Output<PushPull>>,
-    Pin<Gpio21, Output<PushPull>>,
-    Pin<Gpio22, Output<PushPull>>,
->;
-
-pub type Floppy3 = FloppyImpl<
-    Pin<Gpio11, Output<PushPull>>,
-    Pin<Gpio10, Output<PushPull>>,
-    Pin<Gpio9, Output<PushPull>>,
->;
-
-pub type Floppy4 = FloppyImpl<
-    Pin<Gpio16, Output<PushPull>>,
-    Pin<Gpio17, Output<PushPull>>,
-    Pin<Gpio18, Output<PushPull>>,
->;
-
-pub type Floppy5 = FloppyImpl<
-    Pin<Gpio15, Output<PushPull>>,
-    Pin<Gpio14, Output<PushPull>>,
-    Pin<Gpio13, Output<PushPull>>,
->;
-
-pub type Floppies = (Floppy0, Floppy1, Floppy2, Floppy3, Floppy4, Floppy5);
+pub use crate::board::DRIVE_COUNT;
+
+#[cfg(not(any(feature = "hw-step", feature = "pio-step")))]
+pub type Drive = FloppyImpl<DynPin, DynPin, DynPin>;
+#[cfg(any(feature = "hw-step", feature = "pio-step"))]
+pub type Drive = HwStepFloppy<DynPin, DynPin, DynPin>;
+
+pub type Floppies = [Drive; DRIVE_COUNT];
+
+/// Creates drive `index`, switching its pins to the modes the step backend needs.
+///
+/// With PIO stepping drives 0 to 3 are driven by PIO0 and the others by PIO1.
+pub fn new_drive(index: usize, pins: DrivePins) -> Drive {
+    let DrivePins {
+        step: mut pin_step,
+        dir: mut pin_dir,
+        enable: mut pin_en,
+    } = pins;
+    pin_dir.into_push_pull_output();
+    pin_en.into_push_pull_output();
+
+    #[cfg(not(any(feature = "hw-step", feature = "pio-step")))]
+    pin_step.into_push_pull_output();
+    #[cfg(feature = "hw-step")]
+    pin_step
+        .try_into_mode(DynPinMode::Function(DynFunction::Pwm))
+        .unwrap();
+    #[cfg(feature = "pio-step")]
+    pin_step
+        .try_into_mode(DynPinMode::Function(match index {
+            0..=3 => DynFunction::Pio0,
+            _ => DynFunction::Pio1,
+        }))
+        .unwrap();
+    #[cfg(not(feature = "pio-step"))]
+    let _ = index;
+
+    Drive::new(pin_step, pin_dir, pin_en)
+}
@@ -0,0 +1,499 @@
+//! Turns the merged MIDI input into oscillator commands on core 1.
+
+use defmt::{info, warn};
+use embedded_hal::digital::v2::OutputPin;
+use midi_port::MidiMessage;
+
+use crate::{
+    button::{Button, Press},
+    control::{OscCommand, OscMode, OscSender},
+    floppy::DRIVE_COUNT,
+    hal::{gpio::DynPin, Timer},
+    merge::{MidiMerge, MidiSource, SOURCES},
+    midi::MidiEvent,
+    midi_out::MidiOut,
+    player::SongPlayer,
+    presets::{find_preset, BankSelect, Preset, PresetName, Presets, FACTORY_BANK, USER_BANK},
+    settings::{index_count, Settings},
+    store::{FlashStore, Stored},
+    stream::Stream,
+    sysex::{self, Param, Reply, Request, Status, SysExError, BROADCAST_ID},
+    usb_midi::UsbMidiOut,
+    voices::{VoiceAllocator, VoicePolicy},
+};
+
+// changes are saved once they stop for this long, so sweeping a value doesn't wear the flash
+const SAVE_DELAY_US: u64 = 2_000_000;
+
+/// Everything core 1 keeps between MIDI messages.
+pub struct MidiHandler<IP: OutputPin> {
+    voices: VoiceAllocator,
+    sender: OscSender,
+    merge: MidiMerge,
+    midi_out: MidiOut,
+    // replies to requests that came in over USB
+    usb_out: UsbMidiOut,
+    settings: Settings,
+    presets: Presets,
+    banks: BankSelect,
+    store: FlashStore,
+    timer: Timer,
+    // when the changed settings are due to be saved
+    save_at: Option<u64>,
+    songs: SongPlayer,
+    button: Option<Button<DynPin>>,
+    stream: Stream,
+    // the current bend of the vibrato
+    vibrato_cents: i16,
+    indicator_pin: IP,
+}
+
+impl<IP: OutputPin> MidiHandler<IP> {
+    /// Applies the stored settings, except for the oscillator mode which core 0 starts with.
+    pub fn new(
+        sender: OscSender,
+        voice_count: usize,
+        midi_out: MidiOut,
+        (store, stored): (FlashStore, Stored),
+        timer: Timer,
+        button: Option<DynPin>,
+        indicator_pin: IP,
+    ) -> Self {
+        let mut settings = stored.settings;
+        // stored by a build without hardware stepping, core 0 kept the single mode
+        if !settings.osc_mode.is_available() {
+            settings.osc_mode = OscMode::Single;
+        }
+        let mut handler = Self {
+            voices: VoiceAllocator::new(voice_count),
+            sender,
+            merge: MidiMerge::new(),
+            midi_out,
+            usb_out: UsbMidiOut::new(),
+            settings,
+            presets: stored.presets,
+            banks: BankSelect::new(),
+            store,
+            timer,
+            save_at: None,
+            songs: SongPlayer::new(),
+            button: button.map(Button::new),
+            stream: Stream::new(),
+            vibrato_cents: 0,
+            indicator_pin,
+        };
+        handler.apply_all(false);
+        handler
+    }
+
+    /// Handles a byte from `source` and sends what is due on MIDI out.
+    pub fn put_byte(&mut self, source: MidiSource, byte: u8) {
+        match self.merge.put_byte(source, byte) {
+            Some(MidiEvent::SysEx(data)) => self.handle_sysex(source, &data),
+            Some(event) => self.handle_midi_message(event),
+            None => (),
+        }
+    }
+
+    pub fn poll_output(&mut self) {
+        self.midi_out.poll();
+    }
+
+    /// The replies waiting to be sent over USB.
+    pub fn usb_out(&mut self) -> &mut UsbMidiOut {
+        &mut self.usb_out
+    }
+
+    /// Saves changed settings once they are settled and nothing is playing.
+    ///
+    /// Saving pauses both cores, so it waits for the end of songs and streams.
+    pub fn poll_store(&mut self) {
+        let idle =
+            self.voices.busy_count() == 0 && !self.songs.is_playing() && !self.stream.is_running();
+        match self.save_at {
+            Some(at) if self.timer.get_counter() >= at && idle => {
+                self.save_at = None;
+                self.store
+                    .save(&self.settings, &self.presets, &mut self.sender);
+            }
+            _ => (),
+        }
+    }
+
+    /// Plays the song messages that are due and handles the button.
+    pub fn poll_songs(&mut self) {
+        let now = self.timer.get_counter();
+        match self.button.as_mut().and_then(|button| button.poll(now)) {
+            Some(Press::Short) if self.songs.is_playing() => self.stop_song(now),
+            Some(Press::Short) => self.songs.resume(now),
+            Some(Press::Long) => {
+                self.release_song_notes();
+                self.songs.next_song(now);
+            }
+            None => (),
+        }
+        while let Some(msg) = self.songs.next_due(now) {
+            self.handle_midi_message(MidiEvent::Message(msg));
+        }
+    }
+
+    /// Plays the stream events that are due.
+    pub fn poll_stream(&mut self) {
+        let now = self.timer.get_counter();
+        while let Some(msg) = self.stream.next_due(now) {
+            self.handle_midi_message(MidiEvent::Message(msg));
+        }
+    }
+
+    /// Bends the held notes along with the vibrato.
+    pub fn poll_vibrato(&mut self) {
+        let cents = self.settings.vibrato.offset(self.timer.get_counter());
+        if cents != self.vibrato_cents {
+            self.vibrato_cents = cents;
+            self.play_all_voices();
+        }
+    }
+
+    fn release_stream_notes(&mut self) {
+        for msg in self.stream.release() {
+            self.handle_midi_message(MidiEvent::Message(msg));
+        }
+    }
+
+    fn stop_song(&mut self, now: u64) {
+        self.songs.stop(now);
+        self.release_song_notes();
+    }
+
+    fn release_song_notes(&mut self) {
+        for msg in self.songs.release() {
+            self.handle_midi_message(MidiEvent::Message(msg));
+        }
+    }
+
+    fn schedule_save(&mut self) {
+        self.save_at = Some(self.timer.get_counter() + SAVE_DELAY_US);
+    }
+
+    pub fn heartbeat(&self) {
+        self.sender.heartbeat();
+    }
+
+    /// Turns a MIDI message into oscillator commands, the transport messages control the
+    /// song player.
+    pub fn handle_midi_message(&mut self, event: MidiEvent) {
+        let now = self.timer.get_counter();
+        let msg = match event {
+            MidiEvent::Message(msg) => msg,
+            // requests are handled by `put_byte`, which knows the input to answer on
+            MidiEvent::SysEx(_) => return,
+            MidiEvent::Start => {
+                self.release_song_notes();
+                return self.songs.start(now);
+            }
+            MidiEvent::Continue => return self.songs.resume(now),
+            MidiEvent::Stop => return self.stop_song(now),
+            MidiEvent::SongSelect(song) => {
+                self.release_song_notes();
+                return self.songs.select(song as usize);
+            }
+        };
+        self.midi_out.thru(&msg);
+
+        match msg {
+            MidiMessage::NoteOn {
+                channel,
+                note,
+                velocity: 0,
+            } => {
+                info!("note on event (0): {} {}", channel, note);
+                self.indicator_pin.set_low().unwrap_or(());
+                self.stop_note(channel, note);
+            }
+            MidiMessage::NoteOn {
+                channel,
+                note,
+                velocity,
+            } => {
+                info!("note on event: {} {} {}", channel, note, velocity);
+                self.indicator_pin.set_high().unwrap_or(());
+                self.play_note(channel, note, velocity);
+            }
+            MidiMessage::NoteOff {
+                channel,
+                note,
+                velocity,
+            } => {
+                info!("note off event: {} {} {}", channel, note, velocity);
+                self.indicator_pin.set_low().unwrap_or(());
+                self.stop_note(channel, note);
+            }
+            MidiMessage::ProgramChange {
+                channel: _,
+                program,
+            } => self.change_program(program),
+            MidiMessage::ControlChange {
+                channel: _,
+                controller,
+                value,
+            } => {
+                self.banks.control_change(controller, value);
+            }
+            // TODO
+            MidiMessage::PitchBendChange { channel, value } => {
+                info!("Pitchbend {} {}", channel, value);
+            }
+            _ => (),
+        }
+    }
+
+    // the note the oscillators play with the cents it is raised by, notes that end up
+    // outside of the MIDI range are dropped
+    fn tune(&self, note: u8) -> Option<(u8, u8)> {
+        let note = note as i16 + self.settings.transpose as i16;
+        if !(0..128).contains(&note) {
+            return None;
+        }
+        let cents = note * 100
+            + self.settings.tuning as i16
+            + self.settings.scale_tuning[note as usize % 12] as i16
+            + self.vibrato_cents;
+        let note = cents.div_euclid(100);
+        (0..128)
+            .contains(&note)
+            .then_some((note as u8, cents.rem_euclid(100) as u8))
+    }
+
+    // the voices and MIDI out deal with the received notes, only the oscillators get
+    // tuned ones, so held notes follow transpose and tuning changes
+    fn play_note(&mut self, channel: u8, note: u8, velocity: u8) {
+        if self.tune(note).is_none() {
+            return;
+        }
+        let zone = self.settings.zones[channel as usize % 16];
+        let policy = self.settings.voice_policy;
+        let voice = match policy {
+            // retriggers go to the unit that plays the note
+            VoicePolicy::Overflow if self.midi_out.is_overflowing(channel, note) => None,
+            VoicePolicy::Steal => self.voices.play_note(note, zone),
+            VoicePolicy::Refuse | VoicePolicy::Overflow => self.voices.play_note_free(note, zone),
+        };
+        match voice {
+            Some(voice) => self.play_voice(voice),
+            None if policy == VoicePolicy::Overflow => {
+                self.midi_out.overflow_note_on(channel, note, velocity)
+            }
+            None => info!("no free voice for note {}", note),
+        }
+    }
+
+    fn stop_note(&mut self, channel: u8, note: u8) {
+        if let Some(voice) = self.voices.stop_note(note) {
+            self.sender.send(OscCommand::Stop { voice: voice as u8 });
+        }
+        // the note may have been passed on while all voices were busy
+        self.midi_out.overflow_note_off(channel, note);
+    }
+
+    // sends the note of `voice` to the oscillators
+    fn play_voice(&mut self, voice: usize) {
+        let command = match self.voices.note(voice).and_then(|note| self.tune(note)) {
+            Some((note, cents)) => OscCommand::Play {
+                voice: voice as u8,
+                note,
+                cents,
+            },
+            None => OscCommand::Stop { voice: voice as u8 },
+        };
+        self.sender.send(command);
+    }
+
+    fn play_all_voices(&mut self) {
+        for voice in 0..self.voices.count() {
+            if self.voices.note(voice).is_some() {
+                self.play_voice(voice);
+            }
+        }
+    }
+
+    // switching stops all oscillators, the held notes are played again by the new ones
+    fn change_mode(&mut self, mode: OscMode) {
+        let voice_count = self.sender.change_mode(mode);
+        let dropped = self.voices.resize(voice_count);
+        if !dropped.is_empty() {
+            info!("{} notes dropped by the mode change", dropped.len());
+        }
+        self.play_all_voices();
+    }
+
+    // recalls preset `program` of the selected bank, songs change programs all the time,
+    // so it isn't saved
+    fn change_program(&mut self, program: u8) {
+        let bank = self.banks.bank();
+        match find_preset(bank, program, &self.presets) {
+            Some(preset) if !preset.settings.osc_mode.is_available() => {
+                warn!(
+                    "preset {} of bank {} needs mode {}",
+                    program, bank, preset.settings.osc_mode
+                )
+            }
+            Some(preset) => {
+                info!("recalling preset {} of bank {}", program, bank);
+                preset.recall(&mut self.settings);
+                self.apply_all(true);
+            }
+            None => warn!("no preset {} in bank {}", program, bank),
+        }
+    }
+
+    /// Changes the settings and answers on the input the request came from.
+    fn handle_sysex(&mut self, source: MidiSource, data: &[u8]) {
+        let device_id = self.settings.device_id;
+        let target = sysex::target(data);
+        if target != Some(device_id) {
+            self.midi_out.thru_sysex(data);
+        }
+        if target != Some(device_id) && target != Some(BROADCAST_ID) {
+            return;
+        }
+
+        let request = sysex::parse_request(data);
+        if let Err(err) = request {
+            warn!("bad sysex request: {}", err);
+        }
+        let reply = match request {
+            Ok(Request::Set {
+                param: Param::OscMode,
+                values: &[bits],
+                ..
+            }) if OscMode::from_bits(bits as u32).is_some_and(|mode| !mode.is_available()) => {
+                sysex::error_reply(device_id, data, SysExError::BadValue)
+            }
+            Ok(Request::Get { param, index }) => match self.settings.get(param, index) {
+                Ok(values) => Reply::Value {
+                    param,
+                    index,
+                    values: &values,
+                }
+                .encode(device_id),
+                Err(err) => sysex::error_reply(device_id, data, err),
+            },
+            Ok(Request::Set {
+                param,
+                index,
+                values,
+            }) => match self.settings.set(param, index, values) {
+                Ok(()) => {
+                    self.apply_setting(param, index);
+                    self.schedule_save();
+                    Reply::Ack { param, index }.encode(device_id)
+                }
+                Err(err) => sysex::error_reply(device_id, data, err),
+            },
+            Ok(Request::Status) => Reply::Status(self.status()).encode(device_id),
+            Ok(Request::StorePreset { preset, name }) => {
+                self.store_preset(preset, name);
+                Reply::PresetStored { preset }.encode(device_id)
+            }
+            Ok(Request::PresetName { bank, preset }) => match bank as u16 {
+                FACTORY_BANK | USER_BANK => {
+                    let name = find_preset(bank as u16, preset, &self.presets)
+                        .map(|preset| preset.name)
+                        .unwrap_or_default();
+                    Reply::PresetName {
+                        bank,
+                        preset,
+                        name: &name,
+                    }
+                    .encode(device_id)
+                }
+                _ => sysex::error_reply(device_id, data, SysExError::BadIndex),
+            },
+            Ok(Request::StreamStart) => {
+                let now = self.timer.get_counter();
+                self.release_stream_notes();
+                self.stream.start(now);
+                Reply::StreamState(self.stream.state(now)).encode(device_id)
+            }
+            Ok(Request::StreamEvents(events)) => {
+                match self.stream.queue(events, self.timer.get_counter()) {
+                    // events aren't answered, the host asks for the state to pace itself
+                    Ok(()) => return,
+                    Err(err) => sysex::error_reply(device_id, data, err),
+                }
+            }
+            Ok(Request::StreamStop) => {
+                self.stream.stop();
+                self.release_stream_notes();
+                Reply::StreamState(self.stream.state(0)).encode(device_id)
+            }
+            Ok(Request::StreamStatus) => {
+                let now = self.timer.get_counter();
+                Reply::StreamState(self.stream.state(now)).encode(device_id)
+            }
+            Err(err) => sysex::error_reply(device_id, data, err),
+        };
+        match source {
+            MidiSource::Din => self.midi_out.send_sysex(&reply),
+            MidiSource::Usb => self.usb_out.send_sysex(&reply),
+        }
+    }
+
+    // `parse_request` checked the preset and the length of the name
+    fn store_preset(&mut self, preset: u8, name: &[u8]) {
+        self.presets[preset as usize] = (!name.is_empty()).then(|| Preset {
+            name: PresetName::from_slice(name).unwrap(),
+            settings: self.settings.clone(),
+        });
+        self.schedule_save();
+    }
+
+    // passes every setting on, the oscillator mode only if `with_mode`
+    fn apply_all(&mut self, with_mode: bool) {
+        for param in Param::ALL {
+            if param == Param::OscMode && !with_mode {
+                continue;
+            }
+            for index in 0..index_count(param) {
+                self.apply_setting(param, index as u8);
+            }
+        }
+    }
+
+    // passes a changed setting on to the part it affects
+    fn apply_setting(&mut self, param: Param, index: u8) {
+        let index = index as usize;
+        match param {
+            Param::OscMode => self.change_mode(self.settings.osc_mode),
+            Param::ChannelMap => {
+                let channel = index as u8 % 16;
+                let to = self.settings.channel_maps[index / 16][index % 16];
+                self.merge.remap_channel(SOURCES[index / 16], channel, to);
+            }
+            Param::Transpose | Param::Tuning | Param::ScaleTuning => self.play_all_voices(),
+            Param::TrackRange => {
+                let (first, last) = self.settings.track_ranges[index];
+                self.sender.send(OscCommand::Tracks {
+                    drive: index as u8,
+                    first,
+                    last,
+                });
+            }
+            Param::MidiOut => self.midi_out.set_mode(self.settings.midi_out),
+            // held notes keep their voices, new ones go to the new zone,
+            // `poll_vibrato` follows the vibrato
+            Param::DeviceId | Param::Zone | Param::VoicePolicy | Param::Vibrato => (),
+        }
+    }
+
+    fn status(&self) -> Status {
+        Status {
+            osc_mode: self.settings.osc_mode.to_bits() as u8,
+            voice_count: self.voices.count() as u8,
+            busy_voices: self.voices.busy_count() as u8,
+            drive_count: DRIVE_COUNT as u8,
+            midi_out: self.midi_out.mode().to_bits(),
+            voice_policy: self.settings.voice_policy.to_bits(),
+        }
+    }
+}
@@ -1,19 +1,53 @@
-#![no_main]
-#![no_std]
+// the tests run on the host: cargo test --target x86_64-unknown-linux-gnu
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(not(test), no_std)]
 
+#[cfg(feature = "isr-bench")]
+pub mod bench;
+pub mod board;
+pub mod button;
+pub mod control;
 pub mod floppy;
+pub mod handler;
+pub mod merge;
+pub mod midi;
+pub mod midi_out;
 pub mod note_dict;
 pub mod oscillators;
+pub mod player;
+pub mod presets;
+pub mod safety;
+pub mod settings;
+pub mod smf;
+pub mod store;
+pub mod stream;
+pub mod sysex;
+pub mod usb_midi;
+pub mod voices;
 
-use cortex_m::interrupt::{self, CriticalSection};
+pub use board::hal;
+
+use board::{MidiUart, MidiUartPins};
+use control::{apply_commands, Core1Monitor, OscSender};
+use cortex_m::singleton;
 use defmt::info;
-use embedded_hal::digital::v2::OutputPin;
-use midi_port::MidiMessage;
-use oscillators::OSCILLATORS;
-use rp_pico::hal::{
+use embedded_hal::{digital::v2::OutputPin, watchdog::Watchdog as _};
+use hal::{
+    gpio::DynPin,
     pwm::Slices,
-    uart::{self, UartConfig, UartDevice, UartPeripheral, ValidUartPinout},
+    sio::SioFifo,
+    uart::{self, UartPeripheral},
+    usb::UsbBus,
+    watchdog::Watchdog,
+    Timer,
 };
+use handler::MidiHandler;
+use merge::MidiSource;
+use midi::{init_midi_uart, MidiQueue};
+use oscillators::with_oscillators;
+use store::{FlashStore, Stored};
+use usb_device::{class_prelude::UsbBusAllocator, device::UsbDeviceState};
+use usb_midi::{usb_midi_device, UsbMidiClass};
 
 pub fn deactivate_slice_ints(slices: &mut Slices) {
     slices.pwm0.disable_interrupt();
@@ -26,78 +60,79 @@ pub fn deactivate_slice_ints(slices: &mut Slices) {
     slices.pwm7.disable_interrupt();
 }
 
-pub fn listen_to_midi<D: UartDevice, P: ValidUartPinout<D>, IP: OutputPin>(
-    uart: UartPeripheral<uart::Disabled, D, P>,
-    mut p: IP,
+/// Applies the commands sent by `listen_to_midi` on core 0.
+///
+/// The watchdog is only fed while core 1 keeps running, so a hang on either core resets the board.
+pub fn run_oscillators(mut fifo: SioFifo, mut watchdog: Watchdog) -> ! {
+    let voice_count = with_oscillators(|oscs| oscs.voice_count());
+    fifo.write_blocking(voice_count as u32);
+    let mut core1 = Core1Monitor::new();
+
+    loop {
+        if core1.is_alive() {
+            watchdog.feed();
+        }
+        apply_commands(&mut fifo, &mut watchdog);
+
+        #[cfg(feature = "isr-bench")]
+        bench::report();
+    }
+}
+
+/// Receives MIDI from the UART and USB and allocates voices on core 1.
+pub fn listen_to_midi<IP: OutputPin>(
+    uart: UartPeripheral<uart::Disabled, MidiUart, MidiUartPins>,
+    usb_bus: UsbBus,
+    stored: (FlashStore, Stored),
+    timer: Timer,
+    p: IP,
+    button: Option<DynPin>,
 ) -> ! {
-    let mut config = UartConfig::default();
-    config.baudrate = embedded_time::rate::Baud(31250);
-    config.data_bits = uart::DataBits::Eight;
-    config.stop_bits = uart::StopBits::One;
-    config.parity = None;
+    let (sender, voice_count) = OscSender::new();
 
     info!("listening");
-    let uart = uart
-        .enable(config, embedded_time::rate::Hertz(125000000))
-        .unwrap();
+    let queue = singleton!(: MidiQueue = MidiQueue::new()).unwrap();
+    let (mut midi_in, midi_out) = init_midi_uart(uart, queue);
+
+    // the allocator isn't Sync, so it is created on this core
+    let usb_bus = singleton!(: UsbBusAllocator<UsbBus> = UsbBusAllocator::new(usb_bus)).unwrap();
+    let mut usb_midi = UsbMidiClass::new(usb_bus);
+    let mut usb_device = usb_midi_device(usb_bus);
 
-    let mut midi_in = midi_port::MidiInPort::new(uart);
+    let mut handler = MidiHandler::new(sender, voice_count, midi_out, stored, timer, button, p);
 
     loop {
-        midi_in.poll_uart();
-        if let Some(msg) = midi_in.get_message() {
-            interrupt::free(|cs| handle_midi_message(cs, msg, &mut p));
+        handler.heartbeat();
+        while let Some(byte) = midi_in.dequeue() {
+            handler.put_byte(MidiSource::Din, byte);
         }
+        // polled instead of using the USB interrupt, this loop never blocks
+        if usb_device.poll(&mut [&mut usb_midi]) {
+            usb_midi.read(|byte| handler.put_byte(MidiSource::Usb, byte));
+        }
+        if usb_device.state() == UsbDeviceState::Configured {
+            usb_midi.write(handler.usb_out());
+        }
+        handler.poll_output();
+        handler.poll_songs();
+        handler.poll_stream();
+        handler.poll_vibrato();
+        handler.poll_store();
     }
 }
 
-pub fn handle_midi_message<IP: OutputPin>(
-    cs: &CriticalSection,
-    msg: MidiMessage,
-    indicator_pin: &mut IP,
-) {
-    let mut oscs = OSCILLATORS.borrow(cs).borrow_mut();
-    match msg {
-        midi_port::MidiMessage::NoteOn {
-            channel,
-            note,
-            velocity: 0,
-        } => {
-            info!("note on event (0): {} {}", channel, note);
-            indicator_pin.set_low().unwrap_or(());
-            oscs.config.as_mut().unwrap().stop_note(note)
-        }
-        midi_port::MidiMessage::NoteOn {
-            channel,
-            note,
-            velocity,
-        } => {
-            info!("note on event: {} {} {}", channel, note, velocity);
-            indicator_pin.set_high().unwrap_or(());
-            oscs.config.as_mut().unwrap().play_note(note)
-        }
-        midi_port::MidiMessage::NoteOff {
-            channel,
-            note,
-            velocity,
-        } => {
-            info!("note off event: {} {} {}", channel, note, velocity);
-            indicator_pin.set_low().unwrap_or(());
-            oscs.config.as_mut().unwrap().stop_note(note)
-        }
-        midi_port::MidiMessage::ProgramChange {
-            channel: _,
-            program,
-        } => match program {
-            0 => oscs.to_single(),
-            1 => oscs.to_inverse(),
-            2 => oscs.to_unisono(),
-            _ => (),
-        },
-        // TODO
-        midi_port::MidiMessage::PitchBendChange { channel, value } => {
-            info!("Pitchbend {} {}", channel, value);
-        }
-        _ => (),
+// defmt needs a logger to link, the host tests and tools don't look at the output
+#[cfg(any(test, feature = "sim"))]
+mod host_logger {
+    #[defmt::global_logger]
+    struct Logger;
+
+    unsafe impl defmt::Logger for Logger {
+        fn acquire() {}
+        unsafe fn flush() {}
+        unsafe fn release() {}
+        unsafe fn write(_bytes: &[u8]) {}
     }
+
+    defmt::timestamp!("");
 }
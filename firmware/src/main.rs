@@ -4,43 +4,59 @@
 #![no_std]
 #![no_main]
 
-use core::ops::DerefMut;
+use core::panic::PanicInfo;
 
-use bsp::entry;
-use cortex_m::interrupt;
-use defmt::*;
+use cortex_m::{interrupt, singleton};
+use defmt::{error, info, Display2Format};
 use defmt_rtt as _;
-use panic_probe as _;
+use embedded_hal::watchdog::WatchdogEnable;
+use embedded_time::duration::units::*;
 
 use floppotron_jr::{
+    board::{split_pins, XOSC_CRYSTAL_FREQ},
+    control::{OscMode, WATCHDOG_TIMEOUT_US},
     deactivate_slice_ints,
-    floppy::{Floppy0, Floppy1, Floppy2, Floppy3, Floppy4, Floppy5},
+    floppy::{new_drive, Floppies},
     listen_to_midi,
-    oscillators::{OscConfiguration, OSCILLATORS},
+    oscillators::{osc_slices, unmask_oscillator_interrupts, with_oscillators, OscConfiguration},
+    run_oscillators,
+    safety::{enter_safe_state, force_reset, record_panic, take_reset_cause},
+    store::FlashStore,
 };
-// Provide an alias for our BSP so we can switch targets quickly.
-// Uncomment the BSP you included in Cargo.toml, the rest of the code does not need to change.
-use rp_pico as bsp;
-// use sparkfun_pro_micro_rp2040 as bsp;
-
-use bsp::hal::{
+// the board-* features pick the HAL, see board.rs
+use floppotron_jr::hal::{
+    self,
     clocks::{init_clocks_and_plls, Clock},
+    entry,
+    gpio::Pins,
+    multicore::{Multicore, Stack},
     pac,
     sio::Sio,
+    uart::UartPeripheral,
+    usb::UsbBus,
     watchdog::Watchdog,
+    Timer,
 };
 
+// the handler on core 1 keeps the presets
+const CORE1_STACK_SIZE: usize = 8192;
+
+// the rp-pico BSP brings its own second stage bootloader
+#[cfg(not(feature = "board-pico"))]
+#[link_section = ".boot2"]
+#[used]
+pub static BOOT2_FIRMWARE: [u8; 256] = rp2040_boot2::BOOT_LOADER_W25Q080;
+
 #[entry]
 fn main() -> ! {
     info!("Program start");
     let mut pac = pac::Peripherals::take().unwrap();
+    info!("reset cause: {}", take_reset_cause(&pac.WATCHDOG));
     let mut watchdog = Watchdog::new(pac.WATCHDOG);
-    let sio = Sio::new(pac.SIO);
+    let mut sio = Sio::new(pac.SIO);
 
-    // External high-speed crystal on the pico board is 12Mhz
-    let external_xtal_freq_hz = 12_000_000u32;
     let clocks = init_clocks_and_plls(
-        external_xtal_freq_hz,
+        XOSC_CRYSTAL_FREQ,
         pac.XOSC,
         pac.CLOCKS,
         pac.PLL_SYS,
@@ -50,104 +66,102 @@ fn main() -> ! {
     )
     .ok()
     .unwrap();
-    let pins = bsp::Pins::new(
+    // the pin assignments come from the board file, see board.rs
+    let pins = split_pins(Pins::new(
         pac.IO_BANK0,
         pac.PADS_BANK0,
         sio.gpio_bank0,
         &mut pac.RESETS,
-    );
+    ));
 
-    let mut slices = bsp::hal::pwm::Slices::new(pac.PWM, &mut pac.RESETS);
+    let mut slices = hal::pwm::Slices::new(pac.PWM, &mut pac.RESETS);
 
     slices.pwm0.set_div_int(255);
     slices.pwm0.set_top(65465);
     slices.pwm0.enable();
 
-    // let step_pin = pins.gpio26.into_push_pull_output();
-    // let dir_pin = pins.gpio27.into_push_pull_output();
-    // let en_pin = pins.gpio28.into_push_pull_output();
+    let mut drive_pins = pins.drives.map(Some);
+    let floppies: Floppies =
+        core::array::from_fn(|index| new_drive(index, drive_pins[index].take().unwrap()));
 
-    let floppy0 = Floppy0::new(
-        pins.gpio26.into_push_pull_output(),
-        pins.gpio27.into_push_pull_output(),
-        pins.gpio28.into_push_pull_output(),
-    );
+    deactivate_slice_ints(&mut slices);
 
-    let floppy1 = Floppy1::new(
-        pins.gpio7.into_push_pull_output(),
-        pins.gpio6.into_push_pull_output(),
-        pins.gpio5.into_push_pull_output(),
-    );
+    let slices = osc_slices(slices);
 
-    let floppy2 = Floppy2::new(
-        pins.gpio20.into_push_pull_output(),
-        pins.gpio21.into_push_pull_output(),
-        pins.gpio22.into_push_pull_output(),
-    );
+    info!("unmasked");
 
-    let floppy3 = Floppy3::new(
-        pins.gpio11.into_push_pull_output(),
-        pins.gpio10.into_push_pull_output(),
-        pins.gpio9.into_push_pull_output(),
-    );
+    #[cfg(not(feature = "pio-step"))]
+    let osc_config = OscConfiguration::new_single(slices, floppies);
+    #[cfg(feature = "pio-step")]
+    let osc_config =
+        OscConfiguration::new_pio(pac.PIO0, pac.PIO1, &mut pac.RESETS, slices, floppies);
+    info!("config initialized");
 
-    let floppy4 = Floppy4::new(
-        pins.gpio16.into_push_pull_output(),
-        pins.gpio17.into_push_pull_output(),
-        pins.gpio18.into_push_pull_output(),
-    );
+    with_oscillators(|oscs| oscs.init_with_config(osc_config));
 
-    let floppy5 = Floppy5::new(
-        pins.gpio15.into_push_pull_output(),
-        pins.gpio14.into_push_pull_output(),
-        pins.gpio13.into_push_pull_output(),
-    );
+    // the other settings are applied by core 1
+    let (store, stored) = FlashStore::load();
+    if stored.settings.osc_mode != OscMode::Single && stored.settings.osc_mode.is_available() {
+        with_oscillators(|oscs| oscs.set_mode(stored.settings.osc_mode));
+    }
 
-    let floppies = (floppy0, floppy1, floppy2, floppy3, floppy4, floppy5);
+    #[cfg(feature = "isr-bench")]
+    floppotron_jr::bench::init(pac::CorePeripherals::take().unwrap().SYST);
 
-    deactivate_slice_ints(&mut slices);
+    #[cfg(not(midi_uart1))]
+    let midi_uart = pac.UART0;
+    #[cfg(midi_uart1)]
+    let midi_uart = pac.UART1;
+    let midi_uart = UartPeripheral::new(midi_uart, pins.midi_uart, &mut pac.RESETS);
 
-    let slices = (
-        slices.pwm0,
-        slices.pwm1,
-        slices.pwm2,
-        slices.pwm3,
-        slices.pwm4,
-        slices.pwm5,
+    let usb_bus = UsbBus::new(
+        pac.USBCTRL_REGS,
+        pac.USBCTRL_DPRAM,
+        clocks.usb_clock,
+        true,
+        &mut pac.RESETS,
     );
 
-    info!("unmasked");
-
-    let osc_config = OscConfiguration::new_single(slices, floppies);
-    info!("config initialized");
-
-    interrupt::free(|cs| {
-        OSCILLATORS
-            .borrow(cs)
-            .borrow_mut()
-            .deref_mut()
-            .init_with_config(osc_config)
-    });
-
-    let uart_pins = (
-        pins.gpio0.into_mode::<bsp::hal::gpio::FunctionUart>(),
-        pins.gpio1.into_mode::<bsp::hal::gpio::FunctionUart>(),
-    );
-    let uart0 = bsp::hal::uart::UartPeripheral::new(pac.UART0, uart_pins, &mut pac.RESETS);
+    let timer = Timer::new(pac.TIMER, &mut pac.RESETS);
 
     info!("freq: {}", clocks.peripheral_clock.freq().0,);
 
-    let led_pin = pins.led.into_push_pull_output();
+    let mut led_pin = pins.led;
+    led_pin.into_push_pull_output();
+    let mut button = pins.button;
+    if let Some(pin) = &mut button {
+        pin.into_pull_up_input();
+    }
+
+    // the main loop has to feed the watchdog, a hang anywhere resets the board
+    watchdog.start(WATCHDOG_TIMEOUT_US.microseconds());
 
-    // enable PWM interrupt
+    // enable oscillator interrupts
+    unmask_oscillator_interrupts();
     unsafe {
-        info!("unmask");
-        pac::NVIC::unmask(pac::Interrupt::PWM_IRQ_WRAP); // infinite loop?
         info!("enable");
         interrupt::enable(); // infinite loop?
     }
 
-    listen_to_midi(uart0, led_pin);
+    // core 1 handles MIDI input and voice allocation, core 0 only drives the oscillators
+    let core1_stack = singleton!(: Stack<CORE1_STACK_SIZE> = Stack::new()).unwrap();
+    let mut multicore = Multicore::new(&mut pac.PSM, &mut pac.PPB, &mut sio.fifo);
+    multicore.cores()[1]
+        .spawn(&mut core1_stack.mem, move || {
+            listen_to_midi(midi_uart, usb_bus, (store, stored), timer, led_pin, button)
+        })
+        .unwrap();
+
+    run_oscillators(sio.fifo, watchdog);
+}
+
+#[panic_handler]
+fn panic(panic_info: &PanicInfo) -> ! {
+    interrupt::disable();
+    enter_safe_state();
+    record_panic();
+    error!("{}", Display2Format(panic_info));
+    force_reset()
 }
 
 // End of file
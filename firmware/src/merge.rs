@@ -0,0 +1,130 @@
+//! Merges the MIDI inputs into one stream of messages.
+//!
+//! Every source has its own parser, so running status and half received messages
+//! of one input never mix with another one. Channels can be remapped per source, e.g.
+//! a keyboard to channel 1 and a DAW to channel 2. Together with a zone for each of
+//! these channels (see `Param::Zone`) they play on separate voices.
+
+use crate::midi::{MidiEvent, MidiParser};
+use defmt::Format;
+
+#[derive(Clone, Copy, PartialEq, Format)]
+pub enum MidiSource {
+    /// 5-pin DIN on the MIDI UART
+    Din,
+    Usb,
+}
+
+pub const SOURCE_COUNT: usize = 2;
+pub const SOURCES: [MidiSource; SOURCE_COUNT] = [MidiSource::Din, MidiSource::Usb];
+
+impl MidiSource {
+    fn index(self) -> usize {
+        match self {
+            MidiSource::Din => 0,
+            MidiSource::Usb => 1,
+        }
+    }
+}
+
+pub struct MidiMerge {
+    parsers: [MidiParser; SOURCE_COUNT],
+    channel_maps: [[u8; 16]; SOURCE_COUNT],
+}
+
+impl MidiMerge {
+    pub fn new() -> Self {
+        let identity = core::array::from_fn(|channel| channel as u8);
+        Self {
+            parsers: [MidiParser::new(), MidiParser::new()],
+            channel_maps: [identity; SOURCE_COUNT],
+        }
+    }
+
+    /// Moves messages on `channel` of `source` to `to`.
+    pub fn remap_channel(&mut self, source: MidiSource, channel: u8, to: u8) {
+        self.channel_maps[source.index()][channel as usize & 0x0f] = to & 0x0f;
+    }
+
+    /// Puts a byte received from `source` and returns the event it completes.
+    pub fn put_byte(&mut self, source: MidiSource, byte: u8) -> Option<MidiEvent> {
+        let index = source.index();
+        // remapping the status byte also covers running status
+        let byte = match byte {
+            0x80..=0xef => byte & 0xf0 | self.channel_maps[index][byte as usize & 0x0f],
+            _ => byte,
+        };
+        self.parsers[index].put_byte(byte)
+    }
+}
+
+impl Default for MidiMerge {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use midi_port::MidiMessage;
+
+    use super::*;
+
+    #[test]
+    fn running_status_per_source() {
+        let mut merge = MidiMerge::new();
+        assert!(merge.put_byte(MidiSource::Din, 0x90).is_none());
+        assert!(merge.put_byte(MidiSource::Din, 60).is_none());
+        // a complete message from USB in the middle of a DIN message
+        assert!(merge.put_byte(MidiSource::Usb, 0x82).is_none());
+        assert!(merge.put_byte(MidiSource::Usb, 40).is_none());
+        assert!(matches!(
+            merge.put_byte(MidiSource::Usb, 0),
+            Some(MidiEvent::Message(MidiMessage::NoteOff {
+                channel: 2,
+                note: 40,
+                ..
+            }))
+        ));
+        assert!(matches!(
+            merge.put_byte(MidiSource::Din, 100),
+            Some(MidiEvent::Message(MidiMessage::NoteOn {
+                channel: 0,
+                note: 60,
+                velocity: 100
+            }))
+        ));
+        // running status of DIN is untouched by the USB message
+        assert!(merge.put_byte(MidiSource::Din, 62).is_none());
+        assert!(matches!(
+            merge.put_byte(MidiSource::Din, 90),
+            Some(MidiEvent::Message(MidiMessage::NoteOn {
+                channel: 0,
+                note: 62,
+                ..
+            }))
+        ));
+    }
+
+    #[test]
+    fn remapped_channel() {
+        let mut merge = MidiMerge::new();
+        merge.remap_channel(MidiSource::Usb, 0, 5);
+        assert!(merge.put_byte(MidiSource::Usb, 0xc0).is_none());
+        assert!(matches!(
+            merge.put_byte(MidiSource::Usb, 1),
+            Some(MidiEvent::Message(MidiMessage::ProgramChange {
+                channel: 5,
+                program: 1
+            }))
+        ));
+        assert!(merge.put_byte(MidiSource::Din, 0xc0).is_none());
+        assert!(matches!(
+            merge.put_byte(MidiSource::Din, 1),
+            Some(MidiEvent::Message(MidiMessage::ProgramChange {
+                channel: 0,
+                ..
+            }))
+        ));
+    }
+}
@@ -1 +1,320 @@
+use core::cell::RefCell;
 
+use crate::hal::{
+    pac::{self, interrupt},
+    uart::{self, Reader, UartConfig, UartPeripheral},
+};
+use cortex_m::interrupt::{self as cortex_interrupt, Mutex};
+use defmt::warn;
+use heapless::spsc::{Consumer, Producer, Queue};
+use midi_port::MidiMessage;
+
+use crate::{
+    board::{MidiUart, MidiUartPins, MIDI_UART_IRQ},
+    midi_out::MidiOut,
+};
+
+pub use floppotron_protocol::{expected_data_len, SysEx, SYSEX_SIZE};
+
+pub const MIDI_QUEUE_SIZE: usize = 64;
+
+/// Raw bytes from the UART, they are parsed by `MidiMerge` together with the other inputs.
+pub type MidiQueue = Queue<u8, MIDI_QUEUE_SIZE>;
+pub type MidiConsumer = Consumer<'static, u8, MIDI_QUEUE_SIZE>;
+type MidiProducer = Producer<'static, u8, MIDI_QUEUE_SIZE>;
+
+struct MidiUartIn {
+    reader: Reader<MidiUart, MidiUartPins>,
+    producer: MidiProducer,
+}
+
+impl MidiUartIn {
+    fn receive(&mut self) {
+        let mut buffer = [0u8; 32];
+        // reading until the FIFO is empty clears the interrupt
+        loop {
+            let bytes = match self.reader.read_raw(&mut buffer) {
+                Ok(count) => &buffer[..count],
+                Err(nb::Error::WouldBlock) => break,
+                Err(nb::Error::Other(err)) => {
+                    warn!("midi uart read error");
+                    err.discared
+                }
+            };
+
+            for &byte in bytes {
+                if self.producer.enqueue(byte).is_err() {
+                    warn!("midi queue full, dropping byte");
+                }
+            }
+        }
+    }
+}
+
+static MIDI_UART_IN: Mutex<RefCell<Option<MidiUartIn>>> = Mutex::new(RefCell::new(None));
+
+pub enum MidiEvent {
+    Message(MidiMessage),
+    SysEx(SysEx),
+    /// realtime start, continue and stop
+    Start,
+    Continue,
+    Stop,
+    SongSelect(u8),
+}
+
+/// Parses a raw MIDI byte stream, including running status.
+///
+/// Realtime messages don't affect the running status, only the transport messages are
+/// returned, like the song select of the system common messages. System exclusive
+/// messages are collected until `F7`, longer ones than `SYSEX_SIZE` or ones cut short by
+/// another status byte are dropped.
+pub struct MidiParser {
+    status: Option<u8>,
+    data: [u8; 2],
+    data_len: usize,
+    sysex: Option<SysEx>,
+    // a song select waits for its data byte
+    song_select: bool,
+}
+
+impl MidiParser {
+    pub const fn new() -> Self {
+        Self {
+            status: None,
+            data: [0; 2],
+            data_len: 0,
+            sysex: None,
+            song_select: false,
+        }
+    }
+
+    pub fn put_byte(&mut self, byte: u8) -> Option<MidiEvent> {
+        match byte {
+            0xfa => Some(MidiEvent::Start),
+            0xfb => Some(MidiEvent::Continue),
+            0xfc => Some(MidiEvent::Stop),
+            0xf8..=0xff => None,
+            0xf0..=0xf7 => {
+                self.status = None;
+                self.data_len = 0;
+                self.song_select = byte == 0xf3;
+                match byte {
+                    0xf0 => {
+                        self.sysex = Some(SysEx::new());
+                        None
+                    }
+                    0xf7 => self.sysex.take().map(MidiEvent::SysEx),
+                    _ => {
+                        self.sysex = None;
+                        None
+                    }
+                }
+            }
+            0x80..=0xef => {
+                self.status = Some(byte);
+                self.data_len = 0;
+                self.sysex = None;
+                self.song_select = false;
+                None
+            }
+            _ => {
+                if let Some(sysex) = &mut self.sysex {
+                    if sysex.push(byte).is_err() {
+                        warn!("sysex too long, dropping it");
+                        self.sysex = None;
+                    }
+                    return None;
+                }
+                if self.song_select {
+                    self.song_select = false;
+                    return Some(MidiEvent::SongSelect(byte));
+                }
+
+                let status = self.status?;
+                self.data[self.data_len] = byte;
+                self.data_len += 1;
+
+                if self.data_len < expected_data_len(status) {
+                    return None;
+                }
+                self.data_len = 0;
+                Some(MidiEvent::Message(create_message(status, self.data)))
+            }
+        }
+    }
+}
+
+impl Default for MidiParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub fn create_message(status: u8, data: [u8; 2]) -> MidiMessage {
+    let channel = status & 0x0f;
+    match status & 0xf0 {
+        0x80 => MidiMessage::NoteOff {
+            channel,
+            note: data[0],
+            velocity: data[1],
+        },
+        0x90 => MidiMessage::NoteOn {
+            channel,
+            note: data[0],
+            velocity: data[1],
+        },
+        0xa0 => MidiMessage::Aftertouch {
+            channel,
+            note: Some(data[0]),
+            value: data[1],
+        },
+        0xb0 => MidiMessage::ControlChange {
+            channel,
+            controller: data[0],
+            value: data[1],
+        },
+        0xc0 => MidiMessage::ProgramChange {
+            channel,
+            program: data[0],
+        },
+        0xd0 => MidiMessage::Aftertouch {
+            channel,
+            note: None,
+            value: data[0],
+        },
+        0xe0 => MidiMessage::PitchBendChange {
+            channel,
+            value: data[0] as u16 | (data[1] as u16) << 7,
+        },
+        _ => MidiMessage::Unknown,
+    }
+}
+
+/// Notes started and not stopped yet, per channel, so they can be released when the
+/// source that played them stops.
+pub struct HeldNotes([u128; 16]);
+
+impl HeldNotes {
+    pub const fn new() -> Self {
+        Self([0; 16])
+    }
+
+    pub fn track(&mut self, msg: &MidiMessage) {
+        match *msg {
+            MidiMessage::NoteOn {
+                channel,
+                note,
+                velocity,
+            } if velocity > 0 => self.0[channel as usize & 0x0f] |= 1 << (note & 0x7f),
+            MidiMessage::NoteOn { channel, note, .. }
+            | MidiMessage::NoteOff { channel, note, .. } => {
+                self.0[channel as usize & 0x0f] &= !(1 << (note & 0x7f))
+            }
+            _ => (),
+        }
+    }
+
+    /// Returns the note offs for the held notes and forgets them.
+    pub fn release(&mut self) -> impl Iterator<Item = MidiMessage> {
+        let notes = core::mem::take(&mut self.0);
+        (0..16u8).flat_map(move |channel| {
+            (0..128u8)
+                .filter(move |&note| notes[channel as usize] & 1 << note != 0)
+                .map(move |note| MidiMessage::NoteOff {
+                    channel,
+                    note,
+                    velocity: 0,
+                })
+        })
+    }
+}
+
+impl Default for HeldNotes {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Enables the MIDI UART and its receive interrupt.
+///
+/// Received bytes are pushed into `queue` from the UART interrupt, the returned consumer
+/// is meant to be drained from the main loop, the TX side is returned as `MidiOut`.
+/// The interrupt is unmasked on the calling core.
+/// Which UART is used depends on the pins in the board file.
+pub fn init_midi_uart(
+    uart: UartPeripheral<uart::Disabled, MidiUart, MidiUartPins>,
+    queue: &'static mut MidiQueue,
+) -> (MidiConsumer, MidiOut) {
+    let mut config = UartConfig::default();
+    config.baudrate = embedded_time::rate::Baud(31250);
+    config.data_bits = uart::DataBits::Eight;
+    config.stop_bits = uart::StopBits::One;
+    config.parity = None;
+
+    let mut uart = uart
+        .enable(config, embedded_time::rate::Hertz(125000000))
+        .unwrap();
+    uart.enable_rx_interrupt();
+    let (reader, writer) = uart.split();
+
+    let (producer, consumer) = queue.split();
+
+    cortex_interrupt::free(|cs| {
+        MIDI_UART_IN
+            .borrow(cs)
+            .replace(Some(MidiUartIn { reader, producer }))
+    });
+
+    unsafe {
+        pac::NVIC::unmask(MIDI_UART_IRQ);
+    }
+
+    (consumer, MidiOut::new(writer))
+}
+
+fn receive_midi() {
+    // only unmasked on core 1, so the critical section never delays the oscillator interrupts
+    cortex_interrupt::free(|cs| {
+        if let Some(midi_in) = MIDI_UART_IN.borrow(cs).borrow_mut().as_mut() {
+            midi_in.receive();
+        }
+    });
+}
+
+#[cfg(not(midi_uart1))]
+#[interrupt]
+fn UART0_IRQ() {
+    receive_midi();
+}
+
+#[cfg(midi_uart1)]
+#[interrupt]
+fn UART1_IRQ() {
+    receive_midi();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transport_messages() {
+        let mut parser = MidiParser::new();
+        assert!(parser.put_byte(0x90).is_none());
+        assert!(parser.put_byte(60).is_none());
+        // realtime bytes in the middle of a message
+        assert!(matches!(parser.put_byte(0xfa), Some(MidiEvent::Start)));
+        assert!(parser.put_byte(0xf8).is_none());
+        assert!(matches!(
+            parser.put_byte(100),
+            Some(MidiEvent::Message(MidiMessage::NoteOn { note: 60, .. }))
+        ));
+        assert!(matches!(parser.put_byte(0xfc), Some(MidiEvent::Stop)));
+
+        assert!(parser.put_byte(0xf3).is_none());
+        assert!(matches!(parser.put_byte(2), Some(MidiEvent::SongSelect(2))));
+        // the song select ends the running status
+        assert!(parser.put_byte(62).is_none());
+    }
+}
@@ -0,0 +1,315 @@
+//! MIDI output on the TX pin of the MIDI UART.
+//!
+//! Bytes are buffered and handed to the UART from the core 1 main loop, so sending never
+//! blocks the input handling.
+//!
+//! Several units can be daisy-chained, each one connected to the MIDI out of the previous
+//! one. With `MidiOutMode::Chain` and `VoicePolicy::Overflow` a unit plays notes while it
+//! has free voices and passes the rest on, so the chain behaves like one instrument with
+//! the voices of all units.
+
+use defmt::{warn, Format};
+use heapless::{spsc::Queue, Vec};
+use midi_port::MidiMessage;
+
+use crate::{
+    board::{MidiUart, MidiUartPins},
+    hal::uart::Writer,
+};
+
+const OUT_BUFFER_SIZE: usize = 128;
+
+#[derive(Clone, Copy, PartialEq, Debug, Format)]
+pub enum MidiOutMode {
+    /// only replies to SysEx requests from the DIN input are sent
+    Off,
+    /// echoes the channel messages of all inputs
+    Thru,
+    /// passes on all channel messages but notes, the notes that found no voice are added
+    /// by `overflow_note_on`
+    Chain,
+}
+
+impl MidiOutMode {
+    pub fn to_bits(self) -> u8 {
+        match self {
+            MidiOutMode::Off => 0,
+            MidiOutMode::Thru => 1,
+            MidiOutMode::Chain => 2,
+        }
+    }
+
+    pub fn from_bits(bits: u8) -> Option<Self> {
+        match bits {
+            0 => Some(MidiOutMode::Off),
+            1 => Some(MidiOutMode::Thru),
+            2 => Some(MidiOutMode::Chain),
+            _ => None,
+        }
+    }
+}
+
+/// Returns the bytes of a channel message, `Unknown` has none.
+pub fn message_bytes(msg: &MidiMessage) -> Vec<u8, 3> {
+    let (status, channel, data): (u8, u8, &[u8]) = match *msg {
+        MidiMessage::NoteOff {
+            channel,
+            note,
+            velocity,
+        } => (0x80, channel, &[note, velocity]),
+        MidiMessage::NoteOn {
+            channel,
+            note,
+            velocity,
+        } => (0x90, channel, &[note, velocity]),
+        MidiMessage::Aftertouch {
+            channel,
+            note: Some(note),
+            value,
+        } => (0xa0, channel, &[note, value]),
+        MidiMessage::ControlChange {
+            channel,
+            controller,
+            value,
+        } => (0xb0, channel, &[controller, value]),
+        MidiMessage::ProgramChange { channel, program } => (0xc0, channel, &[program]),
+        MidiMessage::Aftertouch {
+            channel,
+            note: None,
+            value,
+        } => (0xd0, channel, &[value]),
+        MidiMessage::PitchBendChange { channel, value } => (
+            0xe0,
+            channel,
+            &[(value & 0x7f) as u8, (value >> 7 & 0x7f) as u8],
+        ),
+        MidiMessage::Unknown => return Vec::new(),
+    };
+
+    let mut bytes = Vec::new();
+    bytes.push(status | channel & 0x0f).unwrap();
+    bytes.extend_from_slice(data).unwrap();
+    bytes
+}
+
+/// A set of notes by channel.
+#[derive(Default)]
+struct NoteSet([u128; 16]);
+
+impl NoteSet {
+    fn bit(channel: u8, note: u8) -> (usize, u128) {
+        (channel as usize & 0x0f, 1 << (note & 0x7f))
+    }
+
+    fn contains(&self, channel: u8, note: u8) -> bool {
+        let (channel, bit) = Self::bit(channel, note);
+        self.0[channel] & bit != 0
+    }
+
+    fn insert(&mut self, channel: u8, note: u8) {
+        let (channel, bit) = Self::bit(channel, note);
+        self.0[channel] |= bit;
+    }
+
+    fn remove(&mut self, channel: u8, note: u8) {
+        let (channel, bit) = Self::bit(channel, note);
+        self.0[channel] &= !bit;
+    }
+}
+
+pub struct MidiOut {
+    writer: Writer<MidiUart, MidiUartPins>,
+    buffer: Queue<u8, OUT_BUFFER_SIZE>,
+    mode: MidiOutMode,
+    // notes passed on by `overflow_note_on`
+    overflow_notes: NoteSet,
+}
+
+impl MidiOut {
+    pub fn new(writer: Writer<MidiUart, MidiUartPins>) -> Self {
+        Self {
+            writer,
+            buffer: Queue::new(),
+            mode: MidiOutMode::Thru,
+            overflow_notes: NoteSet::default(),
+        }
+    }
+
+    pub fn mode(&self) -> MidiOutMode {
+        self.mode
+    }
+
+    pub fn set_mode(&mut self, mode: MidiOutMode) {
+        self.mode = mode;
+    }
+
+    fn fits(&self, len: usize) -> bool {
+        let fits = self.buffer.capacity() - self.buffer.len() >= len;
+        if !fits {
+            warn!("midi out buffer full, dropping {} bytes", len);
+        }
+        fits
+    }
+
+    /// Queues `bytes` as a whole, nothing is sent if they don't fit.
+    pub fn send(&mut self, bytes: &[u8]) {
+        if self.fits(bytes.len()) {
+            for &byte in bytes {
+                self.buffer.enqueue(byte).unwrap();
+            }
+        }
+    }
+
+    /// Sends `data` framed by `F0` and `F7`.
+    pub fn send_sysex(&mut self, data: &[u8]) {
+        if self.fits(data.len() + 2) {
+            self.send(&[0xf0]);
+            self.send(data);
+            self.send(&[0xf7]);
+        }
+    }
+
+    fn send_message(&mut self, msg: &MidiMessage) {
+        self.send(&message_bytes(msg));
+    }
+
+    /// Echoes an input message in thru mode.
+    ///
+    /// In chain mode everything but notes is passed on, so the next unit follows mode
+    /// changes and controllers.
+    pub fn thru(&mut self, msg: &MidiMessage) {
+        let forward = match self.mode {
+            MidiOutMode::Off => false,
+            MidiOutMode::Thru => true,
+            MidiOutMode::Chain => !matches!(
+                msg,
+                MidiMessage::NoteOn { .. } | MidiMessage::NoteOff { .. }
+            ),
+        };
+        if forward {
+            self.send_message(msg);
+        }
+    }
+
+    /// Passes on SysEx messages for other units in thru and chain mode.
+    pub fn thru_sysex(&mut self, data: &[u8]) {
+        if self.mode != MidiOutMode::Off {
+            self.send_sysex(data);
+        }
+    }
+
+    /// Whether `note` on `channel` is currently played by a unit further down the chain.
+    pub fn is_overflowing(&self, channel: u8, note: u8) -> bool {
+        self.overflow_notes.contains(channel, note)
+    }
+
+    /// Sends a note that found no voice in chain mode, in thru mode it was echoed already.
+    pub fn overflow_note_on(&mut self, channel: u8, note: u8, velocity: u8) {
+        if self.mode != MidiOutMode::Chain {
+            return;
+        }
+        self.overflow_notes.insert(channel, note);
+        self.send_message(&MidiMessage::NoteOn {
+            channel,
+            note,
+            velocity,
+        });
+    }
+
+    /// Sends the note off for a note that was passed on by `overflow_note_on`.
+    pub fn overflow_note_off(&mut self, channel: u8, note: u8) {
+        if !self.is_overflowing(channel, note) {
+            return;
+        }
+        self.overflow_notes.remove(channel, note);
+        self.send_message(&MidiMessage::NoteOff {
+            channel,
+            note,
+            velocity: 0,
+        });
+    }
+
+    /// Moves buffered bytes into the UART FIFO, as many as fit.
+    pub fn poll(&mut self) {
+        while let Some(&byte) = self.buffer.peek() {
+            if self.writer.write_raw(&[byte]).is_err() {
+                break;
+            }
+            self.buffer.dequeue();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::midi::{MidiEvent, MidiParser};
+
+    fn round_trip(msg: MidiMessage) -> Option<MidiMessage> {
+        let mut parser = MidiParser::new();
+        match message_bytes(&msg)
+            .iter()
+            .fold(None, |_, &byte| parser.put_byte(byte))
+        {
+            Some(MidiEvent::Message(msg)) => Some(msg),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn channel_messages_round_trip() {
+        assert!(matches!(
+            round_trip(MidiMessage::NoteOn {
+                channel: 9,
+                note: 36,
+                velocity: 127
+            }),
+            Some(MidiMessage::NoteOn {
+                channel: 9,
+                note: 36,
+                velocity: 127
+            })
+        ));
+        assert!(matches!(
+            round_trip(MidiMessage::Aftertouch {
+                channel: 1,
+                note: None,
+                value: 64
+            }),
+            Some(MidiMessage::Aftertouch {
+                channel: 1,
+                note: None,
+                value: 64
+            })
+        ));
+        assert!(matches!(
+            round_trip(MidiMessage::PitchBendChange {
+                channel: 2,
+                value: 0x2345
+            }),
+            Some(MidiMessage::PitchBendChange {
+                channel: 2,
+                value: 0x2345
+            })
+        ));
+    }
+
+    #[test]
+    fn note_set_keeps_channels_apart() {
+        let mut notes = NoteSet::default();
+        notes.insert(0, 60);
+        notes.insert(15, 127);
+        assert!(notes.contains(0, 60));
+        assert!(!notes.contains(1, 60));
+        assert!(notes.contains(15, 127));
+        notes.remove(1, 60);
+        assert!(notes.contains(0, 60));
+        notes.remove(0, 60);
+        assert!(!notes.contains(0, 60));
+    }
+
+    #[test]
+    fn unknown_has_no_bytes() {
+        assert!(message_bytes(&MidiMessage::Unknown).is_empty());
+    }
+}
@@ -6,4 +6,28 @@ pub struct PwmSetting {
     pub top_pb: u16,
 }
 
+impl PwmSetting {
+    /// TOP for the note raised by `cents`, up to 99, the divider stays the same.
+    pub fn top_raised(&self, cents: u8) -> u16 {
+        let factor = CENT_FACTORS[cents.min(99) as usize];
+        ((self.top as u64 * factor) >> 32) as u16
+    }
+}
+
 pub const NOTE_DICT: [PwmSetting; 128] = note_dict!();
+
+// 2^(-1/1200) as 0.32 fixed point, a period gets this much shorter per cent
+const CENT_FACTOR: u64 = 4_292_487_142;
+
+// 2^(-cents/1200) for 0 to 99 cents, multiplied up from `CENT_FACTOR`
+const CENT_FACTORS: [u64; 100] = {
+    let mut factors = [0; 100];
+    let mut factor = 1u64 << 32;
+    let mut cents = 0;
+    while cents < 100 {
+        factors[cents] = factor;
+        factor = (factor * CENT_FACTOR) >> 32;
+        cents += 1;
+    }
+    factors
+};
@@ -1,31 +1,76 @@
-use core::{cell::RefCell, ops::DerefMut};
+use core::cell::UnsafeCell;
 
-use cortex_m::interrupt::Mutex;
-use defmt::info;
-use rp_pico as bsp;
-
-use bsp::{
-    hal::pwm::{Pwm0, Pwm1, Pwm2, Pwm3, Pwm4, Pwm5},
-    pac::interrupt,
-};
-use rp_pico::hal::pwm::{FreeRunning, Slice, SliceId};
+use cortex_m::peripheral::NVIC;
+use defmt::{info, warn};
 
 use crate::{
-    floppy::{Floppies, Floppy0, Floppy1, Floppy2, Floppy3, Floppy4, Floppy5},
+    control::OscMode,
+    floppy::{Drive, Floppies, Floppy, FloppyError, DRIVE_COUNT},
+    hal::{
+        pac::{self, interrupt, Interrupt},
+        pwm::Slices,
+    },
     note_dict::NOTE_DICT,
 };
-use cortex_m::interrupt as cortex_interrupt;
 
+#[cfg(any(test, feature = "sim"))]
+pub mod emulator;
 pub mod inverse;
+#[cfg(feature = "pio-step")]
+pub mod pio;
 pub mod single;
+pub mod slice;
 pub mod unisono;
 
-use self::{inverse::InverseOscillator, single::SingleOscillator, unisono::UnisonoOscillator};
+#[cfg(feature = "pio-step")]
+use self::pio::{step_program, PioOscillator};
+use self::{
+    inverse::InverseOscillator,
+    single::SingleOscillator,
+    slice::{PwmSlice, Slice},
+    unisono::UnisonoOscillator,
+};
+#[cfg(feature = "pio-step")]
+use crate::hal::pio::PIOExt;
+
+// bits of the PIO state machines in the mask passed to handle_interrupt,
+// the PWM slices use bits 0 to 7
+#[cfg(feature = "pio-step")]
+const PIO0_IRQ_OFFSET: u8 = 8;
+#[cfg(feature = "pio-step")]
+const PIO1_IRQ_OFFSET: u8 = 12;
+
+#[cfg(not(feature = "pio-step"))]
+const OSCILLATOR_INTERRUPTS: [Interrupt; 1] = [Interrupt::PWM_IRQ_WRAP];
+#[cfg(feature = "pio-step")]
+const OSCILLATOR_INTERRUPTS: [Interrupt; 2] = [Interrupt::PIO0_IRQ_0, Interrupt::PIO1_IRQ_0];
+
+const INVERSE_COUNT: usize = DRIVE_COUNT / 2;
+
+/// Number of voices the oscillators of `mode` provide.
+pub fn mode_voice_count(mode: OscMode) -> u8 {
+    match mode {
+        OscMode::Single => DRIVE_COUNT as u8,
+        OscMode::Inverse => INVERSE_COUNT as u8,
+        OscMode::Unisono => 1,
+    }
+}
 
-pub fn set_pwm_note<SID: SliceId>(pwm_slice: &mut Slice<SID, FreeRunning>, note: u8) {
+/// Plays `note` raised by `cents`, 0 to 99, on `pwm_slice`.
+pub fn set_pwm_note<S: Slice>(pwm_slice: &mut S, note: u8, cents: u8) {
     if let Some(pwm_setting) = NOTE_DICT.get(note as usize) {
+        let top = pwm_setting.top_raised(cents);
         pwm_slice.set_div_int(pwm_setting.div_int);
-        pwm_slice.set_top(pwm_setting.top);
+        pwm_slice.set_top(top);
+
+        // the channel outputs generate one step pulse per period, phase correct mode
+        // halves the wrap rate to match the toggling done by FloppyImpl
+        #[cfg(feature = "hw-step")]
+        {
+            pwm_slice.set_ph_correct();
+            pwm_slice.set_duty(top / 2);
+        }
+
         pwm_slice.enable();
         pwm_slice.enable_interrupt();
     } else {
@@ -34,256 +79,339 @@ pub fn set_pwm_note<SID: SliceId>(pwm_slice: &mut Slice<SID, FreeRunning>, note:
 }
 
 trait Oscillator {
-    fn stop(&mut self);
-    fn set_note(&mut self, note: u8);
-    fn handle_interrupt(&mut self);
-    fn get_note(&self) -> Option<u8>;
-    fn get_age(&self) -> u8;
-    fn set_age(&mut self, age: u8);
+    fn stop(&mut self) -> Result<(), FloppyError>;
+    fn set_note(&mut self, note: u8, cents: u8) -> Result<(), FloppyError>;
+    // only called when the oscillator's interrupt is pending, it is already cleared
+    fn handle_interrupt(&mut self) -> Result<(), FloppyError>;
+    fn irq_mask(&self) -> u32;
 }
 
-type OscSlices = (
-    Slice<Pwm0, FreeRunning>,
-    Slice<Pwm1, FreeRunning>,
-    Slice<Pwm2, FreeRunning>,
-    Slice<Pwm3, FreeRunning>,
-    Slice<Pwm4, FreeRunning>,
-    Slice<Pwm5, FreeRunning>,
-);
-
-pub enum OscConfiguration {
-    Single(
-        (
-            SingleOscillator<Floppy0, Pwm0>,
-            SingleOscillator<Floppy1, Pwm1>,
-            SingleOscillator<Floppy2, Pwm2>,
-            SingleOscillator<Floppy3, Pwm3>,
-            SingleOscillator<Floppy4, Pwm4>,
-            SingleOscillator<Floppy5, Pwm5>,
-        ),
-    ),
-    Unisono(
-        UnisonoOscillator,
-        (
-            Slice<Pwm1, FreeRunning>,
-            Slice<Pwm2, FreeRunning>,
-            Slice<Pwm3, FreeRunning>,
-            Slice<Pwm4, FreeRunning>,
-            Slice<Pwm5, FreeRunning>,
-        ),
-    ),
+// errors are logged and recovered from here, panicking would freeze the drives mid-note
+fn recover(osc: &mut dyn Oscillator, result: Result<(), FloppyError>) {
+    match result {
+        Ok(()) => (),
+        // the interrupt raced a note stop, there is nothing left to step
+        Err(FloppyError::Disabled) => (),
+        // the floppy already clamped its head position
+        Err(FloppyError::OutOfRange) => warn!("floppy out of range"),
+        Err(FloppyError::PinFault) => {
+            warn!("pin fault, stopping oscillator");
+            osc.stop().ok();
+        }
+    }
+}
+
+/// One PWM slice per drive, slice N belongs to drive N.
+pub type OscSlices = [PwmSlice; DRIVE_COUNT];
+
+/// Takes the slices used by the drives, the remaining ones are dropped.
+pub fn osc_slices(slices: Slices) -> OscSlices {
+    let mut slices = [
+        Some(slices.pwm0.into()),
+        Some(slices.pwm1.into()),
+        Some(slices.pwm2.into()),
+        Some(slices.pwm3.into()),
+        Some(slices.pwm4.into()),
+        Some(slices.pwm5.into()),
+        Some(slices.pwm6.into()),
+        Some(slices.pwm7.into()),
+    ];
+    take(&mut slices, 0)
+}
+
+// moves `N` items starting at `start` out of `items`, each item can only be taken once
+fn take<T, const M: usize, const N: usize>(items: &mut [Option<T>; M], start: usize) -> [T; N] {
+    core::array::from_fn(|i| items[start + i].take().unwrap())
+}
+
+/// The oscillators of a mode with the drives and slices they use.
+///
+/// The firmware uses the board's drives and slices, the host simulator its own ones.
+pub enum OscConfiguration<F = Drive, S = PwmSlice>
+where
+    F: Floppy,
+    S: Slice,
+{
+    Single([SingleOscillator<F, S>; DRIVE_COUNT]),
+    Unisono(UnisonoOscillator<F, S>, [S; DRIVE_COUNT - 1]),
+    // with an odd number of drives the last one stays silent
     Inverse(
-        (
-            InverseOscillator<Pwm0, Floppy0, Floppy1>,
-            InverseOscillator<Pwm1, Floppy2, Floppy3>,
-            InverseOscillator<Pwm2, Floppy4, Floppy5>,
-        ),
-        (
-            Slice<Pwm3, FreeRunning>,
-            Slice<Pwm4, FreeRunning>,
-            Slice<Pwm5, FreeRunning>,
-        ),
+        [InverseOscillator<F, F, S>; INVERSE_COUNT],
+        [S; DRIVE_COUNT - INVERSE_COUNT],
+        [F; DRIVE_COUNT % 2],
     ),
+    #[cfg(feature = "pio-step")]
+    Pio([PioOscillator<F>; DRIVE_COUNT], [S; DRIVE_COUNT]),
 }
 
-impl OscConfiguration {
-    pub fn free(self) -> (OscSlices, Floppies) {
+impl<F, S> OscConfiguration<F, S>
+where
+    F: Floppy,
+    S: Slice,
+{
+    pub fn free(self) -> ([S; DRIVE_COUNT], [F; DRIVE_COUNT]) {
         match self {
-            OscConfiguration::Unisono(os, (s1, s2, s3, s4, s5)) => {
+            OscConfiguration::Unisono(os, rest) => {
                 let (s0, floppies) = os.free();
-                return ((s0, s1, s2, s3, s4, s5), floppies);
+                let mut s0 = Some(s0);
+                let mut slices = rest.map(Some);
+                let slices = core::array::from_fn(|i| match i {
+                    0 => s0.take().unwrap(),
+                    _ => slices[i - 1].take().unwrap(),
+                });
+                (slices, floppies)
             }
-            OscConfiguration::Single((os0, os1, os2, os3, os4, os5)) => {
-                let (f0, s0) = os0.free();
-                let (f1, s1) = os1.free();
-                let (f2, s2) = os2.free();
-                let (f3, s3) = os3.free();
-                let (f4, s4) = os4.free();
-                let (f5, s5) = os5.free();
-
-                return ((s0, s1, s2, s3, s4, s5), (f0, f1, f2, f3, f4, f5));
+            OscConfiguration::Single(oss) => {
+                let mut parts = oss.map(|os| {
+                    let (floppy, slice) = os.free();
+                    (Some(floppy), Some(slice))
+                });
+                let slices = core::array::from_fn(|i| parts[i].1.take().unwrap());
+                let floppies = core::array::from_fn(|i| parts[i].0.take().unwrap());
+                (slices, floppies)
             }
-            OscConfiguration::Inverse((os0, os1, os2), (s3, s4, s5)) => {
-                let (s0, (f0, f1)) = os0.free();
-                let (s1, (f2, f3)) = os1.free();
-                let (s2, (f4, f5)) = os2.free();
-
-                return ((s0, s1, s2, s3, s4, s5), (f0, f1, f2, f3, f4, f5));
+            OscConfiguration::Inverse(oss, rest, spare) => {
+                let mut slices: [Option<S>; DRIVE_COUNT] = core::array::from_fn(|_| None);
+                let mut floppies: [Option<F>; DRIVE_COUNT] = core::array::from_fn(|_| None);
+                for (i, os) in oss.into_iter().enumerate() {
+                    let (slice, (f0, f1)) = os.free();
+                    slices[i] = Some(slice);
+                    floppies[2 * i] = Some(f0);
+                    floppies[2 * i + 1] = Some(f1);
+                }
+                for (i, slice) in rest.into_iter().enumerate() {
+                    slices[INVERSE_COUNT + i] = Some(slice);
+                }
+                for (i, floppy) in spare.into_iter().enumerate() {
+                    floppies[2 * INVERSE_COUNT + i] = Some(floppy);
+                }
+                (take(&mut slices, 0), take(&mut floppies, 0))
             }
+            // the state machines keep running silently, their oscillators can't be rebuilt
+            #[cfg(feature = "pio-step")]
+            OscConfiguration::Pio(oss, slices) => (slices, oss.map(|os| os.free())),
         }
     }
 
-    fn for_each<F: Fn(&mut dyn Oscillator)>(&mut self, func: F) {
-        self.find(|osc| {
-            func(osc);
-            false
-        });
+    fn for_each<C: FnMut(&mut dyn Oscillator)>(&mut self, mut func: C) {
+        match self {
+            OscConfiguration::Single(oss) => oss.iter_mut().for_each(|os| func(os)),
+            OscConfiguration::Unisono(os, _) => func(os),
+            OscConfiguration::Inverse(oss, _, _) => oss.iter_mut().for_each(|os| func(os)),
+            #[cfg(feature = "pio-step")]
+            OscConfiguration::Pio(oss, _) => oss.iter_mut().for_each(|os| func(os)),
+        }
     }
 
-    fn find<F: Fn(&mut dyn Oscillator) -> bool>(&mut self, func: F) -> Option<&mut dyn Oscillator> {
+    fn voice(&mut self, index: u8) -> Option<&mut dyn Oscillator> {
+        let index = index as usize;
         match self {
-            OscConfiguration::Single(oss) => {
-                if func(&mut oss.0) {
-                    return Some(&mut oss.0);
-                }
-                if func(&mut oss.1) {
-                    return Some(&mut oss.1);
-                }
-                if func(&mut oss.2) {
-                    return Some(&mut oss.2);
-                }
-                if func(&mut oss.3) {
-                    return Some(&mut oss.3);
-                }
-                if func(&mut oss.4) {
-                    return Some(&mut oss.4);
-                }
-                if func(&mut oss.5) {
-                    return Some(&mut oss.5);
-                }
-                None
-            }
-            OscConfiguration::Unisono(os, _) => {
-                if func(os) {
-                    return Some(os);
-                }
-                return None;
-            }
-            OscConfiguration::Inverse(oss, _) => {
-                if func(&mut oss.0) {
-                    return Some(&mut oss.0);
-                }
-                if func(&mut oss.1) {
-                    return Some(&mut oss.1);
-                }
-                if func(&mut oss.2) {
-                    return Some(&mut oss.2);
-                }
-                None
+            OscConfiguration::Single(oss) => oss.get_mut(index).map(|os| os as &mut dyn Oscillator),
+            OscConfiguration::Unisono(os, _) => (index == 0).then_some(os as &mut dyn Oscillator),
+            OscConfiguration::Inverse(oss, _, _) => {
+                oss.get_mut(index).map(|os| os as &mut dyn Oscillator)
             }
+            #[cfg(feature = "pio-step")]
+            OscConfiguration::Pio(oss, _) => oss.get_mut(index).map(|os| os as &mut dyn Oscillator),
         }
     }
 
-    pub fn oscillator_count(&self) -> u8 {
+    fn drive(&mut self, index: usize) -> Option<&mut F> {
         match self {
-            OscConfiguration::Single(_) => 6,
-            OscConfiguration::Unisono(_, _) => 1,
-            OscConfiguration::Inverse(_, _) => 3,
+            OscConfiguration::Single(oss) => oss.get_mut(index).map(|os| os.floppy_mut()),
+            OscConfiguration::Unisono(os, _) => os.floppies_mut().get_mut(index),
+            OscConfiguration::Inverse(oss, _, spare) => match (oss.get_mut(index / 2), index % 2) {
+                (Some(os), 0) => Some(os.floppies_mut().0),
+                (Some(os), _) => Some(os.floppies_mut().1),
+                (None, _) => spare.get_mut(index - 2 * INVERSE_COUNT),
+            },
+            #[cfg(feature = "pio-step")]
+            OscConfiguration::Pio(oss, _) => oss.get_mut(index).map(|os| os.floppy_mut()),
         }
     }
 
-    pub fn stop_note(&mut self, note: u8) {
-        info!("stopping note");
-        if let Some(active_osc) = self.find(|osc| osc.get_note() == Some(note)) {
-            let active_age = active_osc.get_age();
-            active_osc.stop();
-
-            self.for_each(|osc| {
-                if let Some(osc_note) = osc.get_note() {
-                    let age = osc.get_age();
-                    if osc_note == note {
-                        osc.set_age(0);
-                    } else if age > active_age {
-                        osc.set_age(age - 1);
-                    }
-                }
-            })
+    pub fn oscillator_count(&self) -> u8 {
+        match self {
+            OscConfiguration::Single(_) => mode_voice_count(OscMode::Single),
+            OscConfiguration::Unisono(_, _) => mode_voice_count(OscMode::Unisono),
+            OscConfiguration::Inverse(_, _, _) => mode_voice_count(OscMode::Inverse),
+            #[cfg(feature = "pio-step")]
+            OscConfiguration::Pio(_, _) => DRIVE_COUNT as u8,
         }
     }
 
-    pub fn play_note(&mut self, note: u8) {
-        info!("playing note {}", note);
-        if let Some(active_osc) = self.find(|osc| osc.get_note() == Some(note)) {
-            // retrigger
-            let active_age = active_osc.get_age();
-            active_osc.set_note(note);
-
-            self.for_each(|osc| {
-                if let Some(osc_note) = osc.get_note() {
-                    let age = osc.get_age();
-                    if age < active_age {
-                        osc.set_age(age + 1);
-                    } else if osc_note == note {
-                        osc.set_age(0);
-                    }
-                }
-            });
-            return;
+    /// Plays `note` raised by `cents`, 0 to 99, on voice `index`.
+    pub fn set_voice(&mut self, index: u8, note: u8, cents: u8) {
+        info!("playing note {} +{} cents on voice {}", note, cents, index);
+        if let Some(osc) = self.voice(index) {
+            let result = osc.set_note(note, cents);
+            recover(osc, result);
         }
+    }
 
-        if let Some(free_osc) = self.find(|osc| osc.get_note() == None) {
-            free_osc.set_note(note);
-
-            self.for_each(|osc| {
-                if let Some(osc_note) = osc.get_note() {
-                    if osc_note == note {
-                        osc.set_age(0);
-                    } else {
-                        osc.set_age(osc.get_age() + 1);
-                    }
-                }
-            });
-            return;
+    pub fn stop_voice(&mut self, index: u8) {
+        info!("stopping voice {}", index);
+        if let Some(osc) = self.voice(index) {
+            let result = osc.stop();
+            recover(osc, result);
         }
+    }
 
-        let osc_count = self.oscillator_count();
-        if let Some(oldest_osc) = self.find(|osc| osc.get_age() >= osc_count) {
-            oldest_osc.set_note(note);
-
-            self.for_each(|osc| {
-                if let Some(osc_note) = osc.get_note() {
-                    if osc_note == note {
-                        osc.set_age(0);
-                    } else {
-                        osc.set_age(osc.get_age() + 1);
-                    }
-                }
-            })
-        }
+    pub fn handle_interrupt(&mut self, pending: u32) {
+        self.for_each(|os| {
+            if pending & os.irq_mask() != 0 {
+                let result = os.handle_interrupt();
+                recover(os, result);
+            }
+        });
     }
 
-    pub fn handle_interrupt(&mut self) {
-        self.for_each(|os| os.handle_interrupt());
+    /// The handler before the per slice dispatch, kept as the `isr-legacy` baseline:
+    /// every oscillator asks `take_flag` whether its slice wrapped, which reads and
+    /// clears the flag on its own.
+    #[cfg(feature = "isr-legacy")]
+    pub fn poll_interrupts(&mut self, mut take_flag: impl FnMut(u32) -> bool) {
+        self.for_each(|os| {
+            if take_flag(os.irq_mask()) {
+                let result = os.handle_interrupt();
+                recover(os, result);
+            }
+        });
     }
 
-    pub fn new_single(slices: OscSlices, floppies: Floppies) -> Self {
-        Self::Single((
-            SingleOscillator::new(slices.0, floppies.0),
-            SingleOscillator::new(slices.1, floppies.1),
-            SingleOscillator::new(slices.2, floppies.2),
-            SingleOscillator::new(slices.3, floppies.3),
-            SingleOscillator::new(slices.4, floppies.4),
-            SingleOscillator::new(slices.5, floppies.5),
-        ))
+    pub fn new_single(slices: [S; DRIVE_COUNT], floppies: [F; DRIVE_COUNT]) -> Self {
+        let mut floppies = floppies.map(Some);
+        let mut i = 0;
+        Self::Single(slices.map(|slice| {
+            let osc = SingleOscillator::new(slice, floppies[i].take().unwrap());
+            i += 1;
+            osc
+        }))
     }
 
-    pub fn new_unisono(slices: OscSlices, floppies: Floppies) -> Self {
-        Self::Unisono(
-            UnisonoOscillator::new(slices.0, floppies),
-            (slices.1, slices.2, slices.3, slices.4, slices.5),
-        )
+    pub fn new_unisono(slices: [S; DRIVE_COUNT], floppies: [F; DRIVE_COUNT]) -> Self {
+        let mut slices = slices.map(Some);
+        let s0 = slices[0].take().unwrap();
+        Self::Unisono(UnisonoOscillator::new(s0, floppies), take(&mut slices, 1))
     }
 
-    pub fn new_inverse(slices: OscSlices, floppies: Floppies) -> Self {
+    pub fn new_inverse(slices: [S; DRIVE_COUNT], floppies: [F; DRIVE_COUNT]) -> Self {
+        let mut slices = slices.map(Some);
+        let mut floppies = floppies.map(Some);
+        let oscillators = core::array::from_fn(|i| {
+            let pair = (
+                floppies[2 * i].take().unwrap(),
+                floppies[2 * i + 1].take().unwrap(),
+            );
+            InverseOscillator::new(slices[i].take().unwrap(), pair)
+        });
         Self::Inverse(
-            (
-                InverseOscillator::new(slices.0, (floppies.0, floppies.1)),
-                InverseOscillator::new(slices.1, (floppies.2, floppies.3)),
-                InverseOscillator::new(slices.2, (floppies.4, floppies.5)),
-            ),
-            (slices.3, slices.4, slices.5),
+            oscillators,
+            take(&mut slices, INVERSE_COUNT),
+            take(&mut floppies, 2 * INVERSE_COUNT),
         )
     }
 }
 
+#[cfg(feature = "pio-step")]
+impl OscConfiguration {
+    /// Plays every floppy from its own PIO state machine, the PWM slices stay unused.
+    pub fn new_pio(
+        pio0: pac::PIO0,
+        pio1: pac::PIO1,
+        resets: &mut pac::RESETS,
+        slices: OscSlices,
+        floppies: Floppies,
+    ) -> Self {
+        let (mut pio0, sm0_0, sm0_1, sm0_2, sm0_3) = pio0.split(resets);
+        let (mut pio1, sm1_0, sm1_1, sm1_2, sm1_3) = pio1.split(resets);
+        let block0 = unsafe { &*pac::PIO0::ptr() };
+        let block1 = unsafe { &*pac::PIO1::ptr() };
+        let program = step_program();
+        let program0 = pio0.install(&program).unwrap();
+        let program1 = pio1.install(&program).unwrap();
+
+        let mut floppies = floppies.map(Some).into_iter().flatten();
+
+        // starts the next drive's state machine if there is one left, the programs
+        // are never uninstalled, so sharing them is fine
+        macro_rules! start {
+            ($pio:expr, $block:expr, $sm:expr, $program:expr, $irq_offset:expr) => {
+                floppies.next().map(|floppy| {
+                    let program = unsafe { $program.share() };
+                    PioOscillator::new(
+                        $pio,
+                        $block,
+                        $sm,
+                        program,
+                        floppy.step_pin(),
+                        $irq_offset,
+                        floppy,
+                    )
+                })
+            };
+        }
+
+        let mut oscillators = [
+            start!(&pio0, block0, sm0_0, program0, PIO0_IRQ_OFFSET),
+            start!(&pio0, block0, sm0_1, program0, PIO0_IRQ_OFFSET),
+            start!(&pio0, block0, sm0_2, program0, PIO0_IRQ_OFFSET),
+            start!(&pio0, block0, sm0_3, program0, PIO0_IRQ_OFFSET),
+            start!(&pio1, block1, sm1_0, program1, PIO1_IRQ_OFFSET),
+            start!(&pio1, block1, sm1_1, program1, PIO1_IRQ_OFFSET),
+            start!(&pio1, block1, sm1_2, program1, PIO1_IRQ_OFFSET),
+            start!(&pio1, block1, sm1_3, program1, PIO1_IRQ_OFFSET),
+        ];
+
+        Self::Pio(take(&mut oscillators, 0), slices)
+    }
+}
+
 pub struct Oscillators {
     pub config: Option<OscConfiguration>,
 }
 
 impl Oscillators {
-    pub fn handle_interrupt(&mut self) {
+    pub fn handle_interrupt(&mut self, pending: u32) {
+        if let Some(config) = &mut self.config {
+            config.handle_interrupt(pending);
+        }
+    }
+
+    #[cfg(feature = "isr-legacy")]
+    pub fn poll_interrupts(&mut self, take_flag: impl FnMut(u32) -> bool) {
+        if let Some(config) = &mut self.config {
+            config.poll_interrupts(take_flag);
+        }
+    }
+
+    pub fn set_voice(&mut self, index: u8, note: u8, cents: u8) {
         if let Some(config) = &mut self.config {
-            config.handle_interrupt();
+            config.set_voice(index, note, cents);
+        }
+    }
+
+    pub fn stop_voice(&mut self, index: u8) {
+        if let Some(config) = &mut self.config {
+            config.stop_voice(index);
+        }
+    }
+
+    pub fn voice_count(&self) -> u8 {
+        self.config
+            .as_ref()
+            .map_or(0, |config| config.oscillator_count())
+    }
+
+    /// Limits the head movement of drive `index`, it applies from the next step on.
+    pub fn set_track_range(&mut self, index: u8, first: u8, last: u8) {
+        match self
+            .config
+            .as_mut()
+            .and_then(|config| config.drive(index as usize))
+        {
+            Some(drive) => drive.set_track_range(first, last),
+            None => warn!("no drive {}", index),
         }
     }
 
@@ -295,7 +423,20 @@ impl Oscillators {
         self.config = Some(config);
     }
 
+    pub fn set_mode(&mut self, mode: OscMode) {
+        match mode {
+            OscMode::Single => self.to_single(),
+            OscMode::Inverse => self.to_inverse(),
+            OscMode::Unisono => self.to_unisono(),
+        }
+    }
+
     pub fn to_single(&mut self) {
+        if cfg!(feature = "pio-step") {
+            warn!("mode switching is not available with PIO stepping");
+            return;
+        }
+
         if let Some(config) = self.config.take() {
             let (slices, floppies) = config.free();
             self.config = Some(OscConfiguration::new_single(slices, floppies))
@@ -303,6 +444,13 @@ impl Oscillators {
     }
 
     pub fn to_unisono(&mut self) {
+        // a slice can only drive the step pins wired to its own channels,
+        // PIO state machines only play the single configuration
+        if cfg!(any(feature = "hw-step", feature = "pio-step")) {
+            warn!("unisono is not available with hardware stepping");
+            return;
+        }
+
         if let Some(config) = self.config.take() {
             let (slices, floppies) = config.free();
             self.config = Some(OscConfiguration::new_unisono(slices, floppies))
@@ -310,6 +458,11 @@ impl Oscillators {
     }
 
     pub fn to_inverse(&mut self) {
+        if cfg!(any(feature = "hw-step", feature = "pio-step")) {
+            warn!("inverse is not available with hardware stepping");
+            return;
+        }
+
         if let Some(config) = self.config.take() {
             let (slices, floppies) = config.free();
             self.config = Some(OscConfiguration::new_inverse(slices, floppies))
@@ -317,16 +470,94 @@ impl Oscillators {
     }
 }
 
-pub static OSCILLATORS: cortex_interrupt::Mutex<RefCell<Oscillators>> =
-    Mutex::new(RefCell::new(Oscillators { config: None }));
+/// Holds the oscillators shared between the main loop and their interrupt handlers.
+///
+/// Instead of a global critical section, the main loop masks the oscillator interrupts
+/// while it accesses the oscillators (see `with_oscillators`), so other interrupts keep running.
+pub struct OscillatorsCell(UnsafeCell<Oscillators>);
+
+// the cell is only accessed from the oscillator interrupts and, with those masked, from thread mode
+unsafe impl Sync for OscillatorsCell {}
+
+pub static OSCILLATORS: OscillatorsCell =
+    OscillatorsCell(UnsafeCell::new(Oscillators { config: None }));
+
+/// Runs `f` on the oscillators with the oscillator interrupts masked.
+///
+/// Must only be called from core 0, outside of interrupt handlers.
+pub fn with_oscillators<R, F: FnOnce(&mut Oscillators) -> R>(f: F) -> R {
+    let was_unmasked = NVIC::is_enabled(OSCILLATOR_INTERRUPTS[0]);
+    for irq in OSCILLATOR_INTERRUPTS {
+        NVIC::mask(irq);
+    }
+    cortex_m::asm::dsb();
+    cortex_m::asm::isb();
+
+    let result = f(unsafe { &mut *OSCILLATORS.0.get() });
+
+    if was_unmasked {
+        unmask_oscillator_interrupts();
+    }
+    result
+}
+
+pub fn unmask_oscillator_interrupts() {
+    for irq in OSCILLATOR_INTERRUPTS {
+        unsafe { NVIC::unmask(irq) };
+    }
+}
 
 #[interrupt]
 fn PWM_IRQ_WRAP() {
-    cortex_interrupt::free(|cs| {
-        OSCILLATORS
-            .borrow(cs)
-            .borrow_mut()
-            .deref_mut()
-            .handle_interrupt()
+    #[cfg(feature = "isr-bench")]
+    let start = crate::bench::start();
+
+    let pwm = unsafe { &*pac::PWM::ptr() };
+    // with_oscillators masks this interrupt, so nothing else holds a reference right now
+    let oscillators = unsafe { &mut *OSCILLATORS.0.get() };
+
+    // read all wrapped slices at once and only step the oscillators that belong to them
+    #[cfg(not(feature = "isr-legacy"))]
+    {
+        let wrapped_slices = pwm.ints.read().bits();
+        pwm.intr.write(|w| unsafe { w.bits(wrapped_slices) });
+        oscillators.handle_interrupt(wrapped_slices);
+    }
+
+    // the old handler: a global critical section and one flag check per oscillator
+    #[cfg(feature = "isr-legacy")]
+    cortex_m::interrupt::free(|_| {
+        oscillators.poll_interrupts(|mask| {
+            let wrapped = pwm.intr.read().bits() & mask != 0;
+            if wrapped {
+                pwm.intr.write(|w| unsafe { w.bits(mask) });
+            }
+            wrapped
+        })
     });
+
+    #[cfg(feature = "isr-bench")]
+    crate::bench::finish(start);
+}
+
+#[cfg(feature = "pio-step")]
+fn handle_pio_interrupt(pio: &pac::pio0::RegisterBlock, irq_offset: u8) {
+    // the step program raises one of the irq flags 0 to 3 per state machine
+    let flags = pio.irq.read().bits() & 0xf;
+    pio.irq.write(|w| unsafe { w.bits(flags) });
+
+    let oscillators = unsafe { &mut *OSCILLATORS.0.get() };
+    oscillators.handle_interrupt(flags << irq_offset);
+}
+
+#[cfg(feature = "pio-step")]
+#[interrupt]
+fn PIO0_IRQ_0() {
+    handle_pio_interrupt(unsafe { &*pac::PIO0::ptr() }, PIO0_IRQ_OFFSET);
+}
+
+#[cfg(feature = "pio-step")]
+#[interrupt]
+fn PIO1_IRQ_0() {
+    handle_pio_interrupt(unsafe { &*pac::PIO1::ptr() }, PIO1_IRQ_OFFSET);
 }
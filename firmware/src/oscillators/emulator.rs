@@ -0,0 +1,350 @@
+//! The PWM slices emulated in virtual time, for the host tests and tools.
+//!
+//! Time is counted in system clock cycles. Every slice divides the clock by `div_int`
+//! and wraps after `top + 1` ticks, twice that in phase correct mode. A wrap sets the
+//! slice's flag in INTR, `run_until` then calls the interrupt handler with INTS and
+//! clears the flags first, like `PWM_IRQ_WRAP` does. Like on the RP2040 the counter keeps
+//! its value while a slice is disabled and TOP is double buffered: while a slice runs,
+//! a new TOP takes effect at the next wrap. The channel outputs aren't emulated.
+
+use core::cell::{Cell, RefCell};
+
+use super::slice::Slice;
+
+/// The clock `NOTE_DICT` is computed for.
+pub const SYS_CLOCK_HZ: u64 = 125_000_000;
+pub const SLICE_COUNT: usize = 8;
+
+// the counter is 16 bits wide, past a lowered TOP it runs to the end before it wraps
+const COUNTER_RANGE: u64 = 1 << 16;
+
+#[derive(Clone, Copy, Default)]
+struct SliceRegs {
+    enabled: bool,
+    ph_correct: bool,
+    div_int: u8,
+    top: u16,
+    top_buffer: u16,
+    // ticks since the last wrap and cycles into the current tick
+    ctr: u64,
+    prescale: u64,
+}
+
+impl SliceRegs {
+    // NOTE_DICT never sets 0
+    fn div(&self) -> u64 {
+        self.div_int.max(1) as u64
+    }
+
+    fn period_ticks(&self) -> u64 {
+        (self.top as u64 + 1) * (self.ph_correct as u64 + 1)
+    }
+
+    fn ticks_to_wrap(&self) -> u64 {
+        match self.period_ticks() {
+            period if self.ctr < period => period - self.ctr,
+            period => COUNTER_RANGE - self.ctr + period,
+        }
+    }
+
+    fn cycles_to_wrap(&self) -> u64 {
+        self.ticks_to_wrap() * self.div() - self.prescale
+    }
+
+    // returns whether the slice wrapped on the way
+    fn advance(&mut self, cycles: u64) -> bool {
+        let cycles = self.prescale + cycles;
+        let mut ticks = cycles / self.div();
+        self.prescale = cycles % self.div();
+
+        let mut wrapped = false;
+        while ticks >= self.ticks_to_wrap() {
+            ticks -= self.ticks_to_wrap();
+            self.ctr = 0;
+            self.top = self.top_buffer;
+            wrapped = true;
+        }
+        self.ctr = (self.ctr + ticks) % COUNTER_RANGE;
+        wrapped
+    }
+}
+
+#[derive(Default)]
+struct Registers {
+    slices: [SliceRegs; SLICE_COUNT],
+    inte: u32,
+    intr: u32,
+}
+
+/// The PWM block, its slices are handed out with `slice`.
+#[derive(Default)]
+pub struct PwmEmulator {
+    regs: RefCell<Registers>,
+    now: Cell<u64>,
+}
+
+impl PwmEmulator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The slice `num`, for the oscillators.
+    pub fn slice(&self, num: u8) -> EmulatedSlice<'_> {
+        assert!((num as usize) < SLICE_COUNT);
+        EmulatedSlice { num, pwm: self }
+    }
+
+    /// The current cycle.
+    pub fn now(&self) -> u64 {
+        self.now.get()
+    }
+
+    /// INTR, the slices that wrapped since their flag was cleared.
+    pub fn raw_interrupts(&self) -> u32 {
+        self.regs.borrow().intr
+    }
+
+    /// INTE, the slices whose wrap raises the interrupt.
+    pub fn interrupt_mask(&self) -> u32 {
+        self.regs.borrow().inte
+    }
+
+    pub fn is_enabled(&self, num: u8) -> bool {
+        self.regs.borrow().slices[num as usize].enabled
+    }
+
+    /// Cycles between two wraps of slice `num` with its current settings.
+    pub fn wrap_period(&self, num: u8) -> u64 {
+        let regs = self.regs.borrow();
+        let slice = &regs.slices[num as usize];
+        slice.period_ticks() * slice.div()
+    }
+
+    /// Advances to `cycle` with the interrupt masked, the flags of the wraps stay set.
+    pub fn advance(&self, cycle: u64) {
+        let mut regs = self.regs.borrow_mut();
+        let cycles = cycle.saturating_sub(self.now.get());
+        let mut wrapped = 0;
+        for (num, slice) in regs.slices.iter_mut().enumerate() {
+            if slice.enabled && slice.advance(cycles) {
+                wrapped |= 1 << num;
+            }
+        }
+        regs.intr |= wrapped;
+        self.now.set(self.now.get().max(cycle));
+    }
+
+    /// Advances to `cycle`, `irq` is called with the cycle and the pending slices whenever
+    /// the interrupt fires. The handler may change the slices.
+    pub fn run_until(&self, cycle: u64, mut irq: impl FnMut(u64, u32)) {
+        loop {
+            // read INTS and clear what was read, as the first thing the handler does
+            let pending = {
+                let mut regs = self.regs.borrow_mut();
+                let pending = regs.intr & regs.inte;
+                regs.intr &= !pending;
+                pending
+            };
+            if pending != 0 {
+                irq(self.now.get(), pending);
+                continue;
+            }
+
+            let next_wrap = self
+                .regs
+                .borrow()
+                .slices
+                .iter()
+                .filter(|slice| slice.enabled)
+                .map(|slice| self.now.get() + slice.cycles_to_wrap())
+                .min();
+            match next_wrap {
+                Some(at) if at <= cycle => self.advance(at),
+                _ => return self.advance(cycle),
+            }
+        }
+    }
+
+    fn modify(&self, num: u8, func: impl FnOnce(&mut SliceRegs)) {
+        func(&mut self.regs.borrow_mut().slices[num as usize]);
+    }
+}
+
+pub struct EmulatedSlice<'a> {
+    num: u8,
+    pwm: &'a PwmEmulator,
+}
+
+impl<'a> Slice for EmulatedSlice<'a> {
+    fn irq_mask(&self) -> u32 {
+        1 << self.num
+    }
+
+    fn enable(&mut self) {
+        self.pwm.modify(self.num, |slice| slice.enabled = true);
+    }
+
+    fn disable(&mut self) {
+        self.pwm.modify(self.num, |slice| slice.enabled = false);
+    }
+
+    fn set_ph_correct(&mut self) {
+        self.pwm.modify(self.num, |slice| slice.ph_correct = true);
+    }
+
+    fn set_div_int(&mut self, value: u8) {
+        self.pwm.modify(self.num, |slice| slice.div_int = value);
+    }
+
+    fn set_top(&mut self, value: u16) {
+        self.pwm.modify(self.num, |slice| {
+            slice.top_buffer = value;
+            if !slice.enabled {
+                slice.top = value;
+            }
+        });
+    }
+
+    fn set_duty(&mut self, _duty: u16) {}
+
+    fn enable_interrupt(&mut self) {
+        self.pwm.regs.borrow_mut().inte |= self.irq_mask();
+    }
+
+    fn disable_interrupt(&mut self) {
+        self.pwm.regs.borrow_mut().inte &= !self.irq_mask();
+    }
+
+    fn clear_interrupt(&mut self) {
+        self.pwm.regs.borrow_mut().intr &= !self.irq_mask();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        floppy::{Floppy, FloppyDirection, FloppyError, DRIVE_COUNT},
+        oscillators::OscConfiguration,
+    };
+
+    #[derive(Default)]
+    struct CountingFloppy {
+        enabled: bool,
+        steps: u32,
+    }
+
+    impl Floppy for CountingFloppy {
+        fn set_enabled(&mut self, enabled: bool) -> Result<(), FloppyError> {
+            self.enabled = enabled;
+            Ok(())
+        }
+
+        fn step(&mut self) -> Result<(), FloppyError> {
+            if !self.enabled {
+                return Err(FloppyError::Disabled);
+            }
+            self.steps += 1;
+            Ok(())
+        }
+
+        fn get_dir(&self) -> FloppyDirection {
+            FloppyDirection::Forward
+        }
+
+        fn error_count(&self) -> u32 {
+            0
+        }
+
+        fn set_track_range(&mut self, _first: u8, _last: u8) {}
+    }
+
+    type Config<'a> = OscConfiguration<CountingFloppy, EmulatedSlice<'a>>;
+
+    fn single(pwm: &PwmEmulator) -> Config<'_> {
+        let slices = core::array::from_fn(|num| pwm.slice(num as u8));
+        OscConfiguration::new_single(slices, Default::default())
+    }
+
+    fn steps(config: &mut Config) -> [u32; DRIVE_COUNT] {
+        core::array::from_fn(|drive| config.drive(drive).unwrap().steps)
+    }
+
+    #[test]
+    fn note_dict_frequencies() {
+        let pwm = PwmEmulator::new();
+        let mut config = single(&pwm);
+        for (note, cents) in (0..128).flat_map(|note| [(note, 0), (note, 50), (note, 99)]) {
+            config.set_voice(0, note, cents);
+            let mut wraps = std::vec::Vec::new();
+            while wraps.len() < 3 {
+                pwm.run_until(pwm.now() + SYS_CLOCK_HZ, |cycle, pending| {
+                    config.handle_interrupt(pending);
+                    if pending & 1 != 0 {
+                        wraps.push(cycle);
+                    }
+                });
+            }
+            config.stop_voice(0);
+
+            // every wrap toggles the step pin, a step takes two of them. With hw-step the
+            // channel outputs pulse once per wrap, phase correct mode halves the rate.
+            let wraps_per_step = if cfg!(feature = "hw-step") { 1 } else { 2 };
+            let step_hz = SYS_CLOCK_HZ as f64 / (wraps_per_step * (wraps[2] - wraps[1])) as f64;
+            let pitch = note as f64 + cents as f64 / 100.0;
+            let expected_hz = 440.0 * 2f64.powf((pitch - 69.0) / 12.0);
+            let off = 1200.0 * (step_hz / expected_hz).log2();
+            assert!(off.abs() < 1.0, "note {note} +{cents} is {off} cents off");
+        }
+        assert!(steps(&mut config)[0] >= 3 * 3 * 128);
+        assert_eq!(steps(&mut config)[1..], [0; DRIVE_COUNT - 1]);
+    }
+
+    #[test]
+    fn modes_step_their_drives() {
+        let pwm = PwmEmulator::new();
+        let slices = core::array::from_fn(|num| pwm.slice(num as u8));
+        let mut config = OscConfiguration::new_unisono(slices, Default::default());
+        config.set_voice(0, 69, 0);
+        let wraps = SYS_CLOCK_HZ / 10 / pwm.wrap_period(0);
+        pwm.run_until(SYS_CLOCK_HZ / 10, |_, pending| {
+            config.handle_interrupt(pending)
+        });
+        // one slice steps all drives
+        assert!(steps(&mut config)
+            .iter()
+            .all(|&steps| steps as u64 == wraps));
+
+        let (slices, floppies) = config.free();
+        let mut config = OscConfiguration::new_inverse(slices, floppies);
+        config.set_voice(1, 69, 0);
+        let mut more = 0;
+        pwm.run_until(2 * SYS_CLOCK_HZ / 10, |_, pending| {
+            config.handle_interrupt(pending);
+            more += 1;
+        });
+        // both drives of the pair point forward, only the first one steps
+        let wraps = wraps as u32;
+        assert_eq!(steps(&mut config)[..4], [wraps, wraps, wraps + more, wraps]);
+    }
+
+    #[test]
+    fn stop_clears_pending_interrupt() {
+        let pwm = PwmEmulator::new();
+        let mut config = single(&pwm);
+        config.set_voice(2, 60, 0);
+        let period = pwm.wrap_period(2);
+
+        // the slice wraps while the interrupt is masked, like during `with_oscillators`
+        pwm.advance(pwm.now() + period);
+        assert_eq!(pwm.raw_interrupts(), 1 << 2);
+        config.stop_voice(2);
+        assert_eq!(pwm.raw_interrupts(), 0);
+        assert!(!pwm.is_enabled(2));
+
+        let mut calls = 0;
+        pwm.run_until(pwm.now() + 10 * period, |_, _| calls += 1);
+        assert_eq!(calls, 0);
+        assert_eq!(steps(&mut config)[2], 0);
+    }
+}
@@ -1,91 +1,81 @@
-use rp_pico::hal::pwm::{FreeRunning, Slice, SliceId};
+use crate::floppy::{Floppy, FloppyError};
 
-use crate::floppy::Floppy;
+use super::{
+    set_pwm_note,
+    slice::{PwmSlice, Slice},
+    Oscillator,
+};
 
-use super::{set_pwm_note, Oscillator};
-
-pub struct InverseOscillator<S, F0, F1>
+pub struct InverseOscillator<F0, F1, S = PwmSlice>
 where
-    S: SliceId,
     F0: Floppy,
     F1: Floppy,
+    S: Slice,
 {
-    pwm_slice: Slice<S, FreeRunning>,
+    pwm_slice: S,
     floppies: (F0, F1),
-    note: Option<u8>,
-    age: u8,
 }
 
-impl<S, F0, F1> InverseOscillator<S, F0, F1>
+impl<F0, F1, S> InverseOscillator<F0, F1, S>
 where
-    S: SliceId,
     F0: Floppy,
     F1: Floppy,
+    S: Slice,
 {
-    pub fn new(mut pwm_slice: Slice<S, FreeRunning>, floppies: (F0, F1)) -> Self {
+    pub fn new(mut pwm_slice: S, floppies: (F0, F1)) -> Self {
         pwm_slice.disable();
         pwm_slice.clear_interrupt();
         pwm_slice.enable_interrupt();
         Self {
             pwm_slice,
             floppies,
-            note: None,
-            age: 0,
         }
     }
 
-    pub fn free(mut self) -> (Slice<S, FreeRunning>, (F0, F1)) {
-        self.stop();
+    pub fn floppies_mut(&mut self) -> (&mut F0, &mut F1) {
+        (&mut self.floppies.0, &mut self.floppies.1)
+    }
+
+    pub fn free(mut self) -> (S, (F0, F1)) {
+        self.stop().ok();
         (self.pwm_slice, self.floppies)
     }
 }
 
-impl<S, F0, F1> Oscillator for InverseOscillator<S, F0, F1>
+impl<F0, F1, S> Oscillator for InverseOscillator<F0, F1, S>
 where
-    S: SliceId,
     F0: Floppy,
     F1: Floppy,
+    S: Slice,
 {
-    fn stop(&mut self) {
+    fn stop(&mut self) -> Result<(), FloppyError> {
         self.pwm_slice.disable();
         self.pwm_slice.clear_interrupt();
-        self.floppies.0.set_enabled(false).unwrap();
-        self.floppies.1.set_enabled(false).unwrap();
-        self.note = None;
-    }
 
-    fn set_note(&mut self, note: u8) {
-        self.floppies.0.set_enabled(true).unwrap();
-        self.floppies.1.set_enabled(true).unwrap();
-        set_pwm_note(&mut self.pwm_slice, note);
-        self.note = Some(note);
+        // disable both drives even if the first one fails
+        let result = self.floppies.0.set_enabled(false);
+        result.and(self.floppies.1.set_enabled(false))
     }
 
-    fn handle_interrupt(&mut self) {
-        if !self.pwm_slice.has_overflown() {
-            return;
-        }
-
-        self.pwm_slice.clear_interrupt();
+    fn set_note(&mut self, note: u8, cents: u8) -> Result<(), FloppyError> {
+        self.floppies.0.set_enabled(true)?;
+        self.floppies.1.set_enabled(true)?;
+        set_pwm_note(&mut self.pwm_slice, note, cents);
+        Ok(())
+    }
 
+    fn handle_interrupt(&mut self) -> Result<(), FloppyError> {
         let is_inverse = self.floppies.0.get_dir() != self.floppies.1.get_dir();
 
-        self.floppies.0.step().unwrap();
+        let result = self.floppies.0.step();
 
         if is_inverse {
-            self.floppies.1.step().unwrap();
+            return result.and(self.floppies.1.step());
         }
+        result
     }
 
-    fn get_note(&self) -> Option<u8> {
-        self.note
-    }
-
-    fn get_age(&self) -> u8 {
-        return self.age;
-    }
-
-    fn set_age(&mut self, age: u8) {
-        self.age = age;
+    fn irq_mask(&self) -> u32 {
+        self.pwm_slice.irq_mask()
     }
 }
@@ -0,0 +1,158 @@
+use crate::hal::{
+    pac,
+    pio::{
+        Buffers, InstalledProgram, PIOBuilder, PIOExt, PinDir, StateMachineIndex,
+        UninitStateMachine, PIO,
+    },
+};
+use pio::{
+    Assembler, InstructionOperands, JmpCondition, MovDestination, MovOperation, MovSource, SideSet,
+};
+
+use crate::{
+    floppy::{Floppy, FloppyError},
+    note_dict::NOTE_DICT,
+};
+
+use super::Oscillator;
+
+// cycles spent outside the two delay loops in one period of the step program, per half period
+const LOOP_OVERHEAD: u32 = 4;
+
+/// Builds the step program: a square wave on the side-set pin with a half period of
+/// `X + 4` cycles, raising the state machine's relative irq 0 once per period.
+///
+/// New half periods are taken from the TX FIFO at the start of every period,
+/// zero keeps the step pin low.
+pub fn step_program() -> pio::Program<{ pio::RP2040_MAX_PROGRAM_SIZE }> {
+    let mut a = Assembler::<{ pio::RP2040_MAX_PROGRAM_SIZE }>::new_with_side_set(SideSet::new(
+        true, 1, false,
+    ));
+    let mut wrap_target = a.label();
+    let mut wrap_source = a.label();
+    let mut high = a.label();
+    let mut low = a.label();
+
+    a.bind(&mut wrap_target);
+    // an empty FIFO makes pull copy X into the OSR, so the last period is kept
+    a.pull(false, false);
+    a.mov(MovDestination::X, MovOperation::None, MovSource::OSR);
+    a.jmp_with_side_set(JmpCondition::XIsZero, &mut wrap_target, 0);
+    a.mov_with_side_set(MovDestination::Y, MovOperation::None, MovSource::X, 1);
+    a.bind(&mut high);
+    a.jmp(JmpCondition::YDecNonZero, &mut high);
+    a.mov_with_side_set(MovDestination::Y, MovOperation::None, MovSource::X, 0);
+    a.irq(false, false, 0, true);
+    a.bind(&mut low);
+    a.jmp(JmpCondition::YDecNonZero, &mut low);
+    a.bind(&mut wrap_source);
+
+    a.assemble_with_wrap(wrap_source, wrap_target)
+}
+
+/// Generates the steps of a `HwStepFloppy` on a PIO state machine instead of a PWM slice.
+///
+/// The state machine's type is dropped once it runs, so oscillators of both PIO blocks fit
+/// into one array. Afterwards only its TX FIFO is written.
+pub struct PioOscillator<F>
+where
+    F: Floppy,
+{
+    floppy: F,
+    block: &'static pac::pio0::RegisterBlock,
+    sm: usize,
+    irq_mask: u32,
+}
+
+impl<F> PioOscillator<F>
+where
+    F: Floppy,
+{
+    /// Starts the step program on `sm` of `block`, driving `step_pin`.
+    ///
+    /// `irq_offset` is the bit of the first state machine of this PIO block in the
+    /// mask passed to `OscConfiguration::handle_interrupt`.
+    pub fn new<P: PIOExt, SM: StateMachineIndex>(
+        pio: &PIO<P>,
+        block: &'static pac::pio0::RegisterBlock,
+        sm: UninitStateMachine<(P, SM)>,
+        program: InstalledProgram<P>,
+        step_pin: u8,
+        irq_offset: u8,
+        floppy: F,
+    ) -> Self {
+        let (mut sm, _rx, _tx) = PIOBuilder::from_program(program)
+            .side_set_pin_base(step_pin)
+            .buffers(Buffers::OnlyTx)
+            .build(sm);
+        sm.set_pindirs([(step_pin, PinDir::Output)]);
+        // the state machine keeps running after its handle is gone
+        sm.start();
+
+        pio.interrupts()[0].enable_sm_interrupt(SM::id() as u8);
+
+        Self {
+            floppy,
+            block,
+            sm: SM::id(),
+            irq_mask: 1 << (irq_offset as usize + SM::id()),
+        }
+    }
+
+    pub fn floppy_mut(&mut self) -> &mut F {
+        &mut self.floppy
+    }
+
+    pub fn free(mut self) -> F {
+        self.stop().ok();
+        self.floppy
+    }
+
+    fn set_half_period(&mut self, half_period: u32) {
+        // drop periods the state machine didn't pick up yet, only the latest one counts
+        let pull = InstructionOperands::PULL {
+            if_empty: false,
+            block: false,
+        }
+        .encode();
+        while self.block.fstat.read().txempty().bits() & (1 << self.sm) == 0 {
+            self.block.sm[self.sm]
+                .sm_instr
+                .write(|w| unsafe { w.sm0_instr().bits(pull) });
+        }
+        self.block.txf[self.sm].write(|w| unsafe { w.bits(half_period) });
+    }
+}
+
+impl<F> Oscillator for PioOscillator<F>
+where
+    F: Floppy,
+{
+    fn stop(&mut self) -> Result<(), FloppyError> {
+        self.set_half_period(0);
+
+        self.floppy.set_enabled(false)
+    }
+
+    fn set_note(&mut self, note: u8, cents: u8) -> Result<(), FloppyError> {
+        self.floppy.set_enabled(true)?;
+        match NOTE_DICT.get(note as usize) {
+            // one PWM period corresponds to one half of a step
+            Some(pwm_setting) => {
+                let top = pwm_setting.top_raised(cents);
+                let half_period = pwm_setting.div_int as u32 * (top as u32 + 1);
+                self.set_half_period(half_period.saturating_sub(LOOP_OVERHEAD).max(1));
+            }
+            None => self.set_half_period(0),
+        }
+        Ok(())
+    }
+
+    fn handle_interrupt(&mut self) -> Result<(), FloppyError> {
+        self.floppy.step()
+    }
+
+    fn irq_mask(&self) -> u32 {
+        self.irq_mask
+    }
+}
@@ -1,84 +1,73 @@
-use rp_pico::hal::pwm::{FreeRunning, Slice, SliceId};
+use crate::floppy::{Floppy, FloppyError};
 
-use crate::floppy::Floppy;
+use super::{
+    set_pwm_note,
+    slice::{PwmSlice, Slice},
+    Oscillator,
+};
 
-use super::{set_pwm_note, Oscillator};
-
-pub struct SingleOscillator<F, SID>
+pub struct SingleOscillator<F, S = PwmSlice>
 where
-    SID: SliceId,
     F: Floppy,
+    S: Slice,
 {
     floppy: F,
-    pwm_slice: Slice<SID, FreeRunning>,
-    note: Option<u8>,
-    age: u8,
+    pwm_slice: S,
 }
 
-impl<F, SID> SingleOscillator<F, SID>
+impl<F, S> SingleOscillator<F, S>
 where
-    SID: SliceId,
     F: Floppy,
+    S: Slice,
 {
-    pub fn new(mut pwm: Slice<SID, FreeRunning>, floppy: F) -> Self {
+    pub fn new(mut pwm: S, floppy: F) -> Self {
         pwm.disable();
         pwm.clear_interrupt();
         pwm.enable_interrupt();
         Self {
             pwm_slice: pwm,
             floppy,
-            note: None,
-            age: 0,
         }
     }
 
-    pub fn step(&mut self) {
-        self.floppy.step().unwrap();
+    pub fn step(&mut self) -> Result<(), FloppyError> {
+        self.floppy.step()
+    }
+
+    pub fn floppy_mut(&mut self) -> &mut F {
+        &mut self.floppy
     }
 
-    pub fn free(mut self) -> (F, Slice<SID, FreeRunning>) {
-        self.stop();
+    pub fn free(mut self) -> (F, S) {
+        // the floppy is handed back either way, its error counter keeps track of failures
+        self.stop().ok();
         (self.floppy, self.pwm_slice)
     }
 }
 
-impl<F, SID> Oscillator for SingleOscillator<F, SID>
+impl<F, S> Oscillator for SingleOscillator<F, S>
 where
-    SID: SliceId,
     F: Floppy,
+    S: Slice,
 {
-    fn stop(&mut self) {
+    fn stop(&mut self) -> Result<(), FloppyError> {
         self.pwm_slice.disable();
         self.pwm_slice.clear_interrupt();
 
-        self.floppy.set_enabled(false).unwrap();
-        self.note = None;
-    }
-
-    fn set_note(&mut self, note: u8) {
-        self.floppy.set_enabled(true).unwrap();
-        set_pwm_note(&mut self.pwm_slice, note);
-        self.note = Some(note);
-    }
-
-    fn handle_interrupt(&mut self) {
-        if !self.pwm_slice.has_overflown() {
-            return;
-        }
-        self.pwm_slice.clear_interrupt();
-
-        self.floppy.step().unwrap();
+        self.floppy.set_enabled(false)
     }
 
-    fn get_note(&self) -> Option<u8> {
-        self.note
+    fn set_note(&mut self, note: u8, cents: u8) -> Result<(), FloppyError> {
+        self.floppy.set_enabled(true)?;
+        set_pwm_note(&mut self.pwm_slice, note, cents);
+        Ok(())
     }
 
-    fn get_age(&self) -> u8 {
-        return self.age;
+    fn handle_interrupt(&mut self) -> Result<(), FloppyError> {
+        self.floppy.step()
     }
 
-    fn set_age(&mut self, age: u8) {
-        self.age = age;
+    fn irq_mask(&self) -> u32 {
+        self.pwm_slice.irq_mask()
     }
 }
@@ -0,0 +1,95 @@
+use crate::hal::{
+    pac,
+    pwm::{FreeRunning, Slice as HalSlice, SliceId},
+};
+
+/// The parts of a PWM slice the oscillators use, the host simulator implements it too.
+pub trait Slice {
+    /// Bit of this slice in the interrupt flags.
+    fn irq_mask(&self) -> u32;
+    fn enable(&mut self);
+    fn disable(&mut self);
+    fn set_ph_correct(&mut self);
+    fn set_div_int(&mut self, value: u8);
+    fn set_top(&mut self, value: u16);
+    /// Sets the compare value of both channels.
+    fn set_duty(&mut self, duty: u16);
+    fn enable_interrupt(&mut self);
+    fn disable_interrupt(&mut self);
+    fn clear_interrupt(&mut self);
+}
+
+// offsets of the atomic set and clear aliases of every peripheral register
+const SET_ALIAS: usize = 0x2000;
+const CLEAR_ALIAS: usize = 0x3000;
+
+/// A free running PWM slice whose id is only known at runtime.
+///
+/// The HAL encodes the slice id in the type, which rules out arrays of slices.
+/// Taking ownership of the typed slice guarantees that nobody else touches its registers.
+pub struct PwmSlice {
+    num: u8,
+}
+
+impl<SID: SliceId> From<HalSlice<SID, FreeRunning>> for PwmSlice {
+    fn from(_slice: HalSlice<SID, FreeRunning>) -> Self {
+        Self { num: SID::DYN.num }
+    }
+}
+
+impl PwmSlice {
+    fn ch(&self) -> &pac::pwm::CH {
+        unsafe { &(*pac::PWM::ptr()).ch[self.num as usize] }
+    }
+}
+
+impl Slice for PwmSlice {
+    fn irq_mask(&self) -> u32 {
+        1 << self.num
+    }
+
+    fn enable(&mut self) {
+        self.ch().csr.modify(|_, w| w.en().set_bit());
+    }
+
+    fn disable(&mut self) {
+        self.ch().csr.modify(|_, w| w.en().clear_bit());
+    }
+
+    fn set_ph_correct(&mut self) {
+        self.ch().csr.modify(|_, w| w.ph_correct().set_bit());
+    }
+
+    fn set_div_int(&mut self, value: u8) {
+        self.ch().div.modify(|_, w| unsafe { w.int().bits(value) });
+    }
+
+    fn set_top(&mut self, value: u16) {
+        self.ch().top.write(|w| unsafe { w.top().bits(value) });
+    }
+
+    fn set_duty(&mut self, duty: u16) {
+        self.ch()
+            .cc
+            .write(|w| unsafe { w.a().bits(duty).b().bits(duty) });
+    }
+
+    // INTE is shared by all slices, so it is only written through the atomic aliases
+    fn enable_interrupt(&mut self) {
+        unsafe {
+            let inte = (*pac::PWM::ptr()).inte.as_ptr() as usize;
+            ((inte + SET_ALIAS) as *mut u32).write_volatile(self.irq_mask());
+        }
+    }
+
+    fn disable_interrupt(&mut self) {
+        unsafe {
+            let inte = (*pac::PWM::ptr()).inte.as_ptr() as usize;
+            ((inte + CLEAR_ALIAS) as *mut u32).write_volatile(self.irq_mask());
+        }
+    }
+
+    fn clear_interrupt(&mut self) {
+        unsafe { (*pac::PWM::ptr()).intr.write(|w| w.bits(self.irq_mask())) };
+    }
+}
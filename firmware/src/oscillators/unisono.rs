@@ -1,86 +1,79 @@
-use rp_pico::hal::pwm::{FreeRunning, Pwm0, Slice};
+use crate::floppy::{Drive, Floppy, FloppyError, DRIVE_COUNT};
 
-use crate::floppy::{Floppy, Floppy0, Floppy1, Floppy2, Floppy3, Floppy4, Floppy5};
+use super::{
+    set_pwm_note,
+    slice::{PwmSlice, Slice},
+    Oscillator,
+};
 
-use super::{set_pwm_note, Oscillator};
-
-pub struct UnisonoOscillator {
-    pwm_slice: Slice<Pwm0, FreeRunning>,
-    floppies: (Floppy0, Floppy1, Floppy2, Floppy3, Floppy4, Floppy5),
-    note: Option<u8>,
-    age: u8,
+pub struct UnisonoOscillator<F = Drive, S = PwmSlice>
+where
+    F: Floppy,
+    S: Slice,
+{
+    pwm_slice: S,
+    floppies: [F; DRIVE_COUNT],
 }
 
-impl UnisonoOscillator {
-    pub fn new(
-        mut pwm_slice: Slice<Pwm0, FreeRunning>,
-        floppies: (Floppy0, Floppy1, Floppy2, Floppy3, Floppy4, Floppy5),
-    ) -> Self {
+impl<F, S> UnisonoOscillator<F, S>
+where
+    F: Floppy,
+    S: Slice,
+{
+    pub fn new(mut pwm_slice: S, floppies: [F; DRIVE_COUNT]) -> Self {
         pwm_slice.disable();
         pwm_slice.clear_interrupt();
         pwm_slice.enable_interrupt();
         Self {
             pwm_slice,
             floppies,
-            note: None,
-            age: 0,
         }
     }
 
-    fn all_floppies(&mut self, f: fn(&mut dyn Floppy) -> ()) {
-        f(&mut self.floppies.0);
-        f(&mut self.floppies.1);
-        f(&mut self.floppies.2);
-        f(&mut self.floppies.3);
-        f(&mut self.floppies.4);
-        f(&mut self.floppies.5);
+    // runs `f` on every floppy, even if some of them fail, and returns the first error
+    fn all_floppies(
+        &mut self,
+        f: fn(&mut dyn Floppy) -> Result<(), FloppyError>,
+    ) -> Result<(), FloppyError> {
+        self.floppies
+            .iter_mut()
+            .map(|floppy| f(floppy))
+            .fold(Ok(()), Result::and)
+    }
+
+    pub fn floppies_mut(&mut self) -> &mut [F; DRIVE_COUNT] {
+        &mut self.floppies
     }
 
-    pub fn free(
-        mut self,
-    ) -> (
-        Slice<Pwm0, FreeRunning>,
-        (Floppy0, Floppy1, Floppy2, Floppy3, Floppy4, Floppy5),
-    ) {
-        self.stop();
+    pub fn free(mut self) -> (S, [F; DRIVE_COUNT]) {
+        self.stop().ok();
         (self.pwm_slice, self.floppies)
     }
 }
 
-impl Oscillator for UnisonoOscillator {
-    fn stop(&mut self) {
+impl<F, S> Oscillator for UnisonoOscillator<F, S>
+where
+    F: Floppy,
+    S: Slice,
+{
+    fn stop(&mut self) -> Result<(), FloppyError> {
         self.pwm_slice.disable();
         self.pwm_slice.clear_interrupt();
 
-        self.all_floppies(|f| f.set_enabled(false).unwrap());
-        self.note = None;
-    }
-
-    fn set_note(&mut self, note: u8) {
-        self.all_floppies(|f| f.set_enabled(true).unwrap());
-        set_pwm_note(&mut self.pwm_slice, note);
-        self.note = Some(note);
-    }
-
-    fn handle_interrupt(&mut self) {
-        if !self.pwm_slice.has_overflown() {
-            return;
-        }
-
-        self.pwm_slice.clear_interrupt();
-
-        self.all_floppies(|f| f.step().unwrap());
+        self.all_floppies(|f| f.set_enabled(false))
     }
 
-    fn get_note(&self) -> Option<u8> {
-        self.note
+    fn set_note(&mut self, note: u8, cents: u8) -> Result<(), FloppyError> {
+        self.all_floppies(|f| f.set_enabled(true))?;
+        set_pwm_note(&mut self.pwm_slice, note, cents);
+        Ok(())
     }
 
-    fn get_age(&self) -> u8 {
-        return self.age;
+    fn handle_interrupt(&mut self) -> Result<(), FloppyError> {
+        self.all_floppies(|f| f.step())
     }
 
-    fn set_age(&mut self, age: u8) {
-        self.age = age;
+    fn irq_mask(&self) -> u32 {
+        self.pwm_slice.irq_mask()
     }
 }
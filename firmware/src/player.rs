@@ -0,0 +1,155 @@
+//! Standalone playback of the songs embedded at build time.
+//!
+//! `build.rs` embeds every `.mid` file in `songs/` (or `FLOPPOTRON_SONGS`) in name order.
+//! The songs play one after the other, the messages go through `MidiHandler` like live
+//! input. MIDI start, continue, stop and song select or the button control the playback.
+
+use defmt::{info, warn};
+use midi_port::MidiMessage;
+
+use crate::{
+    midi::HeldNotes,
+    smf::{Player, Smf},
+};
+
+include!(concat!(env!("OUT_DIR"), "/songs.rs"));
+
+enum State {
+    Stopped,
+    Playing {
+        player: Player<'static>,
+        // timer value at the start of the song
+        start_us: u64,
+    },
+    Paused {
+        player: Player<'static>,
+        elapsed_us: u64,
+    },
+}
+
+pub struct SongPlayer {
+    song: usize,
+    state: State,
+    // notes started by the player and not stopped yet
+    notes: HeldNotes,
+}
+
+impl SongPlayer {
+    pub const fn new() -> Self {
+        Self {
+            song: 0,
+            state: State::Stopped,
+            notes: HeldNotes::new(),
+        }
+    }
+
+    pub fn is_playing(&self) -> bool {
+        matches!(self.state, State::Playing { .. })
+    }
+
+    /// Plays the selected song from the beginning.
+    pub fn start(&mut self, now_us: u64) {
+        let data = match SONGS.get(self.song) {
+            Some(data) => data,
+            None => return warn!("no song {}", self.song),
+        };
+        match Smf::parse(data) {
+            Ok(smf) => {
+                info!("playing song {}", self.song);
+                self.state = State::Playing {
+                    player: Player::new(&smf),
+                    start_us: now_us,
+                };
+            }
+            Err(_) => {
+                warn!("song {} can't be played", self.song);
+                self.state = State::Stopped;
+            }
+        }
+    }
+
+    /// Continues a stopped song where it stopped.
+    pub fn resume(&mut self, now_us: u64) {
+        match core::mem::replace(&mut self.state, State::Stopped) {
+            State::Paused { player, elapsed_us } => {
+                self.state = State::Playing {
+                    player,
+                    start_us: now_us - elapsed_us,
+                }
+            }
+            State::Playing { player, start_us } => self.state = State::Playing { player, start_us },
+            State::Stopped => self.start(now_us),
+        }
+    }
+
+    /// Stops the song, `release` has to be called for the notes it played.
+    pub fn stop(&mut self, now_us: u64) {
+        if let State::Playing { player, start_us } =
+            core::mem::replace(&mut self.state, State::Stopped)
+        {
+            self.state = State::Paused {
+                player,
+                elapsed_us: now_us - start_us,
+            };
+        }
+    }
+
+    /// Selects a song, it starts with the next `start` or `resume`.
+    pub fn select(&mut self, song: usize) {
+        if song >= SONGS.len() {
+            return warn!("no song {}", song);
+        }
+        self.song = song;
+        self.state = State::Stopped;
+    }
+
+    pub fn next_song(&mut self, now_us: u64) {
+        self.select((self.song + 1) % SONGS.len().max(1));
+        self.start(now_us);
+    }
+
+    /// Returns the next message that is due, the next song starts once one ends.
+    pub fn next_due(&mut self, now_us: u64) -> Option<MidiMessage> {
+        let (player, start_us) = match &mut self.state {
+            State::Playing { player, start_us } => (player, *start_us),
+            _ => return None,
+        };
+        match player.next_due(now_us - start_us) {
+            Some(msg) => {
+                self.notes.track(&msg);
+                Some(msg)
+            }
+            None if player.is_finished() => {
+                self.next_song(now_us);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Returns the note offs for the notes still held by the player.
+    pub fn release(&mut self) -> impl Iterator<Item = MidiMessage> {
+        self.notes.release()
+    }
+}
+
+impl Default for SongPlayer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn embedded_songs_play_to_the_end() {
+        for song in SONGS {
+            let smf = Smf::parse(song).unwrap();
+            let mut player = Player::new(&smf);
+            while player.next_due(u64::MAX).is_some() {}
+            assert!(player.is_finished());
+        }
+    }
+}
@@ -0,0 +1,213 @@
+//! Presets recalled by program change, in the bank chosen with CC 0 and CC 32.
+//!
+//! Bank 0 holds the factory presets compiled into the firmware, its first programs select
+//! the oscillator modes like before presets existed. Bank 1 holds the user presets, they
+//! are written with SysEx (see `sysex`) and kept in flash (see `store`).
+//!
+//! A preset covers the sound: oscillator mode, channel maps, the zones of voices each channel
+//! plays on, transpose, tuning, the scale tuning table, vibrato and track windows.
+//! The device ID, the MIDI out mode and the voice policy describe how the unit is wired,
+//! recalling a preset keeps them. A recalled preset is only saved along with the next
+//! setting changed by SysEx.
+
+use heapless::Vec;
+
+use crate::{
+    control::OscMode,
+    floppy::DRIVE_COUNT,
+    settings::{Settings, Vibrato},
+    voices::ALL_VOICES,
+};
+
+pub use floppotron_protocol::{PRESET_COUNT, PRESET_NAME_SIZE};
+
+pub const FACTORY_BANK: u16 = 0;
+pub const USER_BANK: u16 = 1;
+
+const CC_BANK_MSB: u8 = 0;
+const CC_BANK_LSB: u8 = 32;
+
+pub type PresetName = Vec<u8, PRESET_NAME_SIZE>;
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Preset {
+    pub name: PresetName,
+    pub settings: Settings,
+}
+
+impl Preset {
+    /// Changes `settings` to the ones of this preset.
+    pub fn recall(&self, settings: &mut Settings) {
+        *settings = Settings {
+            device_id: settings.device_id,
+            midi_out: settings.midi_out,
+            voice_policy: settings.voice_policy,
+            ..self.settings.clone()
+        };
+    }
+}
+
+/// The user bank.
+pub type Presets = [Option<Preset>; PRESET_COUNT];
+
+pub fn factory_preset(program: u8) -> Option<Preset> {
+    let mut settings = Settings::new();
+    let name: &[u8] = match program {
+        0 => b"Single",
+        1 => {
+            settings.osc_mode = OscMode::Inverse;
+            b"Inverse"
+        }
+        2 => {
+            settings.osc_mode = OscMode::Unisono;
+            b"Unisono"
+        }
+        3 => {
+            settings.osc_mode = OscMode::Unisono;
+            settings.transpose = -12;
+            b"Unisono bass"
+        }
+        4 => {
+            settings.transpose = 12;
+            b"Single high"
+        }
+        5 => {
+            settings.vibrato = Vibrato {
+                rate: 55,
+                depth: 15,
+            };
+            b"Vibrato"
+        }
+        // channel 1 plays bass on the first two drives, which move their heads less,
+        // the other channels play on the rest, boards with fewer drives don't have it
+        6 if DRIVE_COUNT > 2 => {
+            settings.zones = [(2, ALL_VOICES.1); 16];
+            settings.zones[0] = (0, 1);
+            settings
+                .track_ranges
+                .iter_mut()
+                .take(2)
+                .for_each(|range| *range = (0, 40));
+            b"Bass split"
+        }
+        _ => return None,
+    };
+    Some(Preset {
+        name: PresetName::from_slice(name).unwrap(),
+        settings,
+    })
+}
+
+/// Returns preset `program` of `bank`, `None` for free presets and unknown banks.
+pub fn find_preset(bank: u16, program: u8, user: &Presets) -> Option<Preset> {
+    match bank {
+        FACTORY_BANK => factory_preset(program),
+        USER_BANK => user.get(program as usize).cloned().flatten(),
+        _ => None,
+    }
+}
+
+/// Follows the bank select controllers.
+pub struct BankSelect {
+    msb: u8,
+    lsb: u8,
+}
+
+impl BankSelect {
+    pub const fn new() -> Self {
+        Self { msb: 0, lsb: 0 }
+    }
+
+    /// Takes a control change, returns whether it was a bank select.
+    pub fn control_change(&mut self, controller: u8, value: u8) -> bool {
+        match controller {
+            CC_BANK_MSB => self.msb = value & 0x7f,
+            CC_BANK_LSB => self.lsb = value & 0x7f,
+            _ => return false,
+        }
+        true
+    }
+
+    pub fn bank(&self) -> u16 {
+        (self.msb as u16) << 7 | self.lsb as u16
+    }
+}
+
+impl Default for BankSelect {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{midi_out::MidiOutMode, sysex::Param, voices::VoicePolicy};
+
+    use super::*;
+
+    #[test]
+    fn bank_select() {
+        let mut banks = BankSelect::new();
+        assert!(!banks.control_change(7, 100));
+        assert!(banks.control_change(32, 1));
+        assert_eq!(banks.bank(), USER_BANK);
+        assert!(banks.control_change(0, 2));
+        assert_eq!(banks.bank(), 2 << 7 | 1);
+    }
+
+    #[test]
+    fn factory_presets_select_modes() {
+        for (program, mode) in [OscMode::Single, OscMode::Inverse, OscMode::Unisono]
+            .into_iter()
+            .enumerate()
+        {
+            let preset = factory_preset(program as u8).unwrap();
+            assert!(preset.settings.osc_mode == mode);
+        }
+        assert!(factory_preset(PRESET_COUNT as u8).is_none());
+    }
+
+    #[test]
+    fn every_factory_preset_recalls() {
+        for program in 0..PRESET_COUNT as u8 {
+            let mut current = Settings::new();
+            if let Some(preset) = factory_preset(program) {
+                preset.recall(&mut current);
+                assert_eq!(Settings::from_bytes(&current.to_bytes()), current);
+            }
+        }
+    }
+
+    #[test]
+    fn recall_keeps_the_wiring() {
+        let mut user: Presets = Default::default();
+        let mut settings = Settings::new();
+        settings.transpose = 5;
+        settings.zones[3] = (1, 2);
+        settings.scale_tuning[7] = -2;
+        settings.vibrato.depth = 10;
+        settings.vibrato.rate = 40;
+        user[2] = Some(Preset {
+            name: PresetName::from_slice(b"lead").unwrap(),
+            settings,
+        });
+
+        let mut current = Settings::new();
+        current.device_id = 3;
+        current.midi_out = MidiOutMode::Chain;
+        current.voice_policy = VoicePolicy::Overflow;
+        find_preset(USER_BANK, 2, &user)
+            .unwrap()
+            .recall(&mut current);
+        assert_eq!(current.transpose, 5);
+        assert_eq!(current.zones[3], (1, 2));
+        assert_eq!(current.scale_tuning[7], -2);
+        assert_eq!(current.get(Param::Vibrato, 0).unwrap(), [40, 10]);
+        assert_eq!(current.device_id, 3);
+        assert!(current.midi_out == MidiOutMode::Chain);
+        assert!(current.voice_policy == VoicePolicy::Overflow);
+
+        assert!(find_preset(USER_BANK, 3, &user).is_none());
+        assert!(find_preset(2, 0, &user).is_none());
+    }
+}
@@ -0,0 +1,73 @@
+use crate::hal::pac;
+use defmt::Format;
+
+use crate::board::ENABLE_PIN_MASK;
+
+// written to a watchdog scratch register right before a panic resets the board,
+// scratch4..7 are used by the bootrom, so stay clear of those
+const PANIC_MAGIC: u32 = 0xf10b_dead;
+
+#[derive(Clone, Copy, PartialEq, Format)]
+pub enum ResetCause {
+    PowerOn,
+    WatchdogTimeout,
+    Panic,
+    Forced,
+}
+
+/// Drives all enable pins low and stops every PWM slice and PIO state machine.
+///
+/// This bypasses the `Floppy` implementations on purpose: it is called from the
+/// panic handler, where `OSCILLATORS` may be borrowed by the interrupted code.
+pub fn enter_safe_state() {
+    unsafe {
+        let pwm = &*pac::PWM::ptr();
+        pwm.en.write(|w| w.bits(0));
+        pwm.inte.write(|w| w.bits(0));
+        // the state machines stepping the drives of the `pio-step` backend
+        for pio in [&*pac::PIO0::ptr(), &*pac::PIO1::ptr()] {
+            pio.ctrl.write(|w| w.sm_enable().bits(0));
+        }
+
+        let sio = &*pac::SIO::ptr();
+        sio.gpio_out_clr.write(|w| w.bits(ENABLE_PIN_MASK));
+    }
+}
+
+pub fn record_panic() {
+    unsafe {
+        let watchdog = &*pac::WATCHDOG::ptr();
+        watchdog.scratch0.write(|w| w.bits(PANIC_MAGIC));
+    }
+}
+
+/// Resets the chip through the watchdog, so the reset cause survives into the next boot.
+pub fn force_reset() -> ! {
+    unsafe {
+        let watchdog = &*pac::WATCHDOG::ptr();
+        watchdog.ctrl.modify(|_, w| w.trigger().set_bit());
+    }
+    loop {
+        cortex_m::asm::nop();
+    }
+}
+
+/// Reads the reason of the last reset and clears the panic record.
+///
+/// Must be called before the watchdog is started again.
+pub fn take_reset_cause(watchdog: &pac::WATCHDOG) -> ResetCause {
+    let reason = watchdog.reason.read();
+    let panicked = watchdog.scratch0.read().bits() == PANIC_MAGIC;
+    watchdog.scratch0.write(|w| unsafe { w.bits(0) });
+
+    if reason.force().bit_is_set() {
+        match panicked {
+            true => ResetCause::Panic,
+            false => ResetCause::Forced,
+        }
+    } else if reason.timer().bit_is_set() {
+        ResetCause::WatchdogTimeout
+    } else {
+        ResetCause::PowerOn
+    }
+}
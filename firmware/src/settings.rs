@@ -0,0 +1,366 @@
+//! Settings that can be changed at runtime, see `sysex` for the protocol.
+//!
+//! Only the values live here, `MidiHandler` passes changes on to the parts they affect.
+//! `store` keeps them in flash together with the presets, see `presets`.
+
+use heapless::Vec;
+
+use crate::{
+    control::OscMode,
+    floppy::{DRIVE_COUNT, LAST_TRACK},
+    merge::SOURCE_COUNT,
+    midi_out::MidiOutMode,
+    sysex::{Param, SysExError},
+    voices::{VoicePolicy, Zone, ALL_VOICES, MAX_VOICES},
+};
+
+/// Upper bound of the size of `Settings::to_bytes`, no index has more than two values.
+pub const SETTINGS_SIZE: usize = {
+    let mut size = 0;
+    let mut i = 0;
+    while i < Param::ALL.len() {
+        size += 2 + 2 * index_count(Param::ALL[i]);
+        i += 1;
+    }
+    size
+};
+
+/// How many indices `param` has, see `Param`.
+pub const fn index_count(param: Param) -> usize {
+    match param {
+        Param::ChannelMap => SOURCE_COUNT * 16,
+        Param::TrackRange => DRIVE_COUNT,
+        Param::Zone => 16,
+        Param::ScaleTuning => 12,
+        _ => 1,
+    }
+}
+
+// the LFO is updated this often, often enough for the fastest vibrato
+const VIBRATO_STEP_US: u64 = 10_000;
+
+/// A triangle LFO bending all notes.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Vibrato {
+    /// in 0.1Hz
+    pub rate: u8,
+    /// cents above and below the note
+    pub depth: u8,
+}
+
+impl Vibrato {
+    pub fn is_on(&self) -> bool {
+        self.rate > 0 && self.depth > 0
+    }
+
+    /// The cents the notes are bent by at `now_us`, the value only changes every
+    /// `VIBRATO_STEP_US`.
+    pub fn offset(&self, now_us: u64) -> i16 {
+        if !self.is_on() {
+            return 0;
+        }
+        let period_us = 10_000_000 / self.rate as u64;
+        let depth = self.depth as i64;
+        let phase = now_us / VIBRATO_STEP_US * VIBRATO_STEP_US % period_us;
+        // 0 to 4 * depth over a period, up, down and up again
+        let x = (phase * 4 * depth as u64 / period_us) as i64;
+        let offset = match x {
+            x if x < depth => x,
+            x if x < 3 * depth => 2 * depth - x,
+            x => x - 4 * depth,
+        };
+        offset as i16
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Settings {
+    pub device_id: u8,
+    pub osc_mode: OscMode,
+    pub channel_maps: [[u8; 16]; SOURCE_COUNT],
+    /// semitones added to every note
+    pub transpose: i8,
+    /// first and last track of every drive
+    pub track_ranges: [(u8, u8); DRIVE_COUNT],
+    pub midi_out: MidiOutMode,
+    /// the voices every channel plays on, after the channel maps
+    pub zones: [Zone; 16],
+    pub voice_policy: VoicePolicy,
+    /// cents added to every note
+    pub tuning: i8,
+    /// cents added to the notes of each pitch class, starting at C
+    pub scale_tuning: [i8; 12],
+    pub vibrato: Vibrato,
+}
+
+impl Settings {
+    pub fn new() -> Self {
+        Self {
+            device_id: 0,
+            osc_mode: OscMode::Single,
+            channel_maps: [core::array::from_fn(|channel| channel as u8); SOURCE_COUNT],
+            transpose: 0,
+            track_ranges: [(0, LAST_TRACK); DRIVE_COUNT],
+            midi_out: MidiOutMode::Thru,
+            zones: [ALL_VOICES; 16],
+            voice_policy: VoicePolicy::Steal,
+            tuning: 0,
+            scale_tuning: [0; 12],
+            vibrato: Vibrato { rate: 0, depth: 0 },
+        }
+    }
+
+    pub fn get(&self, param: Param, index: u8) -> Result<Vec<u8, 2>, SysExError> {
+        let index = index as usize;
+        let values: &[u8] = match param {
+            Param::OscMode => &[self.osc_mode.to_bits() as u8],
+            Param::ChannelMap => {
+                let map = self
+                    .channel_maps
+                    .get(index / 16)
+                    .ok_or(SysExError::BadIndex)?;
+                &[map[index % 16]]
+            }
+            Param::Transpose => &[(self.transpose + 64) as u8],
+            Param::TrackRange => {
+                let (first, last) = self.track_ranges.get(index).ok_or(SysExError::BadIndex)?;
+                &[*first, *last]
+            }
+            Param::MidiOut => &[self.midi_out.to_bits()],
+            Param::DeviceId => &[self.device_id],
+            Param::Zone => {
+                let (first, last) = self.zones.get(index).ok_or(SysExError::BadIndex)?;
+                &[*first, *last]
+            }
+            Param::VoicePolicy => &[self.voice_policy.to_bits()],
+            Param::Tuning => &[(self.tuning + 64) as u8],
+            Param::ScaleTuning => {
+                let cents = self.scale_tuning.get(index).ok_or(SysExError::BadIndex)?;
+                &[(cents + 64) as u8]
+            }
+            Param::Vibrato => &[self.vibrato.rate, self.vibrato.depth],
+        };
+        Ok(Vec::from_slice(values).unwrap())
+    }
+
+    /// Validates and stores a value, nothing changes on errors.
+    pub fn set(&mut self, param: Param, index: u8, values: &[u8]) -> Result<(), SysExError> {
+        let index = index as usize;
+        match (param, values) {
+            (Param::ChannelMap, _) if index >= SOURCE_COUNT * 16 => Err(SysExError::BadIndex),
+            (Param::TrackRange, _) if index >= DRIVE_COUNT => Err(SysExError::BadIndex),
+            (Param::Zone, _) if index >= 16 => Err(SysExError::BadIndex),
+            (Param::ScaleTuning, _) if index >= 12 => Err(SysExError::BadIndex),
+            (Param::ChannelMap | Param::TrackRange | Param::Zone | Param::ScaleTuning, _) => {
+                self.set_indexed(param, index, values)
+            }
+            (_, _) if index != 0 => Err(SysExError::BadIndex),
+            (Param::OscMode, &[bits]) => {
+                self.osc_mode = OscMode::from_bits(bits as u32).ok_or(SysExError::BadValue)?;
+                Ok(())
+            }
+            (Param::Transpose, &[value]) if value < 0x80 => {
+                self.transpose = value as i8 - 64;
+                Ok(())
+            }
+            (Param::MidiOut, &[bits]) => {
+                self.midi_out = MidiOutMode::from_bits(bits).ok_or(SysExError::BadValue)?;
+                Ok(())
+            }
+            (Param::VoicePolicy, &[bits]) => {
+                self.voice_policy = VoicePolicy::from_bits(bits).ok_or(SysExError::BadValue)?;
+                Ok(())
+            }
+            (Param::Tuning, &[value]) if value < 0x80 => {
+                self.tuning = value as i8 - 64;
+                Ok(())
+            }
+            (Param::Vibrato, &[rate, depth]) if rate < 0x80 && depth < 0x80 => {
+                self.vibrato = Vibrato { rate, depth };
+                Ok(())
+            }
+            (Param::Vibrato, &[_, _]) => Err(SysExError::BadValue),
+            (Param::DeviceId, &[id]) if id < 0x7f => {
+                self.device_id = id;
+                Ok(())
+            }
+            (_, &[_]) => Err(SysExError::BadValue),
+            (_, _) => Err(SysExError::Malformed),
+        }
+    }
+
+    /// Encodes all values as `<param> <length> <values of every index>` for each parameter.
+    pub fn to_bytes(&self) -> Vec<u8, SETTINGS_SIZE> {
+        let mut bytes = Vec::new();
+        for param in Param::ALL {
+            let start = bytes.len();
+            bytes.extend_from_slice(&[param.to_byte(), 0]).unwrap();
+            for index in 0..index_count(param) {
+                let values = self.get(param, index as u8).unwrap();
+                bytes.extend_from_slice(&values).unwrap();
+            }
+            bytes[start + 1] = (bytes.len() - start - 2) as u8;
+        }
+        bytes
+    }
+
+    /// Decodes `to_bytes`, unknown parameters and invalid values keep their defaults.
+    ///
+    /// Missing indices keep their defaults as well, so settings saved with another drive
+    /// count or by an older firmware still load.
+    pub fn from_bytes(mut bytes: &[u8]) -> Self {
+        let mut settings = Self::new();
+        while let [param, len, rest @ ..] = bytes {
+            let len = (*len as usize).min(rest.len());
+            let (values, next) = rest.split_at(len);
+            bytes = next;
+
+            let param = match Param::from_byte(*param) {
+                Some(param) => param,
+                None => continue,
+            };
+            let width = settings.get(param, 0).unwrap().len();
+            for (index, value) in values.chunks_exact(width).enumerate() {
+                if index < index_count(param) {
+                    settings.set(param, index as u8, value).ok();
+                }
+            }
+        }
+        settings
+    }
+
+    fn set_indexed(&mut self, param: Param, index: usize, values: &[u8]) -> Result<(), SysExError> {
+        match (param, values) {
+            (Param::ChannelMap, &[channel]) if channel < 16 => {
+                self.channel_maps[index / 16][index % 16] = channel;
+                Ok(())
+            }
+            (Param::TrackRange, &[first, last]) if first < last && last <= LAST_TRACK => {
+                self.track_ranges[index] = (first, last);
+                Ok(())
+            }
+            (Param::Zone, &[first, last]) if first <= last && (last as usize) < MAX_VOICES => {
+                self.zones[index] = (first, last);
+                Ok(())
+            }
+            (Param::ScaleTuning, &[value]) if value < 0x80 => {
+                self.scale_tuning[index] = value as i8 - 64;
+                Ok(())
+            }
+            (Param::ChannelMap | Param::ScaleTuning, &[_])
+            | (Param::TrackRange | Param::Zone, &[_, _]) => Err(SysExError::BadValue),
+            (_, _) => Err(SysExError::Malformed),
+        }
+    }
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_and_get() {
+        let mut settings = Settings::new();
+        settings.set(Param::TrackRange, 1, &[10, 40]).unwrap();
+        assert_eq!(settings.get(Param::TrackRange, 1).unwrap(), [10, 40]);
+        settings.set(Param::Transpose, 0, &[52]).unwrap();
+        assert_eq!(settings.transpose, -12);
+        assert_eq!(settings.get(Param::Transpose, 0).unwrap(), [52]);
+        settings.set(Param::ChannelMap, 16 + 3, &[9]).unwrap();
+        assert_eq!(settings.channel_maps[1][3], 9);
+        settings.set(Param::MidiOut, 0, &[2]).unwrap();
+        assert!(settings.midi_out == MidiOutMode::Chain);
+        settings.set(Param::VoicePolicy, 0, &[1]).unwrap();
+        assert!(settings.voice_policy == VoicePolicy::Refuse);
+        settings.set(Param::Tuning, 0, &[54]).unwrap();
+        assert_eq!(settings.tuning, -10);
+        settings.set(Param::ScaleTuning, 4, &[50]).unwrap();
+        assert_eq!(settings.scale_tuning[4], -14);
+        settings.set(Param::Vibrato, 0, &[55, 20]).unwrap();
+        assert_eq!(settings.get(Param::Vibrato, 0).unwrap(), [55, 20]);
+        settings.set(Param::Zone, 9, &[3, 3]).unwrap();
+        assert_eq!(settings.get(Param::Zone, 9).unwrap(), [3, 3]);
+    }
+
+    #[test]
+    fn invalid_values_are_rejected() {
+        let mut settings = Settings::new();
+        assert_eq!(
+            settings.set(Param::TrackRange, 0, &[40, 10]),
+            Err(SysExError::BadValue)
+        );
+        assert_eq!(
+            settings.set(Param::TrackRange, DRIVE_COUNT as u8, &[10, 40]),
+            Err(SysExError::BadIndex)
+        );
+        assert_eq!(
+            settings.set(Param::TrackRange, 0, &[10]),
+            Err(SysExError::Malformed)
+        );
+        assert_eq!(
+            settings.set(Param::OscMode, 0, &[7]),
+            Err(SysExError::BadValue)
+        );
+        assert_eq!(
+            settings.set(Param::DeviceId, 0, &[0x7f]),
+            Err(SysExError::BadValue)
+        );
+        assert_eq!(
+            settings.set(Param::Transpose, 1, &[64]),
+            Err(SysExError::BadIndex)
+        );
+        assert_eq!(
+            settings.set(Param::Zone, 0, &[0, MAX_VOICES as u8]),
+            Err(SysExError::BadValue)
+        );
+        assert_eq!(settings.track_ranges[0], (0, LAST_TRACK));
+    }
+
+    #[test]
+    fn bytes_round_trip() {
+        let mut settings = Settings::new();
+        settings.set(Param::OscMode, 0, &[2]).unwrap();
+        settings.set(Param::TrackRange, 1, &[10, 40]).unwrap();
+        settings.set(Param::ChannelMap, 5, &[9]).unwrap();
+        settings.set(Param::DeviceId, 0, &[3]).unwrap();
+        settings.set(Param::Zone, 15, &[2, 4]).unwrap();
+        settings.set(Param::ScaleTuning, 11, &[80]).unwrap();
+        settings.set(Param::Vibrato, 0, &[60, 30]).unwrap();
+        assert!(settings.to_bytes().len() <= SETTINGS_SIZE);
+        assert_eq!(Settings::from_bytes(&settings.to_bytes()), settings);
+    }
+
+    #[test]
+    fn unknown_and_invalid_bytes_are_skipped() {
+        let bytes = [
+            0x40, 2, 1, 2, // unknown parameter
+            0x02, 1, 52, // transpose -12
+            0x00, 1, 9, // invalid mode
+            0x03, 2, 10, // truncated track range
+        ];
+        let settings = Settings::from_bytes(&bytes);
+        assert_eq!(settings.transpose, -12);
+        assert!(settings.osc_mode == OscMode::Single);
+        assert_eq!(settings.track_ranges[0], (0, LAST_TRACK));
+    }
+
+    #[test]
+    fn vibrato_triangle() {
+        // 5Hz, a period of 200ms
+        let vibrato = Vibrato {
+            rate: 50,
+            depth: 20,
+        };
+        let offsets: [_; 5] = core::array::from_fn(|i| vibrato.offset(i as u64 * 50_000));
+        assert_eq!(offsets, [0, 20, 0, -20, 0]);
+        // steps of 10ms
+        assert_eq!(vibrato.offset(29_999), 8);
+        assert_eq!(Vibrato { rate: 0, depth: 20 }.offset(50_000), 0);
+    }
+}
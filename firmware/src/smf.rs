@@ -0,0 +1,353 @@
+//! Reads Standard MIDI Files of format 0 and 1 straight from flash.
+//!
+//! `Player` merges the tracks of a file and returns its channel messages once they are
+//! due. SysEx and meta events are skipped, except for tempo changes.
+
+use heapless::Vec;
+use midi_port::MidiMessage;
+
+use crate::midi::{create_message, expected_data_len};
+
+pub const MAX_TRACKS: usize = 16;
+
+// microseconds per quarter note until the first tempo event
+const DEFAULT_TEMPO: u32 = 500_000;
+const META_END_OF_TRACK: u8 = 0x2f;
+const META_TEMPO: u8 = 0x51;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SmfError {
+    /// there is no `MThd` header
+    NotSmf,
+    /// format 2 or unknown
+    UnsupportedFormat,
+    /// the division is in SMPTE frames instead of ticks per quarter note
+    SmpteDivision,
+    TooManyTracks,
+    /// a chunk is cut short
+    Truncated,
+}
+
+pub struct Smf<'a> {
+    pub format: u16,
+    /// ticks per quarter note
+    pub division: u16,
+    pub tracks: Vec<&'a [u8], MAX_TRACKS>,
+}
+
+fn read_u16(data: &[u8], at: usize) -> u16 {
+    u16::from_be_bytes([data[at], data[at + 1]])
+}
+
+fn read_u32(data: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]])
+}
+
+// splits the next chunk off `data`
+fn split_chunk<'a>(data: &mut &'a [u8]) -> Result<(&'a [u8], &'a [u8]), SmfError> {
+    if data.len() < 8 {
+        return Err(SmfError::Truncated);
+    }
+    let len = read_u32(data, 4) as usize;
+    let body = data.get(8..8 + len).ok_or(SmfError::Truncated)?;
+    let id = &data[..4];
+    *data = &data[8 + len..];
+    Ok((id, body))
+}
+
+impl<'a> Smf<'a> {
+    pub fn parse(mut data: &'a [u8]) -> Result<Self, SmfError> {
+        let (id, header) = split_chunk(&mut data).map_err(|_| SmfError::NotSmf)?;
+        if id != b"MThd" || header.len() < 6 {
+            return Err(SmfError::NotSmf);
+        }
+        let format = read_u16(header, 0);
+        let track_count = read_u16(header, 2) as usize;
+        let division = read_u16(header, 4);
+        if format > 1 {
+            return Err(SmfError::UnsupportedFormat);
+        }
+        if division & 0x8000 != 0 {
+            return Err(SmfError::SmpteDivision);
+        }
+
+        let mut tracks = Vec::new();
+        while tracks.len() < track_count {
+            let (id, body) = split_chunk(&mut data)?;
+            // unknown chunks have to be skipped
+            if id == b"MTrk" {
+                tracks.push(body).map_err(|_| SmfError::TooManyTracks)?;
+            }
+        }
+        Ok(Self {
+            format,
+            division,
+            tracks,
+        })
+    }
+}
+
+enum TrackEvent {
+    Message(MidiMessage),
+    Tempo(u32),
+    Other,
+    End,
+}
+
+struct Track<'a> {
+    data: &'a [u8],
+    running_status: Option<u8>,
+    // absolute tick of the next event, `None` once the track ended
+    next_tick: Option<u32>,
+}
+
+impl<'a> Track<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        let mut track = Self {
+            data,
+            running_status: None,
+            next_tick: None,
+        };
+        track.next_tick = track.read_vlq();
+        track
+    }
+
+    fn byte(&mut self) -> Option<u8> {
+        let (&byte, rest) = self.data.split_first()?;
+        self.data = rest;
+        Some(byte)
+    }
+
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.data.get(..len)?;
+        self.data = &self.data[len..];
+        Some(bytes)
+    }
+
+    // variable length quantity, at most four bytes
+    fn read_vlq(&mut self) -> Option<u32> {
+        let mut value = 0u32;
+        for _ in 0..4 {
+            let byte = self.byte()?;
+            value = value << 7 | (byte & 0x7f) as u32;
+            if byte & 0x80 == 0 {
+                return Some(value);
+            }
+        }
+        None
+    }
+
+    // a broken event ends the track
+    fn read_event(&mut self) -> TrackEvent {
+        self.read_event_inner().unwrap_or(TrackEvent::End)
+    }
+
+    fn read_event_inner(&mut self) -> Option<TrackEvent> {
+        let first = self.byte()?;
+        // SysEx and meta events cancel running status
+        if matches!(first, 0xf0 | 0xf7 | 0xff) {
+            self.running_status = None;
+        }
+        match first {
+            0xff => {
+                let kind = self.byte()?;
+                let len = self.read_vlq()? as usize;
+                let data = self.bytes(len)?;
+                Some(match (kind, data) {
+                    (META_END_OF_TRACK, _) => TrackEvent::End,
+                    (META_TEMPO, &[a, b, c]) => {
+                        TrackEvent::Tempo((a as u32) << 16 | (b as u32) << 8 | c as u32)
+                    }
+                    _ => TrackEvent::Other,
+                })
+            }
+            0xf0 | 0xf7 => {
+                let len = self.read_vlq()? as usize;
+                self.bytes(len)?;
+                Some(TrackEvent::Other)
+            }
+            _ => {
+                let (status, mut data) = match first {
+                    0x80..=0xef => (first, [0; 2]),
+                    _ => (self.running_status?, [first, 0]),
+                };
+                self.running_status = Some(status);
+                let start = (first < 0x80) as usize;
+                for byte in &mut data[start..expected_data_len(status)] {
+                    *byte = self.byte()?;
+                }
+                Some(TrackEvent::Message(create_message(status, data)))
+            }
+        }
+    }
+}
+
+/// Plays the tracks of a file in parallel.
+pub struct Player<'a> {
+    tracks: Vec<Track<'a>, MAX_TRACKS>,
+    division: u64,
+    tempo: u64,
+    // tick and time of the last event
+    tick: u32,
+    time_us: u64,
+}
+
+impl<'a> Player<'a> {
+    pub fn new(smf: &Smf<'a>) -> Self {
+        Self {
+            tracks: smf.tracks.iter().map(|&data| Track::new(data)).collect(),
+            division: smf.division.max(1) as u64,
+            tempo: DEFAULT_TEMPO as u64,
+            tick: 0,
+            time_us: 0,
+        }
+    }
+
+    /// Whether all tracks ended.
+    pub fn is_finished(&self) -> bool {
+        self.tracks.iter().all(|track| track.next_tick.is_none())
+    }
+
+    /// Time of the last message `next_due` returned, after the start.
+    pub fn time_us(&self) -> u64 {
+        self.time_us
+    }
+
+    /// Returns the next message that is due `elapsed_us` after the start.
+    pub fn next_due(&mut self, elapsed_us: u64) -> Option<MidiMessage> {
+        loop {
+            let (index, tick) = self
+                .tracks
+                .iter()
+                .enumerate()
+                .filter_map(|(index, track)| Some((index, track.next_tick?)))
+                .min_by_key(|&(_, tick)| tick)?;
+            let time_us = self.time_us + (tick - self.tick) as u64 * self.tempo / self.division;
+            if time_us > elapsed_us {
+                return None;
+            }
+            self.tick = tick;
+            self.time_us = time_us;
+
+            let track = &mut self.tracks[index];
+            let event = track.read_event();
+            track.next_tick = match event {
+                TrackEvent::End => None,
+                _ => track.read_vlq().and_then(|delta| tick.checked_add(delta)),
+            };
+            match event {
+                TrackEvent::Message(msg) => return Some(msg),
+                TrackEvent::Tempo(tempo) => self.tempo = tempo as u64,
+                TrackEvent::Other | TrackEvent::End => (),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(id: &[u8], body: &[u8]) -> std::vec::Vec<u8> {
+        let mut chunk = id.to_vec();
+        chunk.extend_from_slice(&(body.len() as u32).to_be_bytes());
+        chunk.extend_from_slice(body);
+        chunk
+    }
+
+    fn file(format: u16, division: u16, tracks: &[&[u8]]) -> std::vec::Vec<u8> {
+        let mut header = format.to_be_bytes().to_vec();
+        header.extend_from_slice(&(tracks.len() as u16).to_be_bytes());
+        header.extend_from_slice(&division.to_be_bytes());
+        let mut data = chunk(b"MThd", &header);
+        for track in tracks {
+            data.extend(chunk(b"MTrk", track));
+        }
+        data
+    }
+
+    fn note(msg: Option<MidiMessage>) -> Option<(u8, u8)> {
+        match msg? {
+            MidiMessage::NoteOn { note, velocity, .. } => Some((note, velocity)),
+            MidiMessage::NoteOff { note, .. } => Some((note, 0)),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn bad_headers() {
+        assert!(matches!(Smf::parse(b"RIFF"), Err(SmfError::NotSmf)));
+        assert!(matches!(
+            Smf::parse(&file(2, 96, &[])),
+            Err(SmfError::UnsupportedFormat)
+        ));
+        assert!(matches!(
+            Smf::parse(&file(0, 0xe728, &[])),
+            Err(SmfError::SmpteDivision)
+        ));
+        let mut truncated = file(0, 96, &[&[0, 0x90, 60, 100]]);
+        truncated.pop();
+        assert!(matches!(Smf::parse(&truncated), Err(SmfError::Truncated)));
+    }
+
+    #[test]
+    fn running_status_and_timing() {
+        // 96 ticks per quarter at 500ms per quarter
+        let track = [
+            0, 0x90, 60, 100, // note on at 0
+            96, 62, 100, // running status, half a second later
+            0, 0xff, 0x51, 3, 0x03, 0xd0, 0x90, // 250ms per quarter from here
+            96, 0x80, 60, 0, // a quarter of a second later
+            0, 0xff, 0x2f, 0,
+        ];
+        let data = file(0, 96, &[&track]);
+        let smf = Smf::parse(&data).unwrap();
+        let mut player = Player::new(&smf);
+
+        assert_eq!(note(player.next_due(0)), Some((60, 100)));
+        assert!(player.next_due(499_999).is_none());
+        assert_eq!(note(player.next_due(500_000)), Some((62, 100)));
+        assert!(player.next_due(749_999).is_none());
+        assert_eq!(note(player.next_due(750_000)), Some((60, 0)));
+        assert_eq!(player.time_us(), 750_000);
+        assert!(!player.is_finished());
+        assert!(player.next_due(750_000).is_none());
+        assert!(player.is_finished());
+    }
+
+    #[test]
+    fn meta_and_sysex_cancel_running_status() {
+        for event in [&[0xff, 0x01, 1, b'x'][..], &[0xf0, 1, 0xf7]] {
+            let mut track = vec![0, 0x90, 60, 100, 0];
+            track.extend_from_slice(event);
+            // a data byte without status is broken, it ends the track
+            track.extend_from_slice(&[0, 62, 100, 0, 0x90, 64, 100]);
+            let data = file(0, 96, &[&track]);
+            let smf = Smf::parse(&data).unwrap();
+            let mut player = Player::new(&smf);
+
+            assert_eq!(note(player.next_due(0)), Some((60, 100)));
+            assert!(player.next_due(0).is_none());
+            assert!(player.is_finished());
+        }
+    }
+
+    #[test]
+    fn tracks_are_merged() {
+        let first = [0, 0x90, 60, 100, 20, 0x90, 64, 100, 0, 0xff, 0x2f, 0];
+        // a SysEx event and an unknown meta event are skipped
+        let second = [
+            0, 0xf0, 2, 0x7d, 0xf7, 10, 0xff, 0x01, 2, b'h', b'i', 0, 0x91, 62, 100, 0, 0xff, 0x2f,
+            0,
+        ];
+        let data = file(1, 96, &[&first, &second]);
+        let smf = Smf::parse(&data).unwrap();
+        let mut player = Player::new(&smf);
+
+        let notes: std::vec::Vec<_> = core::iter::from_fn(|| note(player.next_due(u64::MAX)))
+            .map(|(note, _)| note)
+            .collect();
+        assert_eq!(notes, [60, 62, 64]);
+        assert!(player.is_finished());
+    }
+}
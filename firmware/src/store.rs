@@ -0,0 +1,369 @@
+//! Keeps the settings and the user presets in flash.
+//!
+//! The last 64K of the flash are left out of the firmware image in `memory.x` and used as
+//! a ring of sectors. Every save writes a complete record to the sector after the newest
+//! one, so each sector is erased only once per `SLOT_COUNT` saves and an interrupted save
+//! leaves the previous record intact. Loading picks the valid record with the highest
+//! sequence number.
+//!
+//! A record is a header followed by the body:
+//!
+//! `<magic: u32> <sequence: u32> <body length: u16> <reserved: u16> <body crc32: u32>`
+//!
+//! The body holds the settings (see `Settings::to_bytes`) with their length in front, then
+//! `<preset> <name length> <name> <settings length> <settings>` for every stored preset.
+
+use core::ops::Range;
+
+use cortex_m::singleton;
+use defmt::{info, warn};
+
+use crate::{
+    control::OscSender,
+    hal::rom_data,
+    presets::{Preset, PresetName, Presets, PRESET_COUNT, PRESET_NAME_SIZE},
+    settings::{Settings, SETTINGS_SIZE},
+};
+
+const FLASH_BASE: usize = 0x1000_0000;
+const FLASH_SIZE: usize = 2048 * 1024;
+const SECTOR_SIZE: usize = 4096;
+const SLOT_COUNT: usize = 16;
+// keep in sync with the FLASH region in memory.x
+const STORE_OFFSET: usize = FLASH_SIZE - SLOT_COUNT * SECTOR_SIZE;
+// 4K sector erase command of the flash chip
+const SECTOR_ERASE: u8 = 0x20;
+
+const MAGIC: u32 = 0x464a_5331;
+const HEADER_SIZE: usize = 16;
+const MAX_BODY_SIZE: usize =
+    1 + SETTINGS_SIZE + PRESET_COUNT * (3 + PRESET_NAME_SIZE + SETTINGS_SIZE);
+/// The flash is programmed in 256 byte pages, records are padded to whole pages.
+const RECORD_SIZE: usize = SECTOR_SIZE;
+
+const _: () = assert!(HEADER_SIZE + MAX_BODY_SIZE <= RECORD_SIZE);
+const _: () = assert!(RECORD_SIZE <= SECTOR_SIZE);
+
+/// Everything that survives a reboot.
+pub struct Stored {
+    pub settings: Settings,
+    pub presets: Presets,
+}
+
+impl Stored {
+    pub fn new() -> Self {
+        Self {
+            settings: Settings::new(),
+            presets: Default::default(),
+        }
+    }
+}
+
+impl Default for Stored {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = crc >> 1 ^ 0xedb8_8320 & (crc & 1).wrapping_neg();
+        }
+    }
+    !crc
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+}
+
+/// Writes the body of a record to `out` and returns its length.
+fn encode_body(settings: &Settings, presets: &Presets, out: &mut [u8]) -> usize {
+    let mut len = 0;
+    let mut put = |bytes: &[u8]| {
+        out[len..len + bytes.len()].copy_from_slice(bytes);
+        len += bytes.len();
+    };
+
+    let bytes = settings.to_bytes();
+    put(&[bytes.len() as u8]);
+    put(&bytes);
+    for (index, preset) in presets.iter().enumerate() {
+        if let Some(preset) = preset {
+            let bytes = preset.settings.to_bytes();
+            put(&[index as u8, preset.name.len() as u8]);
+            put(&preset.name);
+            put(&[bytes.len() as u8]);
+            put(&bytes);
+        }
+    }
+    len
+}
+
+// splits a length prefixed field off `bytes`
+fn split_field<'a>(bytes: &mut &'a [u8]) -> Option<&'a [u8]> {
+    let (&len, rest) = bytes.split_first()?;
+    let field = rest.get(..len as usize)?;
+    *bytes = &rest[len as usize..];
+    Some(field)
+}
+
+/// Decodes a body written by `encode_body`, a truncated preset list ends at the last
+/// complete preset.
+fn decode_body(mut body: &[u8]) -> Stored {
+    let mut stored = Stored::new();
+    if let Some(settings) = split_field(&mut body) {
+        stored.settings = Settings::from_bytes(settings);
+    }
+    while let Some((&index, mut rest)) = body.split_first() {
+        let (name, settings) = match (split_field(&mut rest), split_field(&mut rest)) {
+            (Some(name), Some(settings)) => (name, settings),
+            _ => break,
+        };
+        body = rest;
+
+        if let (Some(slot), Ok(name)) = (
+            stored.presets.get_mut(index as usize),
+            PresetName::from_slice(name),
+        ) {
+            *slot = Some(Preset {
+                name,
+                settings: Settings::from_bytes(settings),
+            });
+        }
+    }
+    stored
+}
+
+/// Fills `record` with the header for `body_len` bytes of body and pads it.
+fn finish_record(record: &mut [u8; RECORD_SIZE], sequence: u32, body_len: usize) {
+    let body = HEADER_SIZE..HEADER_SIZE + body_len;
+    let crc = crc32(&record[body.clone()]);
+    record[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+    record[4..8].copy_from_slice(&sequence.to_le_bytes());
+    record[8..10].copy_from_slice(&(body_len as u16).to_le_bytes());
+    record[10..12].copy_from_slice(&[0xff, 0xff]);
+    record[12..16].copy_from_slice(&crc.to_le_bytes());
+    record[body.end..].fill(0xff);
+}
+
+/// Returns the sequence number and the body range of a valid record.
+fn check_record(record: &[u8]) -> Option<(u32, Range<usize>)> {
+    if record.len() < HEADER_SIZE || read_u32(record, 0) != MAGIC {
+        return None;
+    }
+    let body_len = u16::from_le_bytes([record[8], record[9]]) as usize;
+    let body = HEADER_SIZE..HEADER_SIZE + body_len;
+    let valid = body_len <= MAX_BODY_SIZE
+        && body.end <= record.len()
+        && crc32(&record[body.clone()]) == read_u32(record, 12);
+    valid.then_some((read_u32(record, 4), body))
+}
+
+/// Returns the index of the valid record with the highest sequence number.
+fn newest<'a>(records: impl Iterator<Item = &'a [u8]>) -> Option<(usize, u32)> {
+    records
+        .enumerate()
+        .filter_map(|(slot, record)| check_record(record).map(|(sequence, _)| (slot, sequence)))
+        .max_by_key(|&(_, sequence)| sequence)
+}
+
+fn slot_memory(slot: usize) -> &'static [u8] {
+    let address = FLASH_BASE + STORE_OFFSET + slot * SECTOR_SIZE;
+    // the store area is never part of the firmware image, so nothing else refers to it
+    unsafe { core::slice::from_raw_parts(address as *const u8, RECORD_SIZE) }
+}
+
+/// The ROM functions for writing the flash, looked up while the flash can still be read.
+struct RomFunctions {
+    connect_internal_flash: unsafe extern "C" fn(),
+    flash_exit_xip: unsafe extern "C" fn(),
+    flash_range_erase: unsafe extern "C" fn(u32, usize, u32, u8),
+    flash_range_program: unsafe extern "C" fn(u32, *const u8, usize),
+    flash_flush_cache: unsafe extern "C" fn(),
+}
+
+impl RomFunctions {
+    fn lookup() -> Self {
+        Self {
+            connect_internal_flash: rom_data::connect_internal_flash::ptr(),
+            flash_exit_xip: rom_data::flash_exit_xip::ptr(),
+            flash_range_erase: rom_data::flash_range_erase::ptr(),
+            flash_range_program: rom_data::flash_range_program::ptr(),
+            flash_flush_cache: rom_data::flash_flush_cache::ptr(),
+        }
+    }
+}
+
+/// Erases the sector at `offset` and programs `record` into it.
+///
+/// Runs from RAM, as the flash can't be read while it is written. `boot2` is a copy of the
+/// second stage bootloader in RAM, calling it restores the fast XIP mode it set up at boot.
+#[inline(never)]
+#[link_section = ".data.ram_func"]
+unsafe fn program_sector(rom: &RomFunctions, offset: u32, record: *const u8, boot2: *const u32) {
+    (rom.connect_internal_flash)();
+    (rom.flash_exit_xip)();
+    (rom.flash_range_erase)(offset, SECTOR_SIZE, SECTOR_SIZE as u32, SECTOR_ERASE);
+    (rom.flash_range_program)(offset, record, RECORD_SIZE);
+    (rom.flash_flush_cache)();
+    let boot2: unsafe extern "C" fn() = core::mem::transmute(boot2 as usize + 1);
+    boot2();
+}
+
+/// Writes records to the ring of flash sectors.
+pub struct FlashStore {
+    // slot of the newest valid record
+    newest: Option<usize>,
+    sequence: u32,
+    record: &'static mut [u8; RECORD_SIZE],
+    boot2: [u32; 64],
+}
+
+impl FlashStore {
+    /// Reads the newest record, the defaults are used if there is none.
+    ///
+    /// Can only be called once.
+    pub fn load() -> (Self, Stored) {
+        let newest = newest((0..SLOT_COUNT).map(slot_memory));
+        let stored = match newest {
+            Some((slot, sequence)) => {
+                info!("loading settings from slot {}, sequence {}", slot, sequence);
+                let record = slot_memory(slot);
+                let (_, body) = check_record(record).unwrap();
+                decode_body(&record[body])
+            }
+            None => {
+                info!("no stored settings, using the defaults");
+                Stored::new()
+            }
+        };
+
+        let mut boot2 = [0; 64];
+        for (i, word) in boot2.iter_mut().enumerate() {
+            *word = unsafe { core::ptr::read_volatile((FLASH_BASE as *const u32).add(i)) };
+        }
+        let store = Self {
+            newest: newest.map(|(slot, _)| slot),
+            sequence: newest.map_or(0, |(_, sequence)| sequence.wrapping_add(1)),
+            record: singleton!(: [u8; RECORD_SIZE] = [0; RECORD_SIZE]).unwrap(),
+            boot2,
+        };
+        (store, stored)
+    }
+
+    /// Writes a new record unless the newest one already holds the same values.
+    ///
+    /// Core 0 is paused and the interrupts of this core are disabled while the flash is
+    /// written, which usually takes about 50ms. Erasing a sector alone may take up to 400ms,
+    /// core 0 gives the watchdog enough time for that. MIDI input arriving meanwhile may
+    /// be lost.
+    pub fn save(&mut self, settings: &Settings, presets: &Presets, sender: &mut OscSender) {
+        let body_len = encode_body(settings, presets, &mut self.record[HEADER_SIZE..]);
+        let body = HEADER_SIZE..HEADER_SIZE + body_len;
+        if let Some(slot) = self.newest {
+            let current = slot_memory(slot);
+            if check_record(current).map(|(_, range)| range) == Some(body.clone())
+                && current[body.clone()] == self.record[body]
+            {
+                return;
+            }
+        }
+
+        let slot = self.newest.map_or(0, |slot| (slot + 1) % SLOT_COUNT);
+        finish_record(self.record, self.sequence, body_len);
+        info!(
+            "saving settings to slot {}, sequence {}",
+            slot, self.sequence
+        );
+
+        let rom = RomFunctions::lookup();
+        let offset = (STORE_OFFSET + slot * SECTOR_SIZE) as u32;
+        sender.pause();
+        cortex_m::interrupt::free(|_| unsafe {
+            program_sector(&rom, offset, self.record.as_ptr(), self.boot2.as_ptr())
+        });
+        sender.resume();
+
+        if check_record(slot_memory(slot)).is_none() {
+            warn!("settings in slot {} don't read back", slot);
+            return;
+        }
+        self.newest = Some(slot);
+        self.sequence = self.sequence.wrapping_add(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sysex::Param;
+
+    use super::*;
+
+    fn record(sequence: u32, stored: &Stored) -> [u8; RECORD_SIZE] {
+        let mut record = [0; RECORD_SIZE];
+        let len = encode_body(
+            &stored.settings,
+            &stored.presets,
+            &mut record[HEADER_SIZE..],
+        );
+        finish_record(&mut record, sequence, len);
+        record
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn record_round_trip() {
+        let mut stored = Stored::new();
+        stored.settings.set(Param::Transpose, 0, &[76]).unwrap();
+        let mut preset = Settings::new();
+        preset.set(Param::OscMode, 0, &[1]).unwrap();
+        stored.presets[3] = Some(Preset {
+            name: PresetName::from_slice(b"organ").unwrap(),
+            settings: preset,
+        });
+
+        let record = record(7, &stored);
+        let (sequence, body) = check_record(&record).unwrap();
+        assert_eq!(sequence, 7);
+        let loaded = decode_body(&record[body]);
+        assert_eq!(loaded.settings, stored.settings);
+        assert_eq!(loaded.presets, stored.presets);
+    }
+
+    #[test]
+    fn full_presets_fit() {
+        let preset = Preset {
+            name: PresetName::from_slice(&[b'x'; PRESET_NAME_SIZE]).unwrap(),
+            settings: Settings::new(),
+        };
+        let stored = Stored {
+            settings: Settings::new(),
+            presets: core::array::from_fn(|_| Some(preset.clone())),
+        };
+        let record = record(0, &stored);
+        let (_, body) = check_record(&record).unwrap();
+        assert_eq!(decode_body(&record[body]).presets, stored.presets);
+    }
+
+    #[test]
+    fn newest_valid_record_wins() {
+        let stored = Stored::new();
+        let old = record(4, &stored);
+        let new = record(5, &stored);
+        let mut broken = record(6, &stored);
+        broken[HEADER_SIZE] ^= 1;
+        let erased = [0xff; RECORD_SIZE];
+
+        let slots = [&old[..], &erased, &new, &broken];
+        assert_eq!(newest(slots.into_iter()), Some((2, 5)));
+        assert_eq!(newest([&erased[..]].into_iter()), None);
+    }
+}
@@ -0,0 +1,186 @@
+//! Notes sent ahead of time by a host and played against the clock of the unit.
+//!
+//! Sending notes the moment they are due suffers from the scheduling jitter of the host.
+//! With the stream commands of `sysex` the host starts a stream and sends every event
+//! with its time after the start, some time before it is due. The events wait in a buffer
+//! and go through `MidiHandler` like live input once their time has come.
+
+use heapless::Vec;
+use midi_port::MidiMessage;
+
+use crate::{
+    midi::{create_message, HeldNotes},
+    sysex::{StreamEvents, StreamState, SysExError},
+};
+
+/// How many events can wait to be played.
+pub const STREAM_BUFFER_SIZE: usize = 128;
+
+pub struct Stream {
+    // timer value at the stream start, `None` while no stream runs
+    start_us: Option<u64>,
+    // the latest event first, so the next one is popped off the end
+    events: Vec<(u32, MidiMessage), STREAM_BUFFER_SIZE>,
+    late: u16,
+    notes: HeldNotes,
+}
+
+impl Stream {
+    pub const fn new() -> Self {
+        Self {
+            start_us: None,
+            events: Vec::new(),
+            late: 0,
+            notes: HeldNotes::new(),
+        }
+    }
+
+    /// Starts a new stream at `now_us`, `release` has to be called for the notes of the
+    /// previous one.
+    pub fn start(&mut self, now_us: u64) {
+        self.start_us = Some(now_us);
+        self.events.clear();
+        self.late = 0;
+    }
+
+    /// Drops the waiting events, `release` has to be called for the notes it played.
+    pub fn stop(&mut self) {
+        self.start_us = None;
+        self.events.clear();
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.start_us.is_some()
+    }
+
+    fn time_ms(&self, now_us: u64) -> u32 {
+        self.start_us
+            .map_or(0, |start_us| ((now_us - start_us) / 1000) as u32)
+    }
+
+    /// Takes all events or, if they don't fit, none of them.
+    pub fn queue(&mut self, events: StreamEvents, now_us: u64) -> Result<(), SysExError> {
+        if self.start_us.is_none() {
+            return Err(SysExError::NotStreaming);
+        }
+        if events.count() > self.events.capacity() - self.events.len() {
+            return Err(SysExError::BufferFull);
+        }
+
+        let now_ms = self.time_ms(now_us);
+        for event in events {
+            let time = event.time_ms;
+            if time < now_ms {
+                self.late = self.late.saturating_add(1);
+            }
+            // events with the same time are played in the order they arrived
+            let index = self.events.partition_point(|&(other, _)| other > time);
+            let msg = create_message(event.status, event.data);
+            self.events.insert(index, (time, msg)).ok();
+        }
+        Ok(())
+    }
+
+    /// Returns the next event that is due.
+    pub fn next_due(&mut self, now_us: u64) -> Option<MidiMessage> {
+        let now_ms = self.time_ms(now_us);
+        match self.events.last() {
+            Some(&(time, _)) if time <= now_ms => {
+                let (_, msg) = self.events.pop()?;
+                self.notes.track(&msg);
+                Some(msg)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn state(&self, now_us: u64) -> StreamState {
+        StreamState {
+            time_ms: self.time_ms(now_us),
+            free: (self.events.capacity() - self.events.len()) as u16,
+            late: self.late,
+        }
+    }
+
+    /// Returns the note offs for the notes still held by the stream.
+    pub fn release(&mut self) -> impl Iterator<Item = MidiMessage> {
+        self.notes.release()
+    }
+}
+
+impl Default for Stream {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sysex::{parse_request, Request};
+
+    fn events(data: &[u8]) -> StreamEvents<'_> {
+        match parse_request(data) {
+            Ok(Request::StreamEvents(events)) => events,
+            _ => panic!("no stream events"),
+        }
+    }
+
+    fn note(msg: Option<MidiMessage>) -> Option<(u8, u8)> {
+        match msg? {
+            MidiMessage::NoteOn { note, velocity, .. } => Some((note, velocity)),
+            MidiMessage::NoteOff { note, .. } => Some((note, 0)),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn events_play_in_time_order() {
+        let mut stream = Stream::new();
+        let data = [0x7d, 0, 0x0d, 10, 0, 0, 0, 0x90, 60, 100];
+        assert_eq!(
+            stream.queue(events(&data), 0),
+            Err(SysExError::NotStreaming)
+        );
+
+        stream.start(1_000_000);
+        // sent out of order: 62 at 20 ms, then 60 off and 64 on at 10 ms
+        let data = [
+            0x7d, 0, 0x0d, 20, 0, 0, 0, 0x90, 62, 100, 10, 0, 0, 0, 0x80, 60, 0, 10, 0, 0, 0, 0x90,
+            64, 100,
+        ];
+        stream.queue(events(&data), 1_000_000).unwrap();
+        assert_eq!(stream.state(1_000_000).free, STREAM_BUFFER_SIZE as u16 - 3);
+
+        assert!(stream.next_due(1_009_999).is_none());
+        assert_eq!(note(stream.next_due(1_010_000)), Some((60, 0)));
+        assert_eq!(note(stream.next_due(1_010_000)), Some((64, 100)));
+        assert!(stream.next_due(1_019_999).is_none());
+        assert_eq!(note(stream.next_due(1_020_000)), Some((62, 100)));
+        assert_eq!(stream.state(1_020_000).late, 0);
+
+        let released: std::vec::Vec<_> = stream.release().map(|msg| note(Some(msg))).collect();
+        assert_eq!(released, [Some((62, 0)), Some((64, 0))]);
+    }
+
+    #[test]
+    fn full_buffer_and_late_events() {
+        let mut stream = Stream::new();
+        stream.start(0);
+        let mut data = std::vec![0x7d, 0, 0x0d];
+        for _ in 0..4 {
+            data.extend_from_slice(&[0, 1, 0, 0, 0x90, 60, 100]);
+        }
+        for _ in 0..STREAM_BUFFER_SIZE / 4 {
+            stream.queue(events(&data), 0).unwrap();
+        }
+        assert_eq!(stream.queue(events(&data), 0), Err(SysExError::BufferFull));
+
+        stream.stop();
+        stream.start(0);
+        stream.queue(events(&data), 200_000).unwrap();
+        let state = stream.state(200_000);
+        assert_eq!((state.time_ms, state.late), (200, 4));
+        assert!(note(stream.next_due(200_000)).is_some());
+    }
+}
@@ -0,0 +1,3 @@
+//! The SysEx protocol, shared with the host tools in the `floppotron-protocol` crate.
+
+pub use floppotron_protocol::sysex::*;
@@ -0,0 +1,365 @@
+//! Class compliant USB MIDI.
+//!
+//! The device shows up with a single MIDI port the host can play to and read from. The
+//! event packets from the host are turned back into a byte stream, which is merged with
+//! the UART input by `MidiMerge`. Only the replies to SysEx requests that came in over
+//! USB are sent back, they are queued in `UsbMidiOut` until the host reads them.
+
+use defmt::warn;
+use heapless::{Deque, Vec};
+use usb_device::{
+    class_prelude::*,
+    device::{UsbDevice, UsbDeviceBuilder, UsbVidPid},
+    Result,
+};
+
+use crate::midi::SYSEX_SIZE;
+
+// shared VID/PID for MIDI class devices by obdev.at
+const VID_PID: UsbVidPid = UsbVidPid(0x16c0, 0x05e4);
+
+const USB_CLASS_AUDIO: u8 = 0x01;
+const SUBCLASS_AUDIO_CONTROL: u8 = 0x01;
+const SUBCLASS_MIDI_STREAMING: u8 = 0x03;
+
+const CS_INTERFACE: u8 = 0x24;
+const CS_ENDPOINT: u8 = 0x25;
+const HEADER: u8 = 0x01;
+const MIDI_IN_JACK: u8 = 0x02;
+const MIDI_OUT_JACK: u8 = 0x03;
+const MS_GENERAL: u8 = 0x01;
+const JACK_EMBEDDED: u8 = 0x01;
+const JACK_EXTERNAL: u8 = 0x02;
+
+// the embedded IN jack receives from the OUT endpoint and feeds the external OUT jack,
+// which stands for the drives, the external IN jack stands for the replies and feeds the
+// embedded OUT jack behind the IN endpoint
+const IN_JACK_ID: u8 = 1;
+const OUT_JACK_ID: u8 = 2;
+const EXTERNAL_IN_JACK_ID: u8 = 3;
+const EMBEDDED_OUT_JACK_ID: u8 = 4;
+
+// class specific descriptors including the endpoint descriptors, see get_configuration_descriptors
+const MS_TOTAL_LENGTH: u16 = 7 + 6 + 9 + 6 + 9 + 2 * (9 + 5);
+
+const PACKET_SIZE: u16 = 64;
+
+// holds a few replies, the host waits for each one before sending the next request
+const OUT_QUEUE_SIZE: usize = 32;
+
+/// Returns the MIDI bytes carried by a USB-MIDI event packet.
+///
+/// The code index number in the low nibble of the first byte tells how many of the
+/// other three bytes are used, the cable number is ignored. Reserved codes carry nothing.
+pub fn packet_bytes(packet: &[u8; 4]) -> &[u8] {
+    let len = match packet[0] & 0x0f {
+        // single byte system common or SysEx end, single byte
+        0x5 | 0xf => 1,
+        // two byte system common, SysEx end, program change, channel pressure
+        0x2 | 0x6 | 0xc | 0xd => 2,
+        // three byte system common, SysEx start or end, channel voice messages
+        0x3 | 0x4 | 0x7 | 0x8..=0xb | 0xe => 3,
+        _ => 0,
+    };
+    &packet[1..1 + len]
+}
+
+/// Splits a complete SysEx message, `F0` and `F7` included, into event packets on cable 0.
+///
+/// All packets but the last start or continue the message, the last one ends it with
+/// one to three bytes.
+pub fn sysex_packets(message: &[u8]) -> impl Iterator<Item = [u8; 4]> + '_ {
+    message.chunks(3).enumerate().map(move |(i, chunk)| {
+        let code = match (i + 1) * 3 >= message.len() {
+            true => 0x4 + chunk.len() as u8,
+            false => 0x4,
+        };
+        let mut packet = [code, 0, 0, 0];
+        packet[1..1 + chunk.len()].copy_from_slice(chunk);
+        packet
+    })
+}
+
+/// Event packets waiting for the host to read them from the IN endpoint.
+pub struct UsbMidiOut {
+    packets: Deque<[u8; 4], OUT_QUEUE_SIZE>,
+}
+
+impl UsbMidiOut {
+    pub fn new() -> Self {
+        Self {
+            packets: Deque::new(),
+        }
+    }
+
+    /// Queues `data` framed by `F0` and `F7`, nothing is sent if it doesn't fit.
+    pub fn send_sysex(&mut self, data: &[u8]) {
+        let mut message = Vec::<u8, { SYSEX_SIZE + 2 }>::new();
+        let framed = message.push(0xf0).is_ok()
+            && message.extend_from_slice(data).is_ok()
+            && message.push(0xf7).is_ok();
+        let count = message.len().div_ceil(3);
+        if !framed || self.packets.capacity() - self.packets.len() < count {
+            warn!("usb midi out queue full, dropping {} bytes", data.len() + 2);
+            return;
+        }
+        for packet in sysex_packets(&message) {
+            self.packets.push_back(packet).unwrap();
+        }
+    }
+}
+
+impl Default for UsbMidiOut {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The audio control and MIDI streaming interfaces with an endpoint in each direction.
+pub struct UsbMidiClass<'a, B: UsbBus> {
+    audio_control: InterfaceNumber,
+    midi_streaming: InterfaceNumber,
+    ep_out: EndpointOut<'a, B>,
+    ep_in: EndpointIn<'a, B>,
+}
+
+impl<'a, B: UsbBus> UsbMidiClass<'a, B> {
+    pub fn new(alloc: &'a UsbBusAllocator<B>) -> Self {
+        Self {
+            audio_control: alloc.interface(),
+            midi_streaming: alloc.interface(),
+            ep_out: alloc.bulk(PACKET_SIZE),
+            ep_in: alloc.bulk(PACKET_SIZE),
+        }
+    }
+
+    /// Hands as many queued packets to the IN endpoint as fit into one transfer.
+    ///
+    /// Call it only while the device is configured, the packets stay queued while the
+    /// endpoint is busy and are dropped on errors.
+    pub fn write(&mut self, out: &mut UsbMidiOut) {
+        let mut buffer = [0u8; PACKET_SIZE as usize];
+        let mut len = 0;
+        for (slot, packet) in buffer.chunks_exact_mut(4).zip(out.packets.iter()) {
+            slot.copy_from_slice(packet);
+            len += 4;
+        }
+        if len == 0 {
+            return;
+        }
+
+        match self.ep_in.write(&buffer[..len]) {
+            Ok(written) => (0..written / 4).for_each(|_| {
+                out.packets.pop_front();
+            }),
+            Err(UsbError::WouldBlock) => (),
+            Err(_) => {
+                warn!("usb midi write error");
+                out.packets.clear();
+            }
+        }
+    }
+
+    /// Calls `handle` for every MIDI byte in the pending packets.
+    pub fn read(&mut self, mut handle: impl FnMut(u8)) {
+        let mut buffer = [0u8; PACKET_SIZE as usize];
+        let len = match self.ep_out.read(&mut buffer) {
+            Ok(len) => len,
+            Err(UsbError::WouldBlock) => return,
+            Err(_) => {
+                warn!("usb midi read error");
+                return;
+            }
+        };
+
+        for packet in buffer[..len].chunks_exact(4) {
+            let packet = packet.try_into().unwrap();
+            packet_bytes(packet).iter().for_each(|&byte| handle(byte));
+        }
+    }
+}
+
+impl<B: UsbBus> UsbClass<B> for UsbMidiClass<'_, B> {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        writer.interface(
+            self.audio_control,
+            USB_CLASS_AUDIO,
+            SUBCLASS_AUDIO_CONTROL,
+            0,
+        )?;
+        // header with bcdADC 1.00, its own length and the streaming interface
+        writer.write(
+            CS_INTERFACE,
+            &[HEADER, 0x00, 0x01, 9, 0, 1, self.midi_streaming.into()],
+        )?;
+
+        writer.interface(
+            self.midi_streaming,
+            USB_CLASS_AUDIO,
+            SUBCLASS_MIDI_STREAMING,
+            0,
+        )?;
+        let [total_low, total_high] = MS_TOTAL_LENGTH.to_le_bytes();
+        writer.write(CS_INTERFACE, &[HEADER, 0x00, 0x01, total_low, total_high])?;
+        writer.write(CS_INTERFACE, &[MIDI_IN_JACK, JACK_EMBEDDED, IN_JACK_ID, 0])?;
+        writer.write(
+            CS_INTERFACE,
+            &[
+                MIDI_OUT_JACK,
+                JACK_EXTERNAL,
+                OUT_JACK_ID,
+                1,
+                IN_JACK_ID,
+                1,
+                0,
+            ],
+        )?;
+        writer.write(
+            CS_INTERFACE,
+            &[MIDI_IN_JACK, JACK_EXTERNAL, EXTERNAL_IN_JACK_ID, 0],
+        )?;
+        writer.write(
+            CS_INTERFACE,
+            &[
+                MIDI_OUT_JACK,
+                JACK_EMBEDDED,
+                EMBEDDED_OUT_JACK_ID,
+                1,
+                EXTERNAL_IN_JACK_ID,
+                1,
+                0,
+            ],
+        )?;
+
+        // audio class endpoints have bRefresh and bSynchAddress on top
+        writer.endpoint_ex(&self.ep_out, |buf| {
+            buf[..2].copy_from_slice(&[0, 0]);
+            Ok(2)
+        })?;
+        writer.write(CS_ENDPOINT, &[MS_GENERAL, 1, IN_JACK_ID])?;
+        writer.endpoint_ex(&self.ep_in, |buf| {
+            buf[..2].copy_from_slice(&[0, 0]);
+            Ok(2)
+        })?;
+        writer.write(CS_ENDPOINT, &[MS_GENERAL, 1, EMBEDDED_OUT_JACK_ID])
+    }
+}
+
+/// Builds the device for a `UsbMidiClass` on the same bus, both have to be polled regularly.
+pub fn usb_midi_device<'a, B: UsbBus>(alloc: &'a UsbBusAllocator<B>) -> UsbDevice<'a, B> {
+    UsbDeviceBuilder::new(alloc, VID_PID)
+        .manufacturer("Floppotron")
+        .product("Floppotron Jr")
+        .build()
+}
+
+#[cfg(test)]
+mod tests {
+    use midi_port::MidiMessage;
+
+    use super::*;
+    use crate::midi::{MidiEvent, MidiParser};
+
+    fn parse(packets: &[[u8; 4]]) -> Option<MidiEvent> {
+        let mut parser = MidiParser::new();
+        let mut last = None;
+        for packet in packets {
+            for &byte in packet_bytes(packet) {
+                if let Some(msg) = parser.put_byte(byte) {
+                    last = Some(msg);
+                }
+            }
+        }
+        last
+    }
+
+    #[test]
+    fn packet_lengths() {
+        assert_eq!(packet_bytes(&[0x09, 0x90, 60, 100]), &[0x90, 60, 100]);
+        assert_eq!(packet_bytes(&[0x0c, 0xc0, 2, 0]), &[0xc0, 2]);
+        assert_eq!(packet_bytes(&[0x0f, 0xf8, 0, 0]), &[0xf8]);
+        assert_eq!(packet_bytes(&[0x06, 0x01, 0xf7, 0]), &[0x01, 0xf7]);
+        assert!(packet_bytes(&[0x00, 0x90, 60, 100]).is_empty());
+        assert!(packet_bytes(&[0x01, 0x90, 60, 100]).is_empty());
+    }
+
+    #[test]
+    fn cable_number_is_ignored() {
+        assert_eq!(packet_bytes(&[0x38, 0x81, 60, 0]), &[0x81, 60, 0]);
+    }
+
+    #[test]
+    fn note_on() {
+        assert!(matches!(
+            parse(&[[0x09, 0x93, 60, 100]]),
+            Some(MidiEvent::Message(MidiMessage::NoteOn {
+                channel: 3,
+                note: 60,
+                velocity: 100
+            }))
+        ));
+    }
+
+    #[test]
+    fn program_change() {
+        assert!(matches!(
+            parse(&[[0x0c, 0xc0, 2, 0]]),
+            Some(MidiEvent::Message(MidiMessage::ProgramChange {
+                channel: 0,
+                program: 2
+            }))
+        ));
+    }
+
+    #[test]
+    fn sysex_packet_ends() {
+        let packets: std::vec::Vec<_> = sysex_packets(&[0xf0, 0x7d, 0x01, 0x02, 0xf7]).collect();
+        assert_eq!(packets, [[0x04, 0xf0, 0x7d, 0x01], [0x06, 0x02, 0xf7, 0]]);
+        let packets: std::vec::Vec<_> = sysex_packets(&[0xf0, 0x7d, 0xf7]).collect();
+        assert_eq!(packets, [[0x07, 0xf0, 0x7d, 0xf7]]);
+        let packets: std::vec::Vec<_> = sysex_packets(&[0xf0, 0x7d, 0x01, 0xf7]).collect();
+        assert_eq!(packets, [[0x04, 0xf0, 0x7d, 0x01], [0x05, 0xf7, 0, 0]]);
+    }
+
+    #[test]
+    fn queued_sysex_reads_back() {
+        let mut out = UsbMidiOut::new();
+        out.send_sysex(&[0x7d, 0x01, 0x06, 0x00]);
+        let packets: std::vec::Vec<_> = out.packets.iter().copied().collect();
+        assert!(matches!(
+            parse(&packets),
+            Some(MidiEvent::SysEx(data)) if data == [0x7d, 0x01, 0x06, 0x00]
+        ));
+    }
+
+    #[test]
+    fn full_queue_drops_whole_messages() {
+        let mut out = UsbMidiOut::new();
+        let data = [0x7d; SYSEX_SIZE];
+        // 34 bytes take 12 packets
+        out.send_sysex(&data);
+        out.send_sysex(&data);
+        out.send_sysex(&data);
+        assert_eq!(out.packets.len(), 24);
+    }
+
+    #[test]
+    fn sysex() {
+        assert!(matches!(
+            parse(&[
+                [0x04, 0xf0, 0x7d, 0x01],
+                [0x07, 0x02, 0x03, 0xf7],
+                [0x08, 0x80, 60, 0]
+            ]),
+            Some(MidiEvent::Message(MidiMessage::NoteOff {
+                channel: 0,
+                note: 60,
+                velocity: 0
+            }))
+        ));
+        assert!(matches!(
+            parse(&[[0x04, 0xf0, 0x7d, 0x01], [0x06, 0x02, 0xf7, 0]]),
+            Some(MidiEvent::SysEx(data)) if data == [0x7d, 0x01, 0x02]
+        ));
+    }
+}
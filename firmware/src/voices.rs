@@ -0,0 +1,341 @@
+use core::{cmp::Reverse, ops::Range};
+
+use defmt::Format;
+use heapless::Vec;
+
+use crate::floppy::DRIVE_COUNT;
+
+/// Highest number of voices any oscillator configuration provides.
+pub const MAX_VOICES: usize = DRIVE_COUNT;
+
+/// The first and the last voice a note may be played on.
+pub type Zone = (u8, u8);
+
+/// The zone covering every voice.
+pub const ALL_VOICES: Zone = (0, MAX_VOICES as u8 - 1);
+
+/// What happens to a new note when all voices of its zone are busy.
+#[derive(Clone, Copy, PartialEq, Debug, Format)]
+pub enum VoicePolicy {
+    /// the oldest note of the zone is stopped
+    Steal,
+    /// the new note is dropped
+    Refuse,
+    /// the new note is passed on to MIDI out, for the next unit of a chain
+    Overflow,
+}
+
+impl VoicePolicy {
+    pub fn to_bits(self) -> u8 {
+        match self {
+            VoicePolicy::Steal => 0,
+            VoicePolicy::Refuse => 1,
+            VoicePolicy::Overflow => 2,
+        }
+    }
+
+    pub fn from_bits(bits: u8) -> Option<Self> {
+        match bits {
+            0 => Some(VoicePolicy::Steal),
+            1 => Some(VoicePolicy::Refuse),
+            2 => Some(VoicePolicy::Overflow),
+            _ => None,
+        }
+    }
+}
+
+/// Assigns notes to the voices of the current oscillator configuration.
+///
+/// Only the bookkeeping lives here, so it can run on another core than the oscillators.
+/// Ages count how many notes were started after a voice's note, the oldest voice of
+/// the zone is reused once all voices of the zone are busy.
+pub struct VoiceAllocator {
+    notes: [Option<u8>; MAX_VOICES],
+    ages: [u8; MAX_VOICES],
+    count: usize,
+}
+
+impl VoiceAllocator {
+    pub fn new(count: usize) -> Self {
+        Self {
+            notes: [None; MAX_VOICES],
+            ages: [0; MAX_VOICES],
+            count: count.min(MAX_VOICES),
+        }
+    }
+
+    /// Forgets all notes, e.g. after the oscillator configuration changed.
+    pub fn reset(&mut self, count: usize) {
+        *self = Self::new(count);
+    }
+
+    /// Changes the number of voices and keeps the held notes, the newest ones if there are
+    /// more than `count`. Returns the notes that were dropped, voices may change.
+    pub fn resize(&mut self, count: usize) -> Vec<u8, MAX_VOICES> {
+        let mut held: Vec<(u8, u8), MAX_VOICES> = (0..self.count)
+            .filter_map(|voice| Some((self.ages[voice], self.notes[voice]?)))
+            .collect();
+        // oldest first, so the ages come out the same
+        held.sort_unstable_by_key(|&(age, _)| Reverse(age));
+
+        *self = Self::new(count);
+        let drop_count = held.len().saturating_sub(self.count);
+        let mut dropped = Vec::new();
+        for (i, &(_, note)) in held.iter().enumerate() {
+            if i < drop_count {
+                dropped.push(note).unwrap();
+            } else {
+                self.play_note_free(note, ALL_VOICES);
+            }
+        }
+        dropped
+    }
+
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// Number of voices playing a note.
+    pub fn busy_count(&self) -> usize {
+        self.notes[..self.count].iter().flatten().count()
+    }
+
+    pub fn note(&self, voice: usize) -> Option<u8> {
+        self.notes[..self.count].get(voice).copied().flatten()
+    }
+
+    fn find(&self, func: impl Fn(usize) -> bool) -> Option<usize> {
+        (0..self.count).find(|&voice| func(voice))
+    }
+
+    // the voices of `zone` that exist in the current configuration, a zone past the
+    // last voice is moved onto it
+    fn zone_voices(&self, (first, last): Zone) -> Range<usize> {
+        match self.count.checked_sub(1) {
+            Some(max) => (first as usize).min(max)..(last as usize).min(max) + 1,
+            None => 0..0,
+        }
+    }
+
+    /// Returns the voice of `zone` that has to play `note`, the oldest note of the zone
+    /// is stolen if all its voices are busy.
+    ///
+    /// A note that already sounds is retriggered on its voice, even outside of `zone`.
+    pub fn play_note(&mut self, note: u8, zone: Zone) -> Option<usize> {
+        self.allocate(note, zone, true)
+    }
+
+    /// Like `play_note`, but returns `None` instead of stealing a voice.
+    pub fn play_note_free(&mut self, note: u8, zone: Zone) -> Option<usize> {
+        self.allocate(note, zone, false)
+    }
+
+    fn allocate(&mut self, note: u8, zone: Zone, steal: bool) -> Option<usize> {
+        if let Some(active) = self.find(|voice| self.notes[voice] == Some(note)) {
+            // retrigger
+            let active_age = self.ages[active];
+            for voice in 0..self.count {
+                if let Some(voice_note) = self.notes[voice] {
+                    if self.ages[voice] < active_age {
+                        self.ages[voice] += 1;
+                    } else if voice_note == note {
+                        self.ages[voice] = 0;
+                    }
+                }
+            }
+            return Some(active);
+        }
+
+        let voices = self.zone_voices(zone);
+        let voice = match voices.clone().find(|&voice| self.notes[voice].is_none()) {
+            Some(voice) => voice,
+            None if steal => {
+                let oldest = voices.max_by_key(|&voice| self.ages[voice])?;
+                self.release(oldest);
+                oldest
+            }
+            None => return None,
+        };
+
+        for other in 0..self.count {
+            if self.notes[other].is_some() {
+                self.ages[other] += 1;
+            }
+        }
+        self.notes[voice] = Some(note);
+        self.ages[voice] = 0;
+        Some(voice)
+    }
+
+    /// Returns the voice that played `note`, if any, which has to be stopped now.
+    pub fn stop_note(&mut self, note: u8) -> Option<usize> {
+        let active = self.find(|voice| self.notes[voice] == Some(note))?;
+        self.release(active);
+        Some(active)
+    }
+
+    // frees `voice`, the notes started after it get one younger
+    fn release(&mut self, voice: usize) {
+        let age = self.ages[voice];
+        self.notes[voice] = None;
+        self.ages[voice] = 0;
+
+        for other in 0..self.count {
+            if self.notes[other].is_some() && self.ages[other] > age {
+                self.ages[other] -= 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{control::OscMode, oscillators::mode_voice_count};
+
+    #[test]
+    fn resize_keeps_the_newest_notes() {
+        let mut voices = VoiceAllocator::new(4);
+        for note in [60, 62, 64, 65] {
+            voices.play_note(note, ALL_VOICES);
+        }
+        voices.stop_note(62);
+
+        assert_eq!(voices.resize(2), [60]);
+        let mut notes: [_; 2] = core::array::from_fn(|voice| voices.note(voice));
+        notes.sort();
+        assert_eq!(notes, [Some(64), Some(65)]);
+        assert_eq!(voices.stop_note(64), Some(0));
+        assert_eq!(voices.play_note(67, ALL_VOICES), Some(0));
+
+        assert!(voices.resize(4).is_empty());
+        assert_eq!(voices.busy_count(), 2);
+    }
+
+    #[test]
+    fn zones_keep_their_voices() {
+        let mut voices = VoiceAllocator::new(6);
+        let (lower, upper) = ((0, 1), (2, 5));
+        assert_eq!(voices.play_note(40, lower), Some(0));
+        assert_eq!(voices.play_note(41, lower), Some(1));
+        assert_eq!(voices.play_note(72, upper), Some(2));
+        // the lower zone steals its own oldest note, the upper one isn't touched
+        assert_eq!(voices.play_note(43, lower), Some(0));
+        assert_eq!(voices.play_note_free(44, lower), None);
+        assert_eq!(voices.note(2), Some(72));
+        // a sounding note is retriggered where it is
+        assert_eq!(voices.play_note(72, lower), Some(2));
+
+        // a zone past the last voice gets the last one
+        voices.reset(3);
+        assert_eq!(voices.play_note(60, upper), Some(2));
+        assert_eq!(voices.play_note(62, upper), Some(2));
+    }
+
+    // xorshift, the sequences only have to differ between seeds
+    struct Rng(u32);
+
+    impl Rng {
+        fn below(&mut self, n: u32) -> u32 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 17;
+            self.0 ^= self.0 << 5;
+            self.0 % n
+        }
+    }
+
+    // the held notes with their voices, the oldest first
+    type Held = std::vec::Vec<(u8, usize)>;
+
+    fn check_invariants(voices: &VoiceAllocator, held: &Held) {
+        for (i, &(note, voice)) in held.iter().enumerate() {
+            assert_eq!(voices.note(voice), Some(note));
+            // the ages are a permutation of 0..held.len(), in the order the notes started
+            assert_eq!(voices.ages[voice] as usize, held.len() - 1 - i);
+        }
+        assert_eq!(voices.busy_count(), held.len());
+        assert!(voices.notes[voices.count..].iter().all(Option::is_none));
+    }
+
+    fn play(voices: &mut VoiceAllocator, held: &mut Held, note: u8, zone: Zone, steal: bool) {
+        let voice = match steal {
+            true => voices.play_note(note, zone),
+            false => voices.play_note_free(note, zone),
+        };
+        let count = voices.count();
+        let first = (zone.0 as usize).min(count.saturating_sub(1));
+        let last = (zone.1 as usize).min(count.saturating_sub(1));
+        let in_zone = |voice: usize| count > 0 && (first..=last).contains(&voice);
+        let zone_busy = held.iter().filter(|&&(_, voice)| in_zone(voice)).count();
+
+        let expected = match held.iter().position(|&(other, _)| other == note) {
+            // a retrigger keeps the voice and makes the note the newest
+            Some(i) => Some(held.remove(i).1),
+            // any free voice of the zone will do
+            None if count > 0 && zone_busy < last - first + 1 => {
+                assert!(voice.is_some_and(in_zone));
+                assert!(held.iter().all(|&(_, other)| Some(other) != voice));
+                voice
+            }
+            // the oldest note of the zone is stolen
+            None if steal && count > 0 => {
+                let i = held.iter().position(|&(_, voice)| in_zone(voice)).unwrap();
+                Some(held.remove(i).1)
+            }
+            None => None,
+        };
+        assert_eq!(voice, expected, "note {note} in {held:?}");
+        if let Some(voice) = voice {
+            assert!(voice < count);
+            held.push((note, voice));
+        }
+    }
+
+    fn stop(voices: &mut VoiceAllocator, held: &mut Held, note: u8) {
+        let expected = held
+            .iter()
+            .position(|&(other, _)| other == note)
+            .map(|i| held.remove(i).1);
+        assert_eq!(voices.stop_note(note), expected, "note {note} in {held:?}");
+    }
+
+    fn resize(voices: &mut VoiceAllocator, held: &mut Held, count: usize) {
+        let drop_count = held.len().saturating_sub(count);
+        let dropped: std::vec::Vec<_> = held.drain(..drop_count).map(|(note, _)| note).collect();
+        assert_eq!(voices.resize(count), dropped[..]);
+        // the voices may change, the order of the notes doesn't
+        for (note, voice) in held.iter_mut() {
+            *voice = (0..count).find(|&v| voices.note(v) == Some(*note)).unwrap();
+        }
+    }
+
+    #[test]
+    fn random_notes_keep_invariants() {
+        let counts = [OscMode::Single, OscMode::Inverse, OscMode::Unisono]
+            .map(|mode| mode_voice_count(mode) as usize);
+        // overlapping ones and some beyond the voices of the smaller configurations
+        let zones = [ALL_VOICES, (0, 1), (1, 3), (2, 5), (4, 4), (5, 5)];
+        for &count in &counts {
+            for seed in 1..=50u32 {
+                let mut rng = Rng(seed.wrapping_mul(2_654_435_761));
+                let mut voices = VoiceAllocator::new(count);
+                let mut held = Held::new();
+                for _ in 0..500 {
+                    // few notes, so retriggers and stolen notes are common
+                    let note = 60 + rng.below(10) as u8;
+                    let zone = zones[rng.below(zones.len() as u32) as usize];
+                    match rng.below(20) {
+                        0..=7 => play(&mut voices, &mut held, note, zone, true),
+                        8..=9 => play(&mut voices, &mut held, note, zone, false),
+                        10..=18 => stop(&mut voices, &mut held, note),
+                        _ => {
+                            let count = counts[rng.below(3) as usize];
+                            resize(&mut voices, &mut held, count);
+                        }
+                    }
+                    check_invariants(&voices, &held);
+                }
+            }
+        }
+    }
+}
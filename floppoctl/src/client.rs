@@ -0,0 +1,197 @@
+//! Requests to a unit and the replies they wait for.
+
+use std::{
+    fmt, io,
+    time::{Duration, Instant},
+};
+
+use floppotron_protocol::sysex::{
+    parse_reply, Param, Reply, Request, Status, SysExError, BROADCAST_ID,
+};
+
+use crate::link::Link;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// no reply arrived in time
+    Timeout,
+    /// the unit answered with an error
+    Unit(SysExError),
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "{err}"),
+            Error::Timeout => write!(f, "the unit didn't answer"),
+            Error::Unit(err) => write!(f, "the unit refused the request: {err:?}"),
+        }
+    }
+}
+
+pub struct Client<L> {
+    link: L,
+    // `BROADCAST_ID` takes the replies of any unit
+    device_id: u8,
+    timeout: Duration,
+}
+
+impl<L: Link> Client<L> {
+    pub fn new(link: L, device_id: u8, timeout: Duration) -> Self {
+        Self {
+            link,
+            device_id,
+            timeout,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn link(&mut self) -> &mut L {
+        &mut self.link
+    }
+
+    /// Sends `request` and waits for the reply `accept` takes. Replies of other units and
+    /// to other requests are skipped.
+    fn request<T>(
+        &mut self,
+        request: Request,
+        mut accept: impl FnMut(Reply) -> Option<T>,
+    ) -> Result<T, Error> {
+        let data = request.encode(self.device_id);
+        let command = data[2];
+        let mut message = vec![0xf0];
+        message.extend_from_slice(&data);
+        message.push(0xf7);
+        self.link.send(&message)?;
+
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            let data = self.link.receive(timeout)?.ok_or(Error::Timeout)?;
+            let (device_id, reply) = match parse_reply(&data) {
+                Ok(reply) => reply,
+                Err(_) => continue,
+            };
+            if self.device_id != BROADCAST_ID && device_id != self.device_id {
+                continue;
+            }
+            match reply {
+                Reply::Error {
+                    command: failed,
+                    error,
+                } if failed == command => return Err(Error::Unit(error)),
+                reply => {
+                    if let Some(value) = accept(reply) {
+                        return Ok(value);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn get(&mut self, param: Param, index: u8) -> Result<Vec<u8>, Error> {
+        self.request(Request::Get { param, index }, |reply| match reply {
+            Reply::Value {
+                param: p,
+                index: i,
+                values,
+            } if (p, i) == (param, index) => Some(values.to_vec()),
+            _ => None,
+        })
+    }
+
+    pub fn set(&mut self, param: Param, index: u8, values: &[u8]) -> Result<(), Error> {
+        let request = Request::Set {
+            param,
+            index,
+            values,
+        };
+        self.request(request, |reply| match reply {
+            Reply::Ack { param: p, index: i } if (p, i) == (param, index) => Some(()),
+            _ => None,
+        })
+    }
+
+    pub fn status(&mut self) -> Result<Status, Error> {
+        self.request(Request::Status, |reply| match reply {
+            Reply::Status(status) => Some(status),
+            _ => None,
+        })
+    }
+
+    /// Stores the current settings as user preset, an empty name deletes it.
+    pub fn store_preset(&mut self, preset: u8, name: &[u8]) -> Result<(), Error> {
+        self.request(Request::StorePreset { preset, name }, |reply| match reply {
+            Reply::PresetStored { preset: p } if p == preset => Some(()),
+            _ => None,
+        })
+    }
+
+    /// Returns the name of a preset, empty if there is none.
+    pub fn preset_name(&mut self, bank: u8, preset: u8) -> Result<Vec<u8>, Error> {
+        self.request(Request::PresetName { bank, preset }, |reply| match reply {
+            Reply::PresetName {
+                bank: b,
+                preset: p,
+                name,
+            } if (b, p) == (bank, preset) => Some(name.to_vec()),
+            _ => None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake::FakeUnit;
+
+    fn client(fake: FakeUnit, device_id: u8) -> Client<FakeUnit> {
+        Client::new(fake, device_id, Duration::ZERO)
+    }
+
+    #[test]
+    fn settings_and_presets() {
+        let mut fake = FakeUnit::new();
+        fake.other_unit = true;
+        let mut client = client(fake, 0);
+
+        assert_eq!(client.get(Param::TrackRange, 2).unwrap(), [0, 79]);
+        client.set(Param::Transpose, 0, &[66]).unwrap();
+        assert_eq!(client.get(Param::Transpose, 0).unwrap(), [66]);
+        assert_eq!(client.status().unwrap().voice_count, 6);
+
+        client.store_preset(3, b"bass").unwrap();
+        assert_eq!(client.preset_name(1, 3).unwrap(), b"bass");
+        assert_eq!(client.preset_name(1, 4).unwrap(), b"");
+        assert_eq!(
+            client.link.presets[3].as_ref().unwrap().1,
+            client.link.settings
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let mut client = client(FakeUnit::new(), 0);
+        assert!(matches!(
+            client.get(Param::TrackRange, 6),
+            Err(Error::Unit(SysExError::BadIndex))
+        ));
+        assert!(matches!(
+            client.set(Param::TrackRange, 0, &[5]),
+            Err(Error::Unit(SysExError::Malformed))
+        ));
+
+        // another unit doesn't answer, the broadcast ID reaches every one
+        let mut client = self::client(FakeUnit::new(), 1);
+        assert!(matches!(client.status(), Err(Error::Timeout)));
+        client.device_id = BROADCAST_ID;
+        assert!(client.status().is_ok());
+    }
+}
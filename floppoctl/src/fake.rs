@@ -0,0 +1,147 @@
+//! A unit in memory for the tests, it answers like the firmware with the default settings.
+
+use std::{collections::BTreeMap, collections::VecDeque, io, time::Duration};
+
+use floppotron_protocol::{
+    sysex::{
+        error_reply, parse_request, target, Param, Reply, Request, Status, SysExError, BROADCAST_ID,
+    },
+    PRESET_COUNT,
+};
+
+use crate::link::Link;
+
+pub const DRIVE_COUNT: u8 = 6;
+
+// by parameter byte and index
+pub type Settings = BTreeMap<(u8, u8), Vec<u8>>;
+
+pub struct FakeUnit {
+    pub device_id: u8,
+    pub settings: Settings,
+    pub presets: [Option<(Vec<u8>, Settings)>; PRESET_COUNT],
+    /// replies of another unit on the same bus are sent before every reply
+    pub other_unit: bool,
+    replies: VecDeque<Vec<u8>>,
+}
+
+impl FakeUnit {
+    pub fn new() -> Self {
+        let mut settings = Settings::new();
+        settings.insert((Param::OscMode.to_byte(), 0), vec![0]);
+        for index in 0..32 {
+            settings.insert((Param::ChannelMap.to_byte(), index), vec![index % 16]);
+        }
+        settings.insert((Param::Transpose.to_byte(), 0), vec![64]);
+        for index in 0..DRIVE_COUNT {
+            settings.insert((Param::TrackRange.to_byte(), index), vec![0, 79]);
+        }
+        settings.insert((Param::MidiOut.to_byte(), 0), vec![1]);
+        settings.insert((Param::DeviceId.to_byte(), 0), vec![0]);
+        for index in 0..16 {
+            settings.insert((Param::Zone.to_byte(), index), vec![0, DRIVE_COUNT - 1]);
+        }
+        settings.insert((Param::VoicePolicy.to_byte(), 0), vec![0]);
+        settings.insert((Param::Tuning.to_byte(), 0), vec![64]);
+        for index in 0..12 {
+            settings.insert((Param::ScaleTuning.to_byte(), index), vec![64]);
+        }
+        settings.insert((Param::Vibrato.to_byte(), 0), vec![0, 0]);
+        Self {
+            device_id: 0,
+            settings,
+            presets: Default::default(),
+            other_unit: false,
+            replies: VecDeque::new(),
+        }
+    }
+
+    /// Handles a message without `F0` and `F7`.
+    pub fn handle(&mut self, data: &[u8]) {
+        match target(data) {
+            Some(id) if id == self.device_id || id == BROADCAST_ID => {}
+            _ => return,
+        }
+        let reply = match parse_request(data) {
+            Ok(Request::Get { param, index }) => match self.settings.get(&(param.to_byte(), index))
+            {
+                Some(values) => Reply::Value {
+                    param,
+                    index,
+                    values,
+                }
+                .encode(self.device_id),
+                None => error_reply(self.device_id, data, SysExError::BadIndex),
+            },
+            Ok(Request::Set {
+                param,
+                index,
+                values,
+            }) => match self.settings.get_mut(&(param.to_byte(), index)) {
+                Some(old) if old.len() == values.len() => {
+                    *old = values.to_vec();
+                    Reply::Ack { param, index }.encode(self.device_id)
+                }
+                Some(_) => error_reply(self.device_id, data, SysExError::Malformed),
+                None => error_reply(self.device_id, data, SysExError::BadIndex),
+            },
+            Ok(Request::Status) => Reply::Status(Status {
+                osc_mode: self.settings[&(Param::OscMode.to_byte(), 0)][0],
+                voice_count: DRIVE_COUNT,
+                busy_voices: 0,
+                drive_count: DRIVE_COUNT,
+                midi_out: self.settings[&(Param::MidiOut.to_byte(), 0)][0],
+                voice_policy: self.settings[&(Param::VoicePolicy.to_byte(), 0)][0],
+            })
+            .encode(self.device_id),
+            Ok(Request::StorePreset { preset, name }) => {
+                self.presets[preset as usize] =
+                    (!name.is_empty()).then(|| (name.to_vec(), self.settings.clone()));
+                Reply::PresetStored { preset }.encode(self.device_id)
+            }
+            Ok(Request::PresetName { bank: 1, preset }) => {
+                let name = match &self.presets[preset as usize] {
+                    Some((name, _)) => name.as_slice(),
+                    None => &[],
+                };
+                Reply::PresetName {
+                    bank: 1,
+                    preset,
+                    name,
+                }
+                .encode(self.device_id)
+            }
+            Ok(Request::PresetName { bank: 0, preset }) => Reply::PresetName {
+                bank: 0,
+                preset,
+                name: if preset == 0 { b"single" } else { b"" },
+            }
+            .encode(self.device_id),
+            Ok(_) => error_reply(self.device_id, data, SysExError::BadIndex),
+            Err(err) => error_reply(self.device_id, data, err),
+        };
+        if self.other_unit {
+            let other = Reply::Ack {
+                param: Param::Transpose,
+                index: 0,
+            };
+            self.replies
+                .push_back(other.encode(self.device_id + 1).to_vec());
+        }
+        self.replies.push_back(reply.to_vec());
+    }
+}
+
+impl Link for FakeUnit {
+    fn send(&mut self, bytes: &[u8]) -> io::Result<()> {
+        match bytes {
+            [0xf0, data @ .., 0xf7] => self.handle(data),
+            _ => panic!("not a SysEx message: {bytes:x?}"),
+        }
+        Ok(())
+    }
+
+    fn receive(&mut self, _timeout: Duration) -> io::Result<Option<Vec<u8>>> {
+        Ok(self.replies.pop_front())
+    }
+}
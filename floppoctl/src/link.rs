@@ -0,0 +1,177 @@
+//! The connection to a unit, a MIDI port or a serial device.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, ErrorKind, Read, Write},
+    process::Command,
+    sync::mpsc::{self, Receiver, RecvTimeoutError, Sender},
+    thread,
+    time::Duration,
+};
+
+/// Carries the SysEx messages to and from a unit.
+pub trait Link {
+    /// Sends raw MIDI bytes.
+    fn send(&mut self, bytes: &[u8]) -> io::Result<()>;
+
+    /// Waits up to `timeout` for the next SysEx message, returns it without `F0` and `F7`.
+    fn receive(&mut self, timeout: Duration) -> io::Result<Option<Vec<u8>>>;
+}
+
+impl<L: Link + ?Sized> Link for Box<L> {
+    fn send(&mut self, bytes: &[u8]) -> io::Result<()> {
+        (**self).send(bytes)
+    }
+
+    fn receive(&mut self, timeout: Duration) -> io::Result<Option<Vec<u8>>> {
+        (**self).receive(timeout)
+    }
+}
+
+/// Collects the SysEx messages in a MIDI byte stream, everything else is skipped.
+#[derive(Default)]
+pub struct SysExReader {
+    data: Option<Vec<u8>>,
+}
+
+impl SysExReader {
+    /// Returns a message once its `F7` arrives.
+    pub fn push(&mut self, byte: u8) -> Option<Vec<u8>> {
+        match byte {
+            0xf0 => self.data = Some(Vec::new()),
+            0xf7 => return self.data.take(),
+            // real time messages may appear anywhere
+            0xf8..=0xff => {}
+            // any other status ends a message without `F7`
+            0x80..=0xef | 0xf1..=0xf6 => self.data = None,
+            _ => {
+                if let Some(data) = &mut self.data {
+                    data.push(byte);
+                }
+            }
+        }
+        None
+    }
+}
+
+/// A byte stream, a thread reads the incoming messages so `receive` can time out.
+pub struct PortLink<W> {
+    writer: W,
+    messages: Receiver<io::Result<Vec<u8>>>,
+}
+
+impl<W: Write> PortLink<W> {
+    pub fn new<R: Read + Send + 'static>(reader: R, writer: W) -> Self {
+        let (sender, messages) = mpsc::channel();
+        thread::spawn(move || read_messages(reader, sender));
+        Self { writer, messages }
+    }
+}
+
+fn read_messages(mut reader: impl Read, sender: Sender<io::Result<Vec<u8>>>) {
+    let mut sysex = SysExReader::default();
+    let mut buf = [0; 256];
+    loop {
+        match reader.read(&mut buf) {
+            // dropping the sender tells `receive` the port is gone
+            Ok(0) => return,
+            Ok(len) => {
+                for &byte in &buf[..len] {
+                    if let Some(message) = sysex.push(byte) {
+                        if sender.send(Ok(message)).is_err() {
+                            return;
+                        }
+                    }
+                }
+            }
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(err) => {
+                sender.send(Err(err)).ok();
+                return;
+            }
+        }
+    }
+}
+
+impl<W: Write> Link for PortLink<W> {
+    fn send(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.writer.write_all(bytes)?;
+        self.writer.flush()
+    }
+
+    fn receive(&mut self, timeout: Duration) -> io::Result<Option<Vec<u8>>> {
+        match self.messages.recv_timeout(timeout) {
+            Ok(message) => message.map(Some),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => Err(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "the port was closed",
+            )),
+        }
+    }
+}
+
+/// Opens a raw MIDI device like `/dev/snd/midiC1D0`, a virtual one of `snd-virmidi` too.
+pub fn open_port(path: &str) -> io::Result<PortLink<File>> {
+    let file = OpenOptions::new().read(true).write(true).open(path)?;
+    Ok(PortLink::new(file.try_clone()?, file))
+}
+
+/// Opens a serial device carrying plain MIDI bytes, `stty` sets it up.
+pub fn open_serial(path: &str, baud: Option<u32>) -> io::Result<PortLink<File>> {
+    let mut stty = Command::new("stty");
+    stty.args(["-F", path, "raw", "-echo"]);
+    if let Some(baud) = baud {
+        stty.arg(baud.to_string());
+    }
+    if !stty.status()?.success() {
+        return Err(io::Error::other(format!("stty can't set up {path}")));
+    }
+    open_port(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::net::UnixStream;
+
+    use super::*;
+    use crate::fake::FakeUnit;
+
+    #[test]
+    fn sysex_between_other_messages() {
+        let mut reader = SysExReader::default();
+        let bytes = [
+            0x90, 60, 100, 0xf0, 0x7d, 0xf8, 0x00, 0x05, 0xf7, 0xf0, 0x7d, 0x80, 60, 0, 0xf7,
+        ];
+        let messages: Vec<_> = bytes.iter().filter_map(|&byte| reader.push(byte)).collect();
+        // the clock is skipped, the note off cuts the second message short
+        assert_eq!(messages, [vec![0x7d, 0x00, 0x05]]);
+    }
+
+    #[test]
+    fn loopback() {
+        let (host, unit) = UnixStream::pair().unwrap();
+        thread::spawn(move || {
+            let mut fake = FakeUnit::new();
+            let mut writer = unit.try_clone().unwrap();
+            let mut sysex = SysExReader::default();
+            for byte in io::BufReader::new(unit).bytes() {
+                if let Some(message) = sysex.push(byte.unwrap()) {
+                    fake.handle(&message);
+                    while let Some(reply) = fake.receive(Duration::ZERO).unwrap() {
+                        // notes and clocks around the reply, like a unit playing
+                        writer.write_all(&[0x90, 60, 100, 0xf0]).unwrap();
+                        writer.write_all(&reply).unwrap();
+                        writer.write_all(&[0xf8, 0xf7, 0x80, 60, 0]).unwrap();
+                    }
+                }
+            }
+        });
+
+        let mut link = PortLink::new(host.try_clone().unwrap(), host);
+        link.send(&[0xf0, 0x7d, 0x00, 0x05, 0xf7]).unwrap();
+        let reply = link.receive(Duration::from_secs(1)).unwrap();
+        assert_eq!(reply, Some(vec![0x7d, 0x00, 0x06, 0, 6, 0, 6, 1, 0]));
+        assert_eq!(link.receive(Duration::from_millis(10)).unwrap(), None);
+    }
+}
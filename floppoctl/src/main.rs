@@ -0,0 +1,401 @@
+//! Reads and writes the settings of a unit, dumps its status and uploads presets.
+//!
+//! The unit is reached through a raw MIDI device of ALSA, a port of the ALSA sequencer or
+//! a serial device carrying plain MIDI bytes, the messages are the SysEx protocol of
+//! `floppotron-protocol`:
+//!
+//!     floppoctl --port /dev/snd/midiC1D0 status
+//!     floppoctl --port /dev/snd/midiC1D0 get transpose 0
+//!     floppoctl --port /dev/snd/midiC1D0 set track-range 2 10 60
+//!     floppoctl --serial /dev/ttyUSB0 --baud 38400 dump > settings.txt
+//!     floppoctl --port /dev/snd/midiC1D0 upload 3 lead settings.txt
+//!
+//! `dump` writes one `<param> <index> <values>` line per setting, `upload` takes such a file
+//! and stores it as user preset. Lines of the device ID, the MIDI out mode and the voice
+//! policy are skipped, presets keep how the unit is wired. The unit plays with the uploaded
+//! settings while they are stored, its previous settings are restored afterwards.
+//!
+//! The unit answers on the input the request came from. Over USB it is a card of its own,
+//! its raw MIDI device can be passed directly, see `amidi -l`.
+//!
+//! Built with the `alsa-seq` feature, which links libasound, `--seq` creates a sequencer
+//! client and connects its port with the given one in both directions. Other programs
+//! can stay connected to the unit meanwhile, and a virtual port can stand in for it:
+//!
+//!     floppoctl --seq 'Floppotron Jr' status
+//!     floppoctl --seq 24:0 dump
+//!
+//! The test suite runs against a unit in memory and over a socket pair, the sequencer
+//! test needs a running sequencer and is ignored by default.
+
+mod client;
+#[cfg(test)]
+mod fake;
+mod link;
+#[cfg(feature = "alsa-seq")]
+mod seq;
+
+use std::{
+    fmt, fs,
+    io::{self, Write},
+    process::exit,
+    time::Duration,
+};
+
+use floppotron_protocol::{
+    sysex::{Param, SysExError},
+    PRESET_COUNT, PRESET_NAME_SIZE,
+};
+
+use crate::{
+    client::{Client, Error},
+    link::{open_port, open_serial, Link},
+};
+
+const FACTORY_BANK: u8 = 0;
+const USER_BANK: u8 = 1;
+// a setting has at most two values, like a track range or a zone
+const MAX_VALUES: usize = 2;
+
+enum Connection {
+    Port(String),
+    Serial(String, Option<u32>),
+    #[cfg(feature = "alsa-seq")]
+    Seq(String),
+}
+
+#[cfg(feature = "alsa-seq")]
+const SEQ_USAGE: &str = " | --seq <client:port>";
+#[cfg(not(feature = "alsa-seq"))]
+const SEQ_USAGE: &str = "";
+
+struct Options {
+    connection: Connection,
+    device_id: u8,
+    timeout: Duration,
+    command: Vec<String>,
+}
+
+fn usage() -> ! {
+    eprintln!(
+        "usage: floppoctl (--port <raw midi device> | --serial <device> [--baud <rate>]{}) \
+         [--device <id>] [--timeout <ms>] <command>\n\
+         \n\
+         commands:\n    \
+         status\n    \
+         get <param> <index>\n    \
+         set <param> <index> <values>...\n    \
+         dump\n    \
+         presets\n    \
+         upload <preset> <name> <file>\n    \
+         delete <preset>\n\
+         \n\
+         params: {}",
+        SEQ_USAGE,
+        Param::ALL.map(Param::name).join(", ")
+    );
+    exit(2)
+}
+
+fn parse_options() -> Options {
+    let mut args = std::env::args().skip(1);
+    let mut port = None;
+    let mut serial = None;
+    let mut seq = None;
+    let mut baud = None;
+    let mut device_id = 0;
+    let mut timeout = Duration::from_millis(500);
+    let mut command = Vec::new();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().unwrap_or_else(|| usage());
+        match arg.as_str() {
+            "--port" => port = Some(value()),
+            "--serial" => serial = Some(value()),
+            "--seq" => seq = Some(value()),
+            "--baud" => baud = Some(value().parse().unwrap_or_else(|_| usage())),
+            "--device" => match value().parse() {
+                Ok(id) if id <= 0x7f => device_id = id,
+                _ => usage(),
+            },
+            "--timeout" => {
+                let ms = value().parse().unwrap_or_else(|_| usage());
+                timeout = Duration::from_millis(ms);
+            }
+            _ if arg.starts_with("--") => usage(),
+            _ => command.push(arg),
+        }
+    }
+    let connection = match (port, serial, seq) {
+        (Some(port), None, None) => Connection::Port(port),
+        (None, Some(serial), None) => Connection::Serial(serial, baud),
+        #[cfg(feature = "alsa-seq")]
+        (None, None, Some(seq)) => Connection::Seq(seq),
+        _ => usage(),
+    };
+    Options {
+        connection,
+        device_id,
+        timeout,
+        command,
+    }
+}
+
+/// A line of a settings file.
+#[derive(Clone, PartialEq, Debug)]
+struct Setting {
+    param: Param,
+    index: u8,
+    values: Vec<u8>,
+}
+
+impl Setting {
+    fn parse<'a>(mut words: impl Iterator<Item = &'a str>) -> Option<Self> {
+        let param = Param::from_name(words.next()?)?;
+        let index = words.next()?.parse().ok().filter(|&index| index < 0x80)?;
+        let values = words
+            .map(|word| word.parse().ok().filter(|&value| value < 0x80))
+            .collect::<Option<Vec<u8>>>()?;
+        (1..=MAX_VALUES).contains(&values.len()).then_some(Self {
+            param,
+            index,
+            values,
+        })
+    }
+
+    /// Whether presets hold the setting.
+    fn in_preset(&self) -> bool {
+        !matches!(
+            self.param,
+            Param::DeviceId | Param::MidiOut | Param::VoicePolicy
+        )
+    }
+}
+
+impl fmt::Display for Setting {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.param.name(), self.index)?;
+        for value in &self.values {
+            write!(f, " {value}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses the lines of a settings file, empty lines and ones starting with `#` are skipped.
+fn parse_settings(text: &str) -> Result<Vec<Setting>, String> {
+    text.lines()
+        .enumerate()
+        .map(|(number, line)| (number + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(number, line)| {
+            Setting::parse(line.split_whitespace()).ok_or(format!("bad setting in line {number}"))
+        })
+        .collect()
+}
+
+fn dump(client: &mut Client<impl Link>, out: &mut impl Write) -> Result<(), Error> {
+    for param in Param::ALL {
+        for index in 0..if param.is_indexed() { 0x80 } else { 1 } {
+            let values = match client.get(param, index) {
+                Ok(values) => values,
+                // the indices end with the channels or drives of the unit
+                Err(Error::Unit(SysExError::BadIndex)) if index > 0 => break,
+                Err(err) => return Err(err),
+            };
+            let setting = Setting {
+                param,
+                index,
+                values,
+            };
+            writeln!(out, "{setting}")?;
+        }
+    }
+    Ok(())
+}
+
+fn presets(client: &mut Client<impl Link>, out: &mut impl Write) -> Result<(), Error> {
+    for bank in [FACTORY_BANK, USER_BANK] {
+        for preset in 0..PRESET_COUNT as u8 {
+            let name = client.preset_name(bank, preset)?;
+            if !name.is_empty() {
+                writeln!(out, "{bank} {preset} {}", String::from_utf8_lossy(&name))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Stores `settings` as user preset, the settings of the unit are restored afterwards.
+fn upload(
+    client: &mut Client<impl Link>,
+    preset: u8,
+    name: &[u8],
+    settings: &[Setting],
+) -> Result<(), Error> {
+    let settings: Vec<_> = settings.iter().filter(|s| s.in_preset()).collect();
+    let mut previous = Vec::new();
+    for setting in &settings {
+        let values = client.get(setting.param, setting.index)?;
+        previous.push((setting.param, setting.index, values));
+    }
+
+    let mut stored = Ok(());
+    for setting in &settings {
+        stored = client.set(setting.param, setting.index, &setting.values);
+        if stored.is_err() {
+            break;
+        }
+    }
+    if stored.is_ok() {
+        stored = client.store_preset(preset, name);
+    }
+
+    for (param, index, values) in previous {
+        client.set(param, index, &values)?;
+    }
+    stored
+}
+
+fn parse_preset(arg: &str) -> u8 {
+    match arg.parse() {
+        Ok(preset) if (preset as usize) < PRESET_COUNT => preset,
+        _ => {
+            eprintln!("presets are numbered 0 to {}", PRESET_COUNT - 1);
+            exit(2)
+        }
+    }
+}
+
+fn parse_name(arg: &str) -> &[u8] {
+    let printable = arg.bytes().all(|byte| (0x20..0x7f).contains(&byte));
+    if arg.is_empty() || arg.len() > PRESET_NAME_SIZE || !printable {
+        eprintln!("preset names are 1 to {PRESET_NAME_SIZE} ASCII characters");
+        exit(2)
+    }
+    arg.as_bytes()
+}
+
+fn run(client: &mut Client<impl Link>, command: &[String]) -> Result<(), Error> {
+    let mut out = io::stdout().lock();
+    let command: Vec<&str> = command.iter().map(String::as_str).collect();
+    match command[..] {
+        ["status"] => {
+            let status = client.status()?;
+            let osc_mode = ["single", "inverse", "unisono"];
+            let midi_out = ["off", "thru", "chain"];
+            let voice_policy = ["steal", "refuse", "overflow"];
+            let name = |names: &[&str], value: u8| match names.get(value as usize) {
+                Some(name) => name.to_string(),
+                None => value.to_string(),
+            };
+            writeln!(out, "osc-mode {}", name(&osc_mode, status.osc_mode))?;
+            writeln!(out, "voices {}", status.voice_count)?;
+            writeln!(out, "busy {}", status.busy_voices)?;
+            writeln!(out, "drives {}", status.drive_count)?;
+            writeln!(out, "midi-out {}", name(&midi_out, status.midi_out))?;
+            writeln!(
+                out,
+                "voice-policy {}",
+                name(&voice_policy, status.voice_policy)
+            )?;
+        }
+        ["get", param, index] => {
+            let setting = match Setting::parse([param, index, "0"].into_iter()) {
+                Some(setting) => setting,
+                None => usage(),
+            };
+            let values = client.get(setting.param, setting.index)?;
+            writeln!(out, "{}", Setting { values, ..setting })?;
+        }
+        ["set", ..] => match Setting::parse(command[1..].iter().copied()) {
+            Some(setting) => client.set(setting.param, setting.index, &setting.values)?,
+            None => usage(),
+        },
+        ["dump"] => dump(client, &mut out)?,
+        ["presets"] => presets(client, &mut out)?,
+        ["upload", preset, name, file] => {
+            let text = fs::read_to_string(file)?;
+            let settings = parse_settings(&text).unwrap_or_else(|err| {
+                eprintln!("{file}: {err}");
+                exit(1)
+            });
+            upload(client, parse_preset(preset), parse_name(name), &settings)?;
+        }
+        ["delete", preset] => client.store_preset(parse_preset(preset), &[])?,
+        _ => usage(),
+    }
+    Ok(())
+}
+
+fn main() {
+    let options = parse_options();
+    let link: io::Result<Box<dyn Link>> = match &options.connection {
+        Connection::Port(path) => open_port(path).map(|link| Box::new(link) as _),
+        Connection::Serial(path, baud) => open_serial(path, *baud).map(|link| Box::new(link) as _),
+        #[cfg(feature = "alsa-seq")]
+        Connection::Seq(address) => seq::open_seq(address).map(|link| Box::new(link) as _),
+    };
+    let link = link.unwrap_or_else(|err| {
+        eprintln!("can't open the unit: {err}");
+        exit(1)
+    });
+
+    let mut client = Client::new(link, options.device_id, options.timeout);
+    if let Err(err) = run(&mut client, &options.command) {
+        eprintln!("{err}");
+        exit(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake::FakeUnit;
+
+    #[test]
+    fn settings_file() {
+        let text = "# lead\nosc-mode 0 1\n\ntrack-range 3 10 60\n";
+        let settings = parse_settings(text).unwrap();
+        assert_eq!(settings[1].to_string(), "track-range 3 10 60");
+        assert_eq!(settings[0].values, [1]);
+
+        assert!(parse_settings("transpose 0").is_err());
+        assert!(parse_settings("transpose 0 128").is_err());
+        assert_eq!(
+            parse_settings("osc-mode 0 0\nvolume 0 1"),
+            Err("bad setting in line 2".to_string())
+        );
+    }
+
+    #[test]
+    fn dump_and_upload() {
+        let mut client = Client::new(FakeUnit::new(), 0, Duration::ZERO);
+        let mut out = Vec::new();
+        dump(&mut client, &mut out).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        // one line per channel of both sources, per drive, per zone and per pitch class
+        assert_eq!(
+            text.lines().count(),
+            1 + 32 + 1 + 6 + 1 + 1 + 16 + 1 + 1 + 12 + 1
+        );
+        assert!(text.contains("\ntrack-range 5 0 79\nmidi-out 0 1\n"));
+
+        let mut settings = parse_settings(&text).unwrap();
+        settings[0].values = vec![2];
+        let device_id = settings.iter_mut().find(|s| s.param == Param::DeviceId);
+        device_id.unwrap().values = vec![9];
+        upload(&mut client, 4, b"wide", &settings).unwrap();
+        // the preset has the new oscillator mode, the device ID isn't part of it
+        let (_, stored) = client.link().presets[4].clone().unwrap();
+        assert_eq!(stored[&(Param::OscMode.to_byte(), 0)], [2]);
+        assert_eq!(stored[&(Param::DeviceId.to_byte(), 0)], [0]);
+
+        let mut out = Vec::new();
+        dump(&mut client, &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), text);
+
+        let mut out = Vec::new();
+        presets(&mut client, &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "0 0 single\n1 4 wide\n");
+    }
+}
@@ -0,0 +1,333 @@
+//! A port of the ALSA sequencer, connected with the unit in both directions.
+//!
+//! Only the few functions of libasound the link needs are declared here. The encoder and
+//! decoder of libasound turn the raw MIDI bytes into sequencer events and back.
+
+use std::{
+    ffi::{c_char, c_int, c_long, c_uchar, c_uint, CStr, CString},
+    io, ptr, thread,
+    time::{Duration, Instant},
+};
+
+use crate::link::{Link, SysExReader};
+
+const SND_SEQ_OPEN_DUPLEX: c_int = 3;
+const SND_SEQ_PORT_CAP_READ: c_uint = 1 << 0;
+const SND_SEQ_PORT_CAP_WRITE: c_uint = 1 << 1;
+const SND_SEQ_PORT_CAP_SUBS_READ: c_uint = 1 << 5;
+const SND_SEQ_PORT_CAP_SUBS_WRITE: c_uint = 1 << 6;
+const SND_SEQ_PORT_TYPE_MIDI_GENERIC: c_uint = 1 << 1;
+const SND_SEQ_PORT_TYPE_APPLICATION: c_uint = 1 << 20;
+const SND_SEQ_EVENT_NONE: u8 = 0;
+const SND_SEQ_QUEUE_DIRECT: u8 = 253;
+const SND_SEQ_ADDRESS_UNKNOWN: u8 = 253;
+const SND_SEQ_ADDRESS_SUBSCRIBERS: u8 = 254;
+const EAGAIN: c_int = 11;
+const ENOSPC: c_int = 28;
+
+// the longest SysEx message the encoder collects, far more than the protocol uses
+const ENCODER_SIZE: usize = 256;
+// how often `receive` looks for new events
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+#[repr(C)]
+struct Seq {
+    _private: [u8; 0],
+}
+
+#[repr(C)]
+struct MidiEvent {
+    _private: [u8; 0],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct SeqAddr {
+    pub client: u8,
+    pub port: u8,
+}
+
+// snd_seq_event_t, the time and data unions are opaque
+#[repr(C)]
+#[derive(Default)]
+struct SeqEvent {
+    kind: u8,
+    flags: u8,
+    tag: u8,
+    queue: u8,
+    time: [u32; 2],
+    source: SeqAddr,
+    dest: SeqAddr,
+    data: [u32; 3],
+}
+
+const _: () = assert!(std::mem::size_of::<SeqEvent>() == 28);
+
+#[link(name = "asound")]
+extern "C" {
+    fn snd_seq_open(seq: *mut *mut Seq, name: *const c_char, streams: c_int, mode: c_int) -> c_int;
+    fn snd_seq_close(seq: *mut Seq) -> c_int;
+    fn snd_seq_set_client_name(seq: *mut Seq, name: *const c_char) -> c_int;
+    #[cfg(test)]
+    fn snd_seq_client_id(seq: *mut Seq) -> c_int;
+    fn snd_seq_nonblock(seq: *mut Seq, nonblock: c_int) -> c_int;
+    fn snd_seq_create_simple_port(
+        seq: *mut Seq,
+        name: *const c_char,
+        caps: c_uint,
+        kind: c_uint,
+    ) -> c_int;
+    fn snd_seq_parse_address(seq: *mut Seq, addr: *mut SeqAddr, text: *const c_char) -> c_int;
+    fn snd_seq_connect_to(seq: *mut Seq, port: c_int, client: c_int, dest_port: c_int) -> c_int;
+    fn snd_seq_connect_from(seq: *mut Seq, port: c_int, client: c_int, src_port: c_int) -> c_int;
+    fn snd_seq_event_output_direct(seq: *mut Seq, event: *mut SeqEvent) -> c_int;
+    fn snd_seq_event_input(seq: *mut Seq, event: *mut *mut SeqEvent) -> c_int;
+    fn snd_midi_event_new(size: usize, dev: *mut *mut MidiEvent) -> c_int;
+    fn snd_midi_event_free(dev: *mut MidiEvent);
+    fn snd_midi_event_no_status(dev: *mut MidiEvent, on: c_int);
+    fn snd_midi_event_encode_byte(dev: *mut MidiEvent, byte: c_int, event: *mut SeqEvent) -> c_int;
+    fn snd_midi_event_decode(
+        dev: *mut MidiEvent,
+        buf: *mut c_uchar,
+        count: c_long,
+        event: *const SeqEvent,
+    ) -> c_long;
+    fn snd_strerror(err: c_int) -> *const c_char;
+}
+
+// libasound returns negative error codes
+fn check(what: &str, result: c_int) -> io::Result<c_int> {
+    if result >= 0 {
+        return Ok(result);
+    }
+    let message = unsafe { CStr::from_ptr(snd_strerror(result)) };
+    Err(io::Error::other(format!(
+        "{what}: {}",
+        message.to_string_lossy()
+    )))
+}
+
+fn c_string(text: &str) -> io::Result<CString> {
+    CString::new(text).map_err(|_| io::Error::other(format!("{text:?} contains a null byte")))
+}
+
+/// A sequencer client with one port, messages go to the subscribers of the port.
+pub struct SeqLink {
+    seq: *mut Seq,
+    port: u8,
+    encoder: *mut MidiEvent,
+    decoder: *mut MidiEvent,
+    sysex: SysExReader,
+}
+
+impl SeqLink {
+    /// Creates the client `name` with a port others can connect to.
+    pub fn new(name: &str) -> io::Result<Self> {
+        let mut link = Self {
+            seq: ptr::null_mut(),
+            port: 0,
+            encoder: ptr::null_mut(),
+            decoder: ptr::null_mut(),
+            sysex: SysExReader::default(),
+        };
+        let default = c_string("default")?;
+        let name = c_string(name)?;
+        unsafe {
+            check(
+                "can't open the sequencer",
+                snd_seq_open(&mut link.seq, default.as_ptr(), SND_SEQ_OPEN_DUPLEX, 0),
+            )?;
+            check(
+                "can't name the client",
+                snd_seq_set_client_name(link.seq, name.as_ptr()),
+            )?;
+            check("can't set up the client", snd_seq_nonblock(link.seq, 1))?;
+            let port = snd_seq_create_simple_port(
+                link.seq,
+                name.as_ptr(),
+                SND_SEQ_PORT_CAP_READ
+                    | SND_SEQ_PORT_CAP_SUBS_READ
+                    | SND_SEQ_PORT_CAP_WRITE
+                    | SND_SEQ_PORT_CAP_SUBS_WRITE,
+                SND_SEQ_PORT_TYPE_MIDI_GENERIC | SND_SEQ_PORT_TYPE_APPLICATION,
+            );
+            link.port = check("can't create a port", port)? as u8;
+            check(
+                "can't create the encoder",
+                snd_midi_event_new(ENCODER_SIZE, &mut link.encoder),
+            )?;
+            check(
+                "can't create the decoder",
+                snd_midi_event_new(ENCODER_SIZE, &mut link.decoder),
+            )?;
+            // every decoded message starts with its status, like on a raw MIDI device
+            snd_midi_event_no_status(link.decoder, 1);
+        }
+        Ok(link)
+    }
+
+    /// The client and port of this link.
+    #[cfg(test)]
+    pub fn address(&self) -> SeqAddr {
+        let client = unsafe { snd_seq_client_id(self.seq) };
+        SeqAddr {
+            client: client as u8,
+            port: self.port,
+        }
+    }
+
+    /// Connects the port with `address` in both directions.
+    pub fn connect(&mut self, address: SeqAddr) -> io::Result<()> {
+        let (port, client, dest_port) = (
+            self.port as c_int,
+            address.client as c_int,
+            address.port as c_int,
+        );
+        unsafe {
+            check(
+                "can't connect to the unit",
+                snd_seq_connect_to(self.seq, port, client, dest_port),
+            )?;
+            check(
+                "can't connect from the unit",
+                snd_seq_connect_from(self.seq, port, client, dest_port),
+            )?;
+        }
+        Ok(())
+    }
+
+    fn parse_address(&self, text: &str) -> io::Result<SeqAddr> {
+        let mut address = SeqAddr::default();
+        let text = c_string(text)?;
+        let result = unsafe { snd_seq_parse_address(self.seq, &mut address, text.as_ptr()) };
+        check("no such sequencer port", result)?;
+        Ok(address)
+    }
+}
+
+impl Drop for SeqLink {
+    fn drop(&mut self) {
+        unsafe {
+            for dev in [self.encoder, self.decoder] {
+                if !dev.is_null() {
+                    snd_midi_event_free(dev);
+                }
+            }
+            if !self.seq.is_null() {
+                snd_seq_close(self.seq);
+            }
+        }
+    }
+}
+
+impl Link for SeqLink {
+    fn send(&mut self, bytes: &[u8]) -> io::Result<()> {
+        for &byte in bytes {
+            let mut event = SeqEvent::default();
+            let done =
+                unsafe { snd_midi_event_encode_byte(self.encoder, byte as c_int, &mut event) };
+            if check("can't encode MIDI", done)? == 0 || event.kind == SND_SEQ_EVENT_NONE {
+                continue;
+            }
+            event.queue = SND_SEQ_QUEUE_DIRECT;
+            event.source.port = self.port;
+            event.dest = SeqAddr {
+                client: SND_SEQ_ADDRESS_SUBSCRIBERS,
+                port: SND_SEQ_ADDRESS_UNKNOWN,
+            };
+            // the buffer of the sequencer may be full for a moment
+            let deadline = Instant::now() + Duration::from_secs(1);
+            loop {
+                match unsafe { snd_seq_event_output_direct(self.seq, &mut event) } {
+                    result if result == -EAGAIN && Instant::now() < deadline => {
+                        thread::sleep(POLL_INTERVAL)
+                    }
+                    result => {
+                        check("can't send the event", result)?;
+                        break;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn receive(&mut self, timeout: Duration) -> io::Result<Option<Vec<u8>>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let mut event = ptr::null_mut();
+            match unsafe { snd_seq_event_input(self.seq, &mut event) } {
+                result if result == -EAGAIN => {
+                    if Instant::now() >= deadline {
+                        return Ok(None);
+                    }
+                    thread::sleep(POLL_INTERVAL);
+                }
+                // the input overran and events were lost, the next ones still arrive
+                result if result == -ENOSPC => {}
+                result => {
+                    check("can't receive events", result)?;
+                    let mut buf = [0u8; ENCODER_SIZE];
+                    let len = unsafe {
+                        snd_midi_event_decode(
+                            self.decoder,
+                            buf.as_mut_ptr(),
+                            buf.len() as c_long,
+                            event,
+                        )
+                    };
+                    // events without MIDI bytes, like port announcements, are skipped
+                    for &byte in &buf[..len.max(0) as usize] {
+                        if let Some(message) = self.sysex.push(byte) {
+                            return Ok(Some(message));
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Connects a new sequencer client with the port at `address`, like `Floppotron Jr` or `24:0`.
+pub fn open_seq(address: &str) -> io::Result<SeqLink> {
+    let mut link = SeqLink::new("floppoctl")?;
+    let address = link.parse_address(address)?;
+    link.connect(address)?;
+    Ok(link)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use super::*;
+    use crate::fake::FakeUnit;
+
+    #[test]
+    #[ignore = "needs the ALSA sequencer"]
+    fn loopback() {
+        let (sender, address) = mpsc::channel();
+        thread::spawn(move || {
+            let mut fake = FakeUnit::new();
+            let mut unit = SeqLink::new("fake unit").unwrap();
+            sender.send(unit.address()).unwrap();
+            loop {
+                if let Some(message) = unit.receive(Duration::from_secs(1)).unwrap() {
+                    fake.handle(&message);
+                    while let Some(reply) = fake.receive(Duration::ZERO).unwrap() {
+                        unit.send(&[0x90, 60, 100, 0xf0]).unwrap();
+                        unit.send(&reply).unwrap();
+                        unit.send(&[0xf7, 0x80, 60, 0]).unwrap();
+                    }
+                }
+            }
+        });
+
+        let address = address.recv().unwrap();
+        let mut link = open_seq(&format!("{}:{}", address.client, address.port)).unwrap();
+        link.send(&[0xf0, 0x7d, 0x00, 0x05, 0xf7]).unwrap();
+        let reply = link.receive(Duration::from_secs(1)).unwrap();
+        assert_eq!(reply, Some(vec![0x7d, 0x00, 0x06, 0, 6, 0, 6, 1, 0]));
+        assert_eq!(link.receive(Duration::from_millis(10)).unwrap(), None);
+    }
+}
@@ -0,0 +1,27 @@
+//! Messages the Floppotron Jr understands, for the firmware and the tools on the host.
+//!
+//! The crate is `no_std` and doesn't allocate, messages are built in `SysEx` buffers.
+#![no_std]
+
+pub mod sysex;
+
+use heapless::Vec;
+
+/// Longest SysEx message, without `F0` and `F7`.
+pub const SYSEX_SIZE: usize = 32;
+
+/// The bytes between `F0` and `F7` of a system exclusive message.
+pub type SysEx = Vec<u8, SYSEX_SIZE>;
+
+/// Presets in the user bank.
+pub const PRESET_COUNT: usize = 16;
+/// Longest preset name in bytes.
+pub const PRESET_NAME_SIZE: usize = 12;
+
+/// Number of data bytes after a channel message status.
+pub fn expected_data_len(status: u8) -> usize {
+    match status & 0xf0 {
+        0xc0 | 0xd0 => 1,
+        _ => 2,
+    }
+}
@@ -0,0 +1,769 @@
+//! SysEx protocol for reading and writing settings and querying the status.
+//!
+//! Every message starts with the manufacturer ID for non-commercial use and the device ID,
+//! so several units on one bus can be addressed individually:
+//!
+//! `F0 7D <device> <command> <arguments> F7`
+//!
+//! Device `7F` addresses all units. Requests and the replies sent on MIDI out:
+//!
+//! | request                             | reply                                               |
+//! |-------------------------------------|-----------------------------------------------------|
+//! | `01 <param> <index>` get            | `02 <param> <index> <values>`                       |
+//! | `03 <param> <index> <values>` set   | `04 <param> <index>`                                |
+//! | `05` status                         | `06 <mode> <voices> <busy> <drives> <out> <policy>` |
+//! | `07 <preset> <name>` store preset   | `08 <preset>`                                       |
+//! | `09 <bank> <preset>` preset name    | `0A <bank> <preset> <name>`                         |
+//! | `0B` stream start                   | `0C <time> <free> <late>`                           |
+//! | `0D <events>` stream events         | none                                                |
+//! | `0E` stream stop                    | `0C <time> <free> <late>`                           |
+//! | `0F` stream status                  | `0C <time> <free> <late>`                           |
+//!
+//! Storing a preset saves the current settings as user preset under `name`, an empty name
+//! deletes it. Free presets have an empty name, bank 0 holds the factory presets and
+//! bank 1 the user presets. Failed requests are answered with `7F <command> <error>`,
+//! see `SysExError`. The parameters and their values are listed at `Param`.
+//!
+//! The stream commands let a host send notes ahead of time, they are played against the
+//! clock of the unit. Every event is `<time> <status> <data>`, a channel message without
+//! running status at `time` milliseconds after the stream start. Times are sent as four
+//! 7 bit groups, the lowest first. The state reply has the stream time, the free events
+//! in the buffer and the number of events that arrived late, the latter two as two 7 bit
+//! groups. Events are only taken while a stream runs and when all of them fit into the
+//! buffer. A message holds up to four note events, see `SYSEX_SIZE`.
+//!
+//! The firmware parses requests and encodes replies, the host tools do the opposite.
+
+#[cfg(feature = "defmt")]
+use defmt::Format;
+
+use crate::{expected_data_len, SysEx, PRESET_COUNT, PRESET_NAME_SIZE};
+
+/// The manufacturer ID for non-commercial use.
+pub const MANUFACTURER_ID: u8 = 0x7d;
+/// The device ID that addresses all units.
+pub const BROADCAST_ID: u8 = 0x7f;
+
+const CMD_GET: u8 = 0x01;
+const CMD_VALUE: u8 = 0x02;
+const CMD_SET: u8 = 0x03;
+const CMD_ACK: u8 = 0x04;
+const CMD_STATUS: u8 = 0x05;
+const CMD_STATUS_REPLY: u8 = 0x06;
+const CMD_STORE_PRESET: u8 = 0x07;
+const CMD_PRESET_STORED: u8 = 0x08;
+const CMD_PRESET_NAME: u8 = 0x09;
+const CMD_PRESET_NAME_REPLY: u8 = 0x0a;
+const CMD_STREAM_START: u8 = 0x0b;
+const CMD_STREAM_STATE: u8 = 0x0c;
+const CMD_STREAM_EVENTS: u8 = 0x0d;
+const CMD_STREAM_STOP: u8 = 0x0e;
+const CMD_STREAM_STATUS: u8 = 0x0f;
+const CMD_ERROR: u8 = 0x7f;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(Format))]
+pub enum Param {
+    /// `00`, index 0: 0 single, 1 inverse, 2 unisono
+    OscMode,
+    /// `01`, index source * 16 + channel with DIN as source 0 and USB as 1: target channel
+    ChannelMap,
+    /// `02`, index 0: semitones added to every note, 64 leaves them unchanged
+    Transpose,
+    /// `03`, index drive: first and last track of the head movement
+    TrackRange,
+    /// `04`, index 0: MIDI out, 0 off, 1 thru, 2 chain: all channel messages but notes,
+    /// for a unit playing the notes `VoicePolicy` 2 passes on
+    MidiOut,
+    /// `05`, index 0: device ID, 0 to 126
+    DeviceId,
+    /// `06`, index channel: first and last voice the notes of the channel are played on
+    Zone,
+    /// `07`, index 0: when all voices of a zone are busy, 0 steals the oldest note, 1 drops
+    /// the new one and 2 passes it on to MIDI out
+    VoicePolicy,
+    /// `08`, index 0: cents added to every note, 64 leaves them unchanged
+    Tuning,
+    /// `09`, index pitch class from 0 for C to 11 for B: cents added to the notes of
+    /// the class on top of `Tuning`, 64 leaves them unchanged
+    ScaleTuning,
+    /// `0A`, index 0: vibrato rate in 0.1Hz and depth in cents, either 0 turns it off
+    Vibrato,
+}
+
+impl Param {
+    pub const ALL: [Param; 11] = [
+        Param::OscMode,
+        Param::ChannelMap,
+        Param::Transpose,
+        Param::TrackRange,
+        Param::MidiOut,
+        Param::DeviceId,
+        Param::Zone,
+        Param::VoicePolicy,
+        Param::Tuning,
+        Param::ScaleTuning,
+        Param::Vibrato,
+    ];
+
+    pub fn to_byte(self) -> u8 {
+        match self {
+            Param::OscMode => 0x00,
+            Param::ChannelMap => 0x01,
+            Param::Transpose => 0x02,
+            Param::TrackRange => 0x03,
+            Param::MidiOut => 0x04,
+            Param::DeviceId => 0x05,
+            Param::Zone => 0x06,
+            Param::VoicePolicy => 0x07,
+            Param::Tuning => 0x08,
+            Param::ScaleTuning => 0x09,
+            Param::Vibrato => 0x0a,
+        }
+    }
+
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0x00 => Some(Param::OscMode),
+            0x01 => Some(Param::ChannelMap),
+            0x02 => Some(Param::Transpose),
+            0x03 => Some(Param::TrackRange),
+            0x04 => Some(Param::MidiOut),
+            0x05 => Some(Param::DeviceId),
+            0x06 => Some(Param::Zone),
+            0x07 => Some(Param::VoicePolicy),
+            0x08 => Some(Param::Tuning),
+            0x09 => Some(Param::ScaleTuning),
+            0x0a => Some(Param::Vibrato),
+            _ => None,
+        }
+    }
+
+    /// The name the host tools use.
+    pub fn name(self) -> &'static str {
+        match self {
+            Param::OscMode => "osc-mode",
+            Param::ChannelMap => "channel-map",
+            Param::Transpose => "transpose",
+            Param::TrackRange => "track-range",
+            Param::MidiOut => "midi-out",
+            Param::DeviceId => "device-id",
+            Param::Zone => "zone",
+            Param::VoicePolicy => "voice-policy",
+            Param::Tuning => "tuning",
+            Param::ScaleTuning => "scale-tuning",
+            Param::Vibrato => "vibrato",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|param| param.name() == name)
+    }
+
+    /// Whether the parameter has other indices than 0.
+    pub fn is_indexed(self) -> bool {
+        matches!(
+            self,
+            Param::ChannelMap | Param::TrackRange | Param::Zone | Param::ScaleTuning
+        )
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(Format))]
+pub enum SysExError {
+    UnknownCommand = 1,
+    UnknownParam = 2,
+    BadIndex = 3,
+    BadValue = 4,
+    /// the request is too short or too long
+    Malformed = 5,
+    /// the stream events don't fit into the buffer, none of them were taken
+    BufferFull = 6,
+    /// stream events arrived before the stream start
+    NotStreaming = 7,
+}
+
+impl SysExError {
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            1 => Some(SysExError::UnknownCommand),
+            2 => Some(SysExError::UnknownParam),
+            3 => Some(SysExError::BadIndex),
+            4 => Some(SysExError::BadValue),
+            5 => Some(SysExError::Malformed),
+            6 => Some(SysExError::BufferFull),
+            7 => Some(SysExError::NotStreaming),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Request<'a> {
+    Get {
+        param: Param,
+        index: u8,
+    },
+    Set {
+        param: Param,
+        index: u8,
+        values: &'a [u8],
+    },
+    Status,
+    StorePreset {
+        preset: u8,
+        name: &'a [u8],
+    },
+    PresetName {
+        bank: u8,
+        preset: u8,
+    },
+    StreamStart,
+    StreamEvents(StreamEvents<'a>),
+    StreamStop,
+    StreamStatus,
+}
+
+/// A channel message at `time_ms` after the stream start.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct StreamEvent {
+    pub time_ms: u32,
+    pub status: u8,
+    /// the second byte is 0 for messages with one data byte
+    pub data: [u8; 2],
+}
+
+impl StreamEvent {
+    /// Appends the event to a stream events message, returns false if it doesn't fit.
+    pub fn encode(&self, message: &mut SysEx) -> bool {
+        let len = expected_data_len(self.status);
+        if message.capacity() - message.len() < 5 + len {
+            return false;
+        }
+        message
+            .extend_from_slice(&groups::<4>(self.time_ms))
+            .unwrap();
+        message.push(self.status).unwrap();
+        message.extend_from_slice(&self.data[..len]).unwrap();
+        true
+    }
+}
+
+/// The events of a stream message, `parse_request` checked them.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct StreamEvents<'a> {
+    data: &'a [u8],
+}
+
+// the event at the start of `data` and its length
+fn read_event(data: &[u8]) -> Option<(StreamEvent, usize)> {
+    let (time, rest) = (data.get(..4)?, &data[4..]);
+    let status = *rest.first()?;
+    if time.iter().any(|&byte| byte >= 0x80) || !(0x80..0xf0).contains(&status) {
+        return None;
+    }
+    let len = expected_data_len(status);
+    let mut values = [0; 2];
+    values[..len].copy_from_slice(rest.get(1..1 + len)?);
+    if values.iter().any(|&byte| byte >= 0x80) {
+        return None;
+    }
+    let event = StreamEvent {
+        time_ms: from_groups(time),
+        status,
+        data: values,
+    };
+    Some((event, 5 + len))
+}
+
+impl<'a> StreamEvents<'a> {
+    fn parse(data: &'a [u8]) -> Result<Self, SysExError> {
+        let mut rest = data;
+        while !rest.is_empty() {
+            let (_, len) = read_event(rest).ok_or(SysExError::Malformed)?;
+            rest = &rest[len..];
+        }
+        match data.is_empty() {
+            true => Err(SysExError::Malformed),
+            false => Ok(Self { data }),
+        }
+    }
+}
+
+impl<'a> Iterator for StreamEvents<'a> {
+    type Item = StreamEvent;
+
+    fn next(&mut self) -> Option<StreamEvent> {
+        let (event, len) = read_event(self.data)?;
+        self.data = &self.data[len..];
+        Some(event)
+    }
+}
+
+/// What the status query reports, the modes are encoded like their parameters.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Status {
+    pub osc_mode: u8,
+    pub voice_count: u8,
+    pub busy_voices: u8,
+    pub drive_count: u8,
+    pub midi_out: u8,
+    pub voice_policy: u8,
+}
+
+/// What the stream replies report.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct StreamState {
+    /// milliseconds since the stream start, 0 while no stream runs
+    pub time_ms: u32,
+    /// events that still fit into the buffer
+    pub free: u16,
+    /// events whose time had passed when they arrived
+    pub late: u16,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Reply<'a> {
+    Value {
+        param: Param,
+        index: u8,
+        values: &'a [u8],
+    },
+    Ack {
+        param: Param,
+        index: u8,
+    },
+    Status(Status),
+    PresetStored {
+        preset: u8,
+    },
+    PresetName {
+        bank: u8,
+        preset: u8,
+        name: &'a [u8],
+    },
+    StreamState(StreamState),
+    Error {
+        command: u8,
+        error: SysExError,
+    },
+}
+
+// `value` in `N` 7 bit groups, the lowest first
+fn groups<const N: usize>(value: u32) -> [u8; N] {
+    core::array::from_fn(|i| (value >> (7 * i)) as u8 & 0x7f)
+}
+
+fn from_groups(groups: &[u8]) -> u32 {
+    groups
+        .iter()
+        .rev()
+        .fold(0, |value, &group| value << 7 | group as u32)
+}
+
+/// Returns the device a SysEx message is addressed to, `None` if it isn't for this protocol.
+pub fn target(data: &[u8]) -> Option<u8> {
+    match data {
+        [MANUFACTURER_ID, device, ..] => Some(*device),
+        _ => None,
+    }
+}
+
+/// Parses a message that `target` accepted.
+pub fn parse_request(data: &[u8]) -> Result<Request<'_>, SysExError> {
+    let (&command, args) = data
+        .get(2..)
+        .and_then(|rest| rest.split_first())
+        .ok_or(SysExError::Malformed)?;
+    let param_index = |args: &[u8]| match args {
+        [param, index, ..] => Param::from_byte(*param)
+            .map(|param| (param, *index))
+            .ok_or(SysExError::UnknownParam),
+        _ => Err(SysExError::Malformed),
+    };
+
+    match command {
+        CMD_GET => {
+            if args.len() != 2 {
+                return Err(SysExError::Malformed);
+            }
+            let (param, index) = param_index(args)?;
+            Ok(Request::Get { param, index })
+        }
+        CMD_SET => {
+            let (param, index) = param_index(args)?;
+            Ok(Request::Set {
+                param,
+                index,
+                values: &args[2..],
+            })
+        }
+        CMD_STATUS if args.is_empty() => Ok(Request::Status),
+        CMD_STATUS => Err(SysExError::Malformed),
+        CMD_STORE_PRESET => match args {
+            [preset, ..] if *preset as usize >= PRESET_COUNT => Err(SysExError::BadIndex),
+            [_, name @ ..] if name.len() > PRESET_NAME_SIZE => Err(SysExError::BadValue),
+            [preset, name @ ..] => Ok(Request::StorePreset {
+                preset: *preset,
+                name,
+            }),
+            [] => Err(SysExError::Malformed),
+        },
+        CMD_PRESET_NAME => match args {
+            [_, preset] if *preset as usize >= PRESET_COUNT => Err(SysExError::BadIndex),
+            [bank, preset] => Ok(Request::PresetName {
+                bank: *bank,
+                preset: *preset,
+            }),
+            _ => Err(SysExError::Malformed),
+        },
+        CMD_STREAM_START | CMD_STREAM_STOP | CMD_STREAM_STATUS if !args.is_empty() => {
+            Err(SysExError::Malformed)
+        }
+        CMD_STREAM_START => Ok(Request::StreamStart),
+        CMD_STREAM_STOP => Ok(Request::StreamStop),
+        CMD_STREAM_STATUS => Ok(Request::StreamStatus),
+        CMD_STREAM_EVENTS => StreamEvents::parse(args).map(Request::StreamEvents),
+        _ => Err(SysExError::UnknownCommand),
+    }
+}
+
+fn message(device_id: u8, command: u8, args: &[&[u8]]) -> SysEx {
+    let mut data = SysEx::new();
+    data.extend_from_slice(&[MANUFACTURER_ID, device_id, command])
+        .unwrap();
+    for arg in args {
+        data.extend_from_slice(arg).unwrap();
+    }
+    data
+}
+
+impl<'a> Request<'a> {
+    /// The message for `device_id`, without `F0` and `F7`.
+    pub fn encode(&self, device_id: u8) -> SysEx {
+        match *self {
+            Request::Get { param, index } => {
+                message(device_id, CMD_GET, &[&[param.to_byte(), index]])
+            }
+            Request::Set {
+                param,
+                index,
+                values,
+            } => message(device_id, CMD_SET, &[&[param.to_byte(), index], values]),
+            Request::Status => message(device_id, CMD_STATUS, &[]),
+            Request::StorePreset { preset, name } => {
+                message(device_id, CMD_STORE_PRESET, &[&[preset], name])
+            }
+            Request::PresetName { bank, preset } => {
+                message(device_id, CMD_PRESET_NAME, &[&[bank, preset]])
+            }
+            Request::StreamStart => message(device_id, CMD_STREAM_START, &[]),
+            Request::StreamEvents(events) => message(device_id, CMD_STREAM_EVENTS, &[events.data]),
+            Request::StreamStop => message(device_id, CMD_STREAM_STOP, &[]),
+            Request::StreamStatus => message(device_id, CMD_STREAM_STATUS, &[]),
+        }
+    }
+}
+
+impl<'a> Reply<'a> {
+    /// The message from `device_id`, without `F0` and `F7`.
+    pub fn encode(&self, device_id: u8) -> SysEx {
+        match *self {
+            Reply::Value {
+                param,
+                index,
+                values,
+            } => message(device_id, CMD_VALUE, &[&[param.to_byte(), index], values]),
+            Reply::Ack { param, index } => {
+                message(device_id, CMD_ACK, &[&[param.to_byte(), index]])
+            }
+            Reply::Status(status) => message(
+                device_id,
+                CMD_STATUS_REPLY,
+                &[&[
+                    status.osc_mode,
+                    status.voice_count,
+                    status.busy_voices,
+                    status.drive_count,
+                    status.midi_out,
+                    status.voice_policy,
+                ]],
+            ),
+            Reply::PresetStored { preset } => message(device_id, CMD_PRESET_STORED, &[&[preset]]),
+            Reply::PresetName { bank, preset, name } => {
+                message(device_id, CMD_PRESET_NAME_REPLY, &[&[bank, preset], name])
+            }
+            Reply::StreamState(state) => message(
+                device_id,
+                CMD_STREAM_STATE,
+                &[
+                    &groups::<4>(state.time_ms),
+                    &groups::<2>(state.free as u32),
+                    &groups::<2>(state.late as u32),
+                ],
+            ),
+            Reply::Error { command, error } => {
+                message(device_id, CMD_ERROR, &[&[command, error as u8]])
+            }
+        }
+    }
+}
+
+/// Answers a failed request, `data` is the request as passed to `parse_request`.
+pub fn error_reply(device_id: u8, data: &[u8], error: SysExError) -> SysEx {
+    let command = data.get(2).copied().unwrap_or(0);
+    Reply::Error { command, error }.encode(device_id)
+}
+
+/// Parses a reply from a unit, returns the device that sent it.
+///
+/// Requests and messages of other manufacturers are `Malformed`.
+pub fn parse_reply(data: &[u8]) -> Result<(u8, Reply<'_>), SysExError> {
+    let (device_id, command, args) = match data {
+        [MANUFACTURER_ID, device_id, command, args @ ..] => (*device_id, *command, args),
+        _ => return Err(SysExError::Malformed),
+    };
+    let param = |byte| Param::from_byte(byte).ok_or(SysExError::UnknownParam);
+
+    let reply = match (command, args) {
+        (CMD_VALUE, [p, index, values @ ..]) => Reply::Value {
+            param: param(*p)?,
+            index: *index,
+            values,
+        },
+        (CMD_ACK, [p, index]) => Reply::Ack {
+            param: param(*p)?,
+            index: *index,
+        },
+        (
+            CMD_STATUS_REPLY,
+            &[osc_mode, voice_count, busy_voices, drive_count, midi_out, voice_policy],
+        ) => Reply::Status(Status {
+            osc_mode,
+            voice_count,
+            busy_voices,
+            drive_count,
+            midi_out,
+            voice_policy,
+        }),
+        (CMD_PRESET_STORED, [preset]) => Reply::PresetStored { preset: *preset },
+        (CMD_PRESET_NAME_REPLY, [bank, preset, name @ ..]) => Reply::PresetName {
+            bank: *bank,
+            preset: *preset,
+            name,
+        },
+        (CMD_STREAM_STATE, [time @ .., f0, f1, l0, l1]) if time.len() == 4 => {
+            Reply::StreamState(StreamState {
+                time_ms: from_groups(time),
+                free: from_groups(&[*f0, *f1]) as u16,
+                late: from_groups(&[*l0, *l1]) as u16,
+            })
+        }
+        (CMD_ERROR, [command, error]) => Reply::Error {
+            command: *command,
+            error: SysExError::from_byte(*error).ok_or(SysExError::BadValue)?,
+        },
+        (
+            CMD_VALUE
+            | CMD_ACK
+            | CMD_STATUS_REPLY
+            | CMD_PRESET_STORED
+            | CMD_PRESET_NAME_REPLY
+            | CMD_STREAM_STATE
+            | CMD_ERROR,
+            _,
+        ) => return Err(SysExError::Malformed),
+        _ => return Err(SysExError::UnknownCommand),
+    };
+    Ok((device_id, reply))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn target_needs_manufacturer_id() {
+        assert_eq!(target(&[0x7d, 0x03, 0x05]), Some(3));
+        assert_eq!(target(&[0x7e, 0x03, 0x05]), None);
+        assert_eq!(target(&[0x7d]), None);
+    }
+
+    #[test]
+    fn requests() {
+        assert_eq!(
+            parse_request(&[0x7d, 0, 0x01, 0x03, 2]),
+            Ok(Request::Get {
+                param: Param::TrackRange,
+                index: 2
+            })
+        );
+        assert_eq!(
+            parse_request(&[0x7d, 0, 0x03, 0x03, 2, 10, 40]),
+            Ok(Request::Set {
+                param: Param::TrackRange,
+                index: 2,
+                values: &[10, 40]
+            })
+        );
+        assert_eq!(parse_request(&[0x7d, 0, 0x05]), Ok(Request::Status));
+        assert_eq!(
+            parse_request(&[0x7d, 0, 0x07, 3, b'o', b'r', b'g']),
+            Ok(Request::StorePreset {
+                preset: 3,
+                name: b"org"
+            })
+        );
+        assert_eq!(
+            parse_request(&[0x7d, 0, 0x09, 1, 3]),
+            Ok(Request::PresetName { bank: 1, preset: 3 })
+        );
+        assert_eq!(parse_request(&[0x7d, 0, 0x0b]), Ok(Request::StreamStart));
+    }
+
+    #[test]
+    fn requests_round_trip() {
+        let requests = [
+            Request::Get {
+                param: Param::Transpose,
+                index: 0,
+            },
+            Request::Set {
+                param: Param::TrackRange,
+                index: 5,
+                values: &[2, 70],
+            },
+            Request::Status,
+            Request::StorePreset {
+                preset: 15,
+                name: b"bass",
+            },
+            Request::PresetName { bank: 0, preset: 2 },
+            Request::StreamStop,
+        ];
+        for request in requests {
+            assert_eq!(parse_request(&request.encode(9)), Ok(request));
+        }
+    }
+
+    #[test]
+    fn stream_events() {
+        // a note on at 200 ms and a program change at 16384 ms
+        let request = [
+            0x7d, 0, 0x0d, 0x48, 0x01, 0, 0, 0x91, 60, 100, 0, 0, 1, 0, 0xc0, 3,
+        ];
+        let events = match parse_request(&request) {
+            Ok(Request::StreamEvents(events)) => events,
+            _ => panic!("no stream events"),
+        };
+        assert_eq!(Request::StreamEvents(events).encode(0), request);
+
+        let mut events = events;
+        let note = StreamEvent {
+            time_ms: 200,
+            status: 0x91,
+            data: [60, 100],
+        };
+        assert_eq!(events.next(), Some(note));
+        let program = events.next().unwrap();
+        assert_eq!((program.time_ms, program.data), (16384, [3, 0]));
+        assert_eq!(events.next(), None);
+
+        // four note events fit into a message
+        let mut message = SysEx::from_slice(&[0x7d, 0, 0x0d]).unwrap();
+        for _ in 0..4 {
+            assert!(note.encode(&mut message));
+        }
+        assert!(!note.encode(&mut message));
+        assert_eq!(message.len(), 3 + 4 * 7);
+
+        for bad in [
+            &[0x7d, 0, 0x0d][..],
+            // cut short
+            &[0x7d, 0, 0x0d, 0, 0, 0, 0, 0x90, 60],
+            // a time byte with the top bit set
+            &[0x7d, 0, 0x0d, 0x80, 0, 0, 0, 0x90, 60, 100],
+            // system messages can't be streamed
+            &[0x7d, 0, 0x0d, 0, 0, 0, 0, 0xf2, 0, 0],
+        ] {
+            assert_eq!(parse_request(bad), Err(SysExError::Malformed));
+        }
+    }
+
+    #[test]
+    fn bad_requests() {
+        assert_eq!(parse_request(&[0x7d, 0]), Err(SysExError::Malformed));
+        assert_eq!(
+            parse_request(&[0x7d, 0, 0x01, 0x03]),
+            Err(SysExError::Malformed)
+        );
+        assert_eq!(
+            parse_request(&[0x7d, 0, 0x01, 0x03, 0, 1]),
+            Err(SysExError::Malformed)
+        );
+        assert_eq!(
+            parse_request(&[0x7d, 0, 0x01, 0x40, 0]),
+            Err(SysExError::UnknownParam)
+        );
+        assert_eq!(
+            parse_request(&[0x7d, 0, 0x42]),
+            Err(SysExError::UnknownCommand)
+        );
+        assert_eq!(
+            parse_request(&[0x7d, 0, 0x07, PRESET_COUNT as u8]),
+            Err(SysExError::BadIndex)
+        );
+        let mut long_name = [b'x'; 4 + PRESET_NAME_SIZE + 1];
+        long_name[..4].copy_from_slice(&[0x7d, 0, 0x07, 0]);
+        assert_eq!(parse_request(&long_name), Err(SysExError::BadValue));
+    }
+
+    #[test]
+    fn replies() {
+        let value = Reply::Value {
+            param: Param::TrackRange,
+            index: 1,
+            values: &[10, 40],
+        };
+        assert_eq!(value.encode(4), [0x7d, 4, 0x02, 0x03, 1, 10, 40]);
+        let ack = Reply::Ack {
+            param: Param::Transpose,
+            index: 0,
+        };
+        assert_eq!(ack.encode(4), [0x7d, 4, 0x04, 0x02, 0]);
+        let name = Reply::PresetName {
+            bank: 1,
+            preset: 2,
+            name: b"org",
+        };
+        assert_eq!(name.encode(4), [0x7d, 4, 0x0a, 1, 2, b'o', b'r', b'g']);
+        let state = Reply::StreamState(StreamState {
+            time_ms: 1 << 14 | 5,
+            free: 130,
+            late: 1,
+        });
+        assert_eq!(state.encode(4), [0x7d, 4, 0x0c, 5, 0, 1, 0, 2, 1, 1, 0]);
+        assert_eq!(
+            error_reply(4, &[0x7d, 4, 0x42], SysExError::UnknownCommand),
+            [0x7d, 4, 0x7f, 0x42, 1]
+        );
+
+        let status = Reply::Status(Status {
+            osc_mode: 1,
+            voice_count: 3,
+            busy_voices: 2,
+            drive_count: 6,
+            midi_out: 1,
+            voice_policy: 2,
+        });
+        for reply in [value, ack, name, state, status] {
+            assert_eq!(parse_reply(&reply.encode(4)), Ok((4, reply)));
+        }
+        assert_eq!(
+            parse_reply(&[0x7d, 4, 0x01, 0, 0]),
+            Err(SysExError::UnknownCommand)
+        );
+        assert_eq!(parse_reply(&[0x7d, 4, 0x04, 0]), Err(SysExError::Malformed));
+    }
+}